@@ -0,0 +1,333 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::http::{civil_from_days, json_string, Request};
+
+/// 轮转时保留 `access.log.1` 到 `access.log.5`
+pub const ROTATE_KEEP: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    /// `127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] "GET / HTTP/1.1" 200 2326`
+    Common,
+    /// Common 加上 referer、user-agent，最后是处理耗时(秒)
+    Combined,
+    /// 每行一个 JSON 对象
+    Json,
+}
+
+impl LogFormat {
+    pub fn parse(name: &str) -> Option<LogFormat> {
+        match name {
+            "common" => Some(LogFormat::Common),
+            "combined" => Some(LogFormat::Combined),
+            "json" => Some(LogFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccessLogConfig {
+    /// `None` 写到标准输出
+    pub path: Option<PathBuf>,
+    pub format: LogFormat,
+    /// 文件超过这个大小就轮转，`None` 表示不轮转
+    pub max_size: Option<u64>,
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> AccessLogConfig {
+        AccessLogConfig {
+            path: None,
+            format: LogFormat::Common,
+            max_size: None,
+        }
+    }
+}
+
+/// 一次请求的记录。请求没能解析时 `request` 为 `None`
+pub struct Entry<'a> {
+    pub client: IpAddr,
+    pub time: SystemTime,
+    pub request: Option<&'a Request>,
+    pub status: u16,
+    pub bytes: u64,
+    pub latency: Duration,
+}
+
+impl Entry<'_> {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.request.and_then(|request| request.header(name))
+    }
+
+    pub fn format(&self, format: LogFormat) -> String {
+        let request_line = self
+            .request
+            .map(|request| format!("{} {} {}", request.method, request.target, request.version));
+        let bytes = match self.bytes {
+            0 => "-".to_string(),
+            bytes => bytes.to_string(),
+        };
+        let common = format!(
+            "{} - - [{}] \"{}\" {} {bytes}",
+            self.client,
+            clf_date(self.time),
+            escape(request_line.as_deref().unwrap_or("-")),
+            self.status,
+        );
+        match format {
+            LogFormat::Common => common,
+            LogFormat::Combined => format!(
+                "{common} \"{}\" \"{}\" {:.3}",
+                escape(self.header("Referer").unwrap_or("-")),
+                escape(self.header("User-Agent").unwrap_or("-")),
+                self.latency.as_secs_f64()
+            ),
+            LogFormat::Json => {
+                let field = |value: Option<&str>| value.map_or("null".to_string(), json_string);
+                let version = self.request.map(|request| request.version.to_string());
+                let secs = self
+                    .time
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0.0, |since| since.as_secs_f64());
+                format!(
+                    "{{\"time\":{secs:.3},\"client\":\"{}\",\"method\":{},\"path\":{},\"query\":{},\"version\":{},\"status\":{},\"bytes\":{},\"referer\":{},\"user_agent\":{},\"latency_ms\":{:.3}}}",
+                    self.client,
+                    field(self.request.map(|request| request.method.as_str())),
+                    field(self.request.map(|request| request.path.as_str())),
+                    field(
+                        self.request
+                            .and_then(|request| request.target.split_once('?'))
+                            .map(|(_, query)| query)
+                    ),
+                    field(version.as_deref()),
+                    self.status,
+                    self.bytes,
+                    field(self.header("Referer")),
+                    field(self.header("User-Agent")),
+                    self.latency.as_secs_f64() * 1000.0
+                )
+            }
+        }
+    }
+}
+
+/// 写日志的线程之间共用一把锁，保证每行完整
+pub struct AccessLog {
+    format: LogFormat,
+    max_size: Option<u64>,
+    target: Mutex<Target>,
+}
+
+enum Target {
+    Stdout,
+    File {
+        path: PathBuf,
+        file: File,
+        size: u64,
+    },
+}
+
+impl AccessLog {
+    pub fn open(config: &AccessLogConfig) -> io::Result<AccessLog> {
+        let target = match &config.path {
+            None => Target::Stdout,
+            Some(path) => {
+                let file = append(path)?;
+                let size = file.metadata()?.len();
+                Target::File {
+                    path: path.clone(),
+                    file,
+                    size,
+                }
+            }
+        };
+        Ok(AccessLog {
+            format: config.format,
+            max_size: config.max_size,
+            target: Mutex::new(target),
+        })
+    }
+
+    /// 写失败只打印错误，不影响请求处理
+    pub fn log(&self, entry: &Entry) {
+        let line = entry.format(self.format) + "\n";
+        if let Err(err) = self.write(line.as_bytes()) {
+            eprintln!("ERROR: could not write access log: {err}");
+        }
+    }
+
+    fn write(&self, line: &[u8]) -> io::Result<()> {
+        let mut target = self.target.lock().unwrap();
+        let Target::File { path, file, size } = &mut *target else {
+            return io::stdout().lock().write_all(line);
+        };
+        if let Some(max_size) = self.max_size {
+            if *size > 0 && *size + line.len() as u64 > max_size {
+                rotate(path)?;
+                *file = append(path)?;
+                *size = 0;
+            }
+        }
+        file.write_all(line)?;
+        *size += line.len() as u64;
+        Ok(())
+    }
+}
+
+fn append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn numbered(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{n}"));
+    PathBuf::from(name)
+}
+
+/// `access.log` 改名为 `access.log.1`，原来的 `.1` 变成 `.2`，最老的一个删掉
+fn rotate(path: &Path) -> io::Result<()> {
+    for n in (1..ROTATE_KEEP).rev() {
+        let from = numbered(path, n);
+        if from.exists() {
+            fs::rename(&from, numbered(path, n + 1))?;
+        }
+    }
+    fs::rename(path, numbered(path, 1))
+}
+
+/// `10/Oct/2000:13:55:36 +0000`，统一用 UTC
+fn clf_date(time: SystemTime) -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let rest = secs % 86400;
+    format!(
+        "{day:02}/{}/{year}:{:02}:{:02}:{:02} +0000",
+        MONTHS[month as usize - 1],
+        rest / 3600,
+        rest / 60 % 60,
+        rest % 60
+    )
+}
+
+/// 引号里的字段转义 `"`、`\` 和控制字符，避免伪造日志行
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{parse_head, HttpConfig};
+
+    fn entry(request: Option<&Request>) -> Entry<'_> {
+        Entry {
+            client: "10.0.0.1".parse().unwrap(),
+            time: UNIX_EPOCH + Duration::from_secs(971186136),
+            request,
+            status: 200,
+            bytes: 2326,
+            latency: Duration::from_micros(1500),
+        }
+    }
+
+    #[test]
+    fn format_entries() {
+        let request = parse_head(
+            "GET /a%20b?x=1 HTTP/1.1\r\nHost: h\r\nReferer: http://h/\r\nUser-Agent: curl \"8\"",
+        )
+        .unwrap();
+        let logged = entry(Some(&request));
+        assert_eq!(
+            logged.format(LogFormat::Common),
+            "10.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /a%20b?x=1 HTTP/1.1\" 200 2326"
+        );
+        assert_eq!(
+            logged.format(LogFormat::Combined),
+            "10.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /a%20b?x=1 HTTP/1.1\" 200 2326 \"http://h/\" \"curl \\\"8\\\"\" 0.002"
+        );
+        assert_eq!(
+            logged.format(LogFormat::Json),
+            r#"{"time":971186136.000,"client":"10.0.0.1","method":"GET","path":"/a b","query":"x=1","version":"HTTP/1.1","status":200,"bytes":2326,"referer":"http://h/","user_agent":"curl \"8\"","latency_ms":1.500}"#
+        );
+
+        let mut bad = entry(None);
+        (bad.status, bad.bytes) = (400, 0);
+        assert_eq!(
+            bad.format(LogFormat::Common),
+            "10.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"-\" 400 -"
+        );
+        assert!(bad.format(LogFormat::Json).contains("\"method\":null"));
+        assert_eq!(escape("a\"b\n"), "a\\\"b\\x0a");
+    }
+
+    #[test]
+    fn rotate_by_size() {
+        let dir = std::env::temp_dir().join(format!("access-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.log");
+        let log = AccessLog::open(&AccessLogConfig {
+            path: Some(path.clone()),
+            format: LogFormat::Common,
+            max_size: Some(200),
+        })
+        .unwrap();
+        for _ in 0..20 {
+            log.log(&entry(None));
+        }
+        let line_len = entry(None).format(LogFormat::Common).len() as u64 + 1;
+        for file in [
+            path.clone(),
+            numbered(&path, 1),
+            numbered(&path, ROTATE_KEEP),
+        ] {
+            let len = fs::metadata(&file).unwrap().len();
+            assert!(len <= 200 && len % line_len == 0, "{}", file.display());
+        }
+        assert!(!numbered(&path, ROTATE_KEEP + 1).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn access_log_options() {
+        let args = [
+            "--access-log",
+            "access.log",
+            "--log-format",
+            "json",
+            "--log-max-size",
+            "1M",
+        ]
+        .map(String::from);
+        let config = HttpConfig::build(args.into_iter()).unwrap();
+        assert_eq!(
+            config.site.access_log,
+            AccessLogConfig {
+                path: Some(PathBuf::from("access.log")),
+                format: LogFormat::Json,
+                max_size: Some(1024 * 1024),
+            }
+        );
+    }
+}
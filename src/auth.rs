@@ -0,0 +1,181 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    hint,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use pbkdf2::pbkdf2_hmac;
+use sha2::Sha256;
+
+use crate::irc;
+
+const SALT_LEN: usize = 16;
+const HASH_LEN: usize = 32;
+const SCHEME: &str = "pbkdf2-sha256";
+/// PBKDF2-HMAC-SHA256 的迭代次数，取 OWASP 建议的值
+const ROUNDS: u32 = 600_000;
+
+/// 凭据文件，每行一个用户: `用户名:pbkdf2-sha256:迭代次数:盐(hex):哈希(hex)`
+pub struct Credentials {
+    users: HashMap<String, PasswordHash>,
+}
+
+struct PasswordHash {
+    rounds: u32,
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+impl Credentials {
+    pub fn load(path: &Path) -> io::Result<Credentials> {
+        let mut users = HashMap::new();
+        for (number, line) in fs::read_to_string(path)?.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let Some((user, stored)) = parse_line(line.trim()) else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "line {}: expected user:{SCHEME}:rounds:salt:hash",
+                        number + 1
+                    ),
+                ));
+            };
+            users.insert(user.to_string(), stored);
+        }
+        Ok(Credentials { users })
+    }
+
+    /// 用户名出现在凭据文件里即视为保留昵称
+    pub fn is_reserved(&self, user: &str) -> bool {
+        self.users.contains_key(user)
+    }
+
+    /// 用户不存在时也用固定的盐按文件里的迭代次数算一遍，响应时间不会暴露哪些用户名存在
+    pub fn verify(&self, user: &str, password: &str) -> bool {
+        match self.users.get(user) {
+            Some(stored) => constant_time_eq(
+                &hash_password(&stored.salt, password, stored.rounds),
+                &stored.hash,
+            ),
+            None => {
+                let rounds = self.users.values().map(|stored| stored.rounds).max();
+                hint::black_box(hash_password(
+                    &[0; SALT_LEN],
+                    password,
+                    rounds.unwrap_or(ROUNDS),
+                ));
+                false
+            }
+        }
+    }
+}
+
+fn parse_line(line: &str) -> Option<(&str, PasswordHash)> {
+    let [user, scheme, rounds, salt, hash] = line.split(':').collect::<Vec<_>>()[..] else {
+        return None;
+    };
+    let rounds = rounds.parse().ok().filter(|&rounds| rounds > 0)?;
+    if scheme != SCHEME {
+        return None;
+    }
+    let (salt, hash) = (from_hex(salt)?, from_hex(hash)?);
+    Some((user, PasswordHash { rounds, salt, hash }))
+}
+
+/// 追加一个用户到凭据文件
+pub fn add_user(path: &Path, user: &str, password: &str) -> io::Result<()> {
+    append_user(path, user, password, ROUNDS)
+}
+
+fn append_user(path: &Path, user: &str, password: &str, rounds: u32) -> io::Result<()> {
+    // 登录以后用户名就是昵称，也要能出现在 IRC 消息里
+    if !irc::valid_nick(user) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "user name must be a valid IRC nick",
+        ));
+    }
+    let mut salt = [0; SALT_LEN];
+    File::open("/dev/urandom")?.read_exact(&mut salt)?;
+    let hash = hash_password(&salt, password, rounds);
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(
+        file,
+        "{user}:{SCHEME}:{rounds}:{}:{}",
+        to_hex(&salt),
+        to_hex(&hash)
+    )
+}
+
+fn hash_password(salt: &[u8], password: &str, rounds: u32) -> Vec<u8> {
+    let mut hash = vec![0; HASH_LEN];
+    pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, rounds, &mut hash);
+    hash
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_and_verify() {
+        let path = std::env::temp_dir().join(format!("creds-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        // 测试用很少的迭代次数，轮数记在文件里，校验时照样读出来
+        append_user(&path, "alice", "secret", 10).unwrap();
+        append_user(&path, "bob", "hunter2", 20).unwrap();
+        assert!(add_user(&path, "bad:name", "x").is_err());
+
+        let credentials = Credentials::load(&path).unwrap();
+        assert!(credentials.verify("alice", "secret"));
+        assert!(!credentials.verify("alice", "hunter2"));
+        assert!(!credentials.verify("carol", "secret"));
+        assert!(credentials.is_reserved("bob"));
+        assert!(!credentials.is_reserved("carol"));
+
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        writeln!(file, "carol:0102:0304").unwrap();
+        let err = Credentials::load(&path).err().unwrap();
+        assert!(err.to_string().starts_with("line 3:"));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn known_pbkdf2_vector() {
+        // RFC 7914 第 11 节的 PBKDF2-HMAC-SHA256 测试向量
+        assert_eq!(
+            to_hex(&hash_password(b"salt", "passwd", 1)),
+            "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc"
+        );
+    }
+
+    #[test]
+    fn hex_roundtrip() {
+        assert_eq!(to_hex(&[0, 15, 255]), "000fff");
+        assert_eq!(from_hex("000fff"), Some(vec![0, 15, 255]));
+        assert_eq!(from_hex("0g"), None);
+        assert_eq!(from_hex("abc"), None);
+    }
+}
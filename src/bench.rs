@@ -0,0 +1,711 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    io::{self, BufRead, BufReader, Write},
+    net::TcpStream,
+    result, thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+use mini_redis::blocking_client::{self, BlockingClient};
+
+use crate::http::{self, parse_head, HttpReader, MAX_RESPONSE_SIZE};
+use crate::units::parse_duration;
+use crate::{chat, kv};
+
+pub const DEFAULT_CONNECTIONS: usize = 8;
+pub const DEFAULT_DURATION: Duration = Duration::from_secs(10);
+/// redis 请求在 `bench:0` 到 `bench:999` 里随机挑键
+pub const DEFAULT_KEYS: u64 = 1000;
+/// 一个请求等这么久没有回应就算失败，换一个连接
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// 连不上时隔一会儿再试，免得错误数刷屏
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    Http,
+    Chat,
+    Redis,
+}
+
+impl Target {
+    pub fn parse(name: &str) -> Option<Target> {
+        match name {
+            "http" => Some(Target::Http),
+            "chat" => Some(Target::Chat),
+            "redis" => Some(Target::Redis),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Target::Http => "http",
+            Target::Chat => "chat",
+            Target::Redis => "redis",
+        }
+    }
+
+    fn default_address(self) -> &'static str {
+        match self {
+            Target::Http => http::DEFAULT_ADDRESS,
+            Target::Chat => chat::DEFAULT_ADDRESS,
+            Target::Redis => kv::DEFAULT_ADDRESS,
+        }
+    }
+
+    fn default_mix(self) -> &'static str {
+        match self {
+            Target::Http => "GET /",
+            Target::Chat => "say",
+            Target::Redis => "get=9,set=1",
+        }
+    }
+}
+
+/// 请求组合里的一种操作
+#[derive(Debug, Clone, PartialEq)]
+pub enum Op {
+    /// `GET /hello`，POST、PUT、PATCH 带一个很小的 urlencoded 正文
+    Http {
+        method: String,
+        target: String,
+    },
+    /// 在自己的房间里发一条消息，直到同一房间的另一个连接收到
+    Say,
+    /// `/history 1`，直到收到回放
+    History,
+    Get,
+    Set,
+}
+
+impl Op {
+    fn parse(target: Target, text: &str) -> Option<Op> {
+        match target {
+            Target::Http => {
+                let (method, path) = text.split_once(' ')?;
+                let method_ok =
+                    !method.is_empty() && method.bytes().all(|b| b.is_ascii_uppercase());
+                if !method_ok || !path.starts_with('/') || path.contains(char::is_whitespace) {
+                    return None;
+                }
+                Some(Op::Http {
+                    method: method.to_string(),
+                    target: path.to_string(),
+                })
+            }
+            Target::Chat => match text {
+                "say" => Some(Op::Say),
+                "history" => Some(Op::History),
+                _ => None,
+            },
+            Target::Redis => match text {
+                "get" => Some(Op::Get),
+                "set" => Some(Op::Set),
+                _ => None,
+            },
+        }
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Op::Http { method, target } => write!(f, "{method} {target}"),
+            Op::Say => write!(f, "say"),
+            Op::History => write!(f, "history"),
+            Op::Get => write!(f, "get"),
+            Op::Set => write!(f, "set"),
+        }
+    }
+}
+
+/// `GET /=8,GET /hello=2` 这样按权重混合，权重省略时为 1
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mix(Vec<(Op, u64)>);
+
+impl Mix {
+    pub fn parse(target: Target, spec: &str) -> Option<Mix> {
+        let mut ops = Vec::new();
+        for item in spec.split(',').map(str::trim) {
+            // 路径的查询串里也可能有 `=`，最后一个 `=` 后面是数字才算权重
+            let (op, weight) = match item.rsplit_once('=') {
+                Some((op, weight)) if weight.bytes().all(|b| b.is_ascii_digit()) => {
+                    (op, weight.parse().ok().filter(|weight| *weight > 0)?)
+                }
+                _ => (item, 1),
+            };
+            ops.push((Op::parse(target, op.trim())?, weight));
+        }
+        Some(Mix(ops))
+    }
+
+    /// 按权重挑一个操作，返回下标
+    fn pick(&self, roll: u64) -> usize {
+        let total: u64 = self.0.iter().map(|(_, weight)| weight).sum();
+        let mut roll = roll % total;
+        for (index, (_, weight)) in self.0.iter().enumerate() {
+            if roll < *weight {
+                return index;
+            }
+            roll -= weight;
+        }
+        self.0.len() - 1
+    }
+}
+
+pub struct BenchConfig {
+    pub target: Target,
+    pub address: String,
+    /// 并发的连接数，每个连接一个线程。聊天的每个连接实际上是同一房间里的一收一发两个连接
+    pub connections: usize,
+    pub duration: Duration,
+    pub mix: Mix,
+    pub keys: u64,
+}
+
+impl BenchConfig {
+    pub fn build(
+        mut args: impl Iterator<Item = String>,
+    ) -> result::Result<BenchConfig, &'static str> {
+        let target = args
+            .next()
+            .as_deref()
+            .and_then(Target::parse)
+            .ok_or("bench expects http, chat or redis")?;
+        let mut config = BenchConfig {
+            target,
+            address: target.default_address().to_string(),
+            connections: DEFAULT_CONNECTIONS,
+            duration: DEFAULT_DURATION,
+            mix: Mix::parse(target, target.default_mix()).ok_or("bad default mix")?,
+            keys: DEFAULT_KEYS,
+        };
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--address" => {
+                    config.address = match args.next() {
+                        Some(address) => address,
+                        None => return Err("--address expects host:port"),
+                    }
+                }
+                "--connections" => {
+                    config.connections = match args.next().and_then(|n| n.parse().ok()) {
+                        Some(n) if n > 0 => n,
+                        _ => return Err("--connections expects a positive number"),
+                    }
+                }
+                "--duration" => {
+                    config.duration = match args.next().and_then(|d| parse_duration(&d)) {
+                        Some(duration) if !duration.is_zero() => duration,
+                        _ => return Err("--duration expects a duration such as 10s"),
+                    }
+                }
+                "--mix" => {
+                    config.mix = match args.next().and_then(|mix| Mix::parse(target, &mix)) {
+                        Some(mix) => mix,
+                        None => {
+                            return Err("--mix expects op[=weight],... such as \"GET /=9,POST /upload=1\", say,history or get,set")
+                        }
+                    }
+                }
+                "--keys" => {
+                    config.keys = match args.next().and_then(|n| n.parse().ok()) {
+                        Some(n) if n > 0 => n,
+                        _ => return Err("--keys expects a positive number"),
+                    }
+                }
+                _ => return Err("unknown bench option"),
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// 一种操作的延迟(微秒)和按原因分类的错误数
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Stats {
+    latencies: Vec<u32>,
+    pub errors: BTreeMap<String, usize>,
+}
+
+impl Stats {
+    fn record(&mut self, latency: Duration) {
+        self.latencies
+            .push(latency.as_micros().min(u32::MAX as u128) as u32);
+    }
+
+    fn error(&mut self, reason: String) {
+        *self.errors.entry(reason).or_default() += 1;
+    }
+
+    fn merge(&mut self, other: Stats) {
+        self.latencies.extend(other.latencies);
+        for (reason, count) in other.errors {
+            *self.errors.entry(reason).or_default() += count;
+        }
+    }
+
+    /// 成功的请求数
+    pub fn count(&self) -> usize {
+        self.latencies.len()
+    }
+
+    pub fn error_count(&self) -> usize {
+        self.errors.values().sum()
+    }
+
+    /// 最近秩法，`latencies` 要先排好序
+    pub fn percentile(&self, p: f64) -> Option<Duration> {
+        let rank = (p / 100.0 * self.latencies.len() as f64).ceil() as usize;
+        let micros = self.latencies.get(rank.max(1) - 1)?;
+        Some(Duration::from_micros(u64::from(*micros)))
+    }
+}
+
+pub struct Report {
+    pub title: String,
+    pub elapsed: Duration,
+    pub ops: Vec<(String, Stats)>,
+    pub total: Stats,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ms = |latency: Option<Duration>| match latency {
+            Some(latency) => format!("{:.2}ms", latency.as_secs_f64() * 1000.0),
+            None => "-".to_string(),
+        };
+        let row = |f: &mut fmt::Formatter<'_>, name: &str, stats: &Stats| {
+            writeln!(
+                f,
+                "{name:<20} {:>9} {:>7} {:>9} {:>9} {:>9} {:>9}",
+                stats.count(),
+                stats.error_count(),
+                ms(stats.percentile(50.0)),
+                ms(stats.percentile(95.0)),
+                ms(stats.percentile(99.0)),
+                ms(stats.percentile(100.0)),
+            )
+        };
+        writeln!(f, "{}", self.title)?;
+        writeln!(
+            f,
+            "{:<20} {:>9} {:>7} {:>9} {:>9} {:>9} {:>9}",
+            "operation", "ok", "errors", "p50", "p95", "p99", "max"
+        )?;
+        for (name, stats) in &self.ops {
+            row(f, name, stats)?;
+        }
+        if self.ops.len() > 1 {
+            row(f, "total", &self.total)?;
+        }
+        let secs = self.elapsed.as_secs_f64();
+        writeln!(
+            f,
+            "throughput: {:.1} requests/s over {secs:.1}s",
+            self.total.count() as f64 / secs
+        )?;
+        for (reason, count) in &self.total.errors {
+            writeln!(f, "error: {reason} x{count}")?;
+        }
+        Ok(())
+    }
+}
+
+/// 每个连接一个线程，按请求组合一个接一个地发，直到时间用完。
+/// 出错的连接丢掉重连，建立连接的时间不算进延迟
+pub fn run(config: &BenchConfig) -> Report {
+    let started = Instant::now();
+    let deadline = started + config.duration;
+    let results: Vec<Vec<Stats>> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..config.connections)
+            .map(|worker| {
+                scope.spawn(move || match config.target {
+                    Target::Http => drive::<HttpSession>(config, worker, deadline),
+                    Target::Chat => drive::<ChatSession>(config, worker, deadline),
+                    Target::Redis => drive::<RedisSession>(config, worker, deadline),
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .map(|worker| worker.join().expect("bench worker panicked"))
+            .collect()
+    });
+    let elapsed = started.elapsed();
+
+    let mut ops: Vec<(String, Stats)> = config
+        .mix
+        .0
+        .iter()
+        .map(|(op, _)| (op.to_string(), Stats::default()))
+        .collect();
+    for worker in results {
+        for (index, stats) in worker.into_iter().enumerate() {
+            ops[index].1.merge(stats);
+        }
+    }
+    let mut total = Stats::default();
+    for (_, stats) in &mut ops {
+        stats.latencies.sort_unstable();
+        total.merge(stats.clone());
+    }
+    total.latencies.sort_unstable();
+    Report {
+        title: format!(
+            "{} {}: {} connections",
+            config.target.name(),
+            config.address,
+            config.connections
+        ),
+        elapsed,
+        ops,
+        total,
+    }
+}
+
+trait Session: Sized {
+    fn connect(config: &BenchConfig, rng: &mut Rng) -> result::Result<Self, String>;
+
+    /// 返回连接还能不能接着用
+    fn call(
+        &mut self,
+        op: &Op,
+        config: &BenchConfig,
+        rng: &mut Rng,
+    ) -> result::Result<bool, String>;
+}
+
+fn drive<S: Session>(config: &BenchConfig, worker: usize, deadline: Instant) -> Vec<Stats> {
+    let mut stats = vec![Stats::default(); config.mix.0.len()];
+    let mut rng = Rng::new(worker);
+    let mut session: Option<S> = None;
+    while Instant::now() < deadline {
+        let index = config.mix.pick(rng.next());
+        let current = match &mut session {
+            Some(current) => current,
+            None => match S::connect(config, &mut rng) {
+                Ok(connected) => session.insert(connected),
+                Err(err) => {
+                    stats[index].error(format!("connect: {err}"));
+                    thread::sleep(RETRY_DELAY);
+                    continue;
+                }
+            },
+        };
+        let started = Instant::now();
+        match current.call(&config.mix.0[index].0, config, &mut rng) {
+            Ok(keep) => {
+                stats[index].record(started.elapsed());
+                if !keep {
+                    session = None;
+                }
+            }
+            Err(err) => {
+                stats[index].error(err);
+                session = None;
+            }
+        }
+    }
+    stats
+}
+
+fn connect(address: &str) -> io::Result<TcpStream> {
+    let stream = TcpStream::connect(address)?;
+    // 请求分几次写出时不能被 Nagle 攒着，否则延迟里全是 40ms 的延迟确认
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    Ok(stream)
+}
+
+struct HttpSession {
+    stream: TcpStream,
+    reader: HttpReader<TcpStream>,
+}
+
+impl Session for HttpSession {
+    fn connect(config: &BenchConfig, _: &mut Rng) -> result::Result<HttpSession, String> {
+        let stream = connect(&config.address).map_err(|err| err.to_string())?;
+        let reader = HttpReader::new(stream.try_clone().map_err(|err| err.to_string())?)
+            .with_max_body(MAX_RESPONSE_SIZE);
+        Ok(HttpSession { stream, reader })
+    }
+
+    fn call(&mut self, op: &Op, config: &BenchConfig, _: &mut Rng) -> result::Result<bool, String> {
+        let Op::Http { method, target } = op else {
+            return Err(format!("{op} is not an http request"));
+        };
+        let mut request = parse_head(&format!(
+            "{method} {target} HTTP/1.1\r\nHost: {}\r\nUser-Agent: rust_commandlines-bench",
+            config.address
+        ))
+        .map_err(|err| err.to_string())?;
+        if matches!(method.as_str(), "POST" | "PUT" | "PATCH") {
+            request.headers.push((
+                "Content-Type".to_string(),
+                "application/x-www-form-urlencoded".to_string(),
+            ));
+            request.body = b"bench=1".to_vec();
+        }
+        request
+            .write_to(&self.stream)
+            .map_err(|err| err.to_string())?;
+        let response = self
+            .reader
+            .read_response(method == "HEAD")
+            .map_err(|err| err.to_string())?;
+        if response.status >= 400 {
+            return Err(format!("status {}", response.status));
+        }
+        Ok(!response
+            .header("Connection")
+            .is_some_and(|value| value.eq_ignore_ascii_case("close")))
+    }
+}
+
+/// 两个连接进同一个新房间，一个发一个收
+struct ChatSession {
+    sender: BufReader<TcpStream>,
+    listener: BufReader<TcpStream>,
+    room: String,
+    seq: u64,
+}
+
+impl ChatSession {
+    fn token(&self) -> String {
+        format!("{} {}", self.room, self.seq)
+    }
+}
+
+impl Session for ChatSession {
+    fn connect(config: &BenchConfig, rng: &mut Rng) -> result::Result<ChatSession, String> {
+        let open = || -> io::Result<BufReader<TcpStream>> {
+            Ok(BufReader::new(connect(&config.address)?))
+        };
+        let (sender, listener) = (open(), open());
+        let mut session = ChatSession {
+            sender: sender.map_err(|err| err.to_string())?,
+            listener: listener.map_err(|err| err.to_string())?,
+            room: format!("bench-{:x}", rng.next()),
+            seq: 0,
+        };
+        for conn in [&session.sender, &session.listener] {
+            writeln!(conn.get_ref(), "/join {}", session.room).map_err(|err| err.to_string())?;
+        }
+        // 先走一遍，把进默认房间时回放的消息读掉
+        session.call(&Op::Say, config, rng)?;
+        session.call(&Op::History, config, rng)?;
+        Ok(session)
+    }
+
+    fn call(&mut self, op: &Op, _: &BenchConfig, _: &mut Rng) -> result::Result<bool, String> {
+        let result = match op {
+            Op::Say => {
+                self.seq += 1;
+                let token = self.token();
+                writeln!(self.sender.get_ref(), "{token}")
+                    .and_then(|()| read_until(&mut self.listener, &token))
+            }
+            Op::History => {
+                let token = self.token();
+                writeln!(self.sender.get_ref(), "/history 1")
+                    .and_then(|()| read_until(&mut self.sender, &token))
+            }
+            op => return Err(format!("{op} is not a chat operation")),
+        };
+        result.map(|()| true).map_err(|err| err.to_string())
+    }
+}
+
+/// 读到以 `token` 结尾的行为止，顺便回应心跳
+fn read_until(reader: &mut BufReader<TcpStream>, token: &str) -> io::Result<()> {
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed",
+            ));
+        }
+        let line = line.trim_end();
+        if chat::heartbeat_token(line).is_some() {
+            writeln!(reader.get_ref(), "PONG")?;
+        } else if line.ends_with(token) {
+            return Ok(());
+        }
+    }
+}
+
+/// mini-redis 的阻塞客户端没有超时，服务端卡住时这个线程也会卡住
+struct RedisSession {
+    client: BlockingClient,
+}
+
+impl Session for RedisSession {
+    fn connect(config: &BenchConfig, _: &mut Rng) -> result::Result<RedisSession, String> {
+        let client = blocking_client::connect(&config.address).map_err(|err| err.to_string())?;
+        Ok(RedisSession { client })
+    }
+
+    fn call(
+        &mut self,
+        op: &Op,
+        config: &BenchConfig,
+        rng: &mut Rng,
+    ) -> result::Result<bool, String> {
+        let key = format!("bench:{}", rng.next() % config.keys);
+        let result = match op {
+            Op::Get => self.client.get(&key).map(|_| ()),
+            Op::Set => self.client.set(&key, Bytes::from_static(b"bench")),
+            op => return Err(format!("{op} is not a redis command")),
+        };
+        result.map(|()| true).map_err(|err| err.to_string())
+    }
+}
+
+/// xorshift64，挑操作和键用，不需要密码学强度
+struct Rng(u64);
+
+impl Rng {
+    fn new(worker: usize) -> Rng {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_nanos() as u64);
+        Rng((nanos ^ (worker as u64 + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15)) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Response;
+    use std::net::TcpListener;
+
+    #[test]
+    fn build_config() {
+        let args = [
+            "http",
+            "--address",
+            "127.0.0.1:9000",
+            "--connections",
+            "32",
+            "--duration",
+            "1m",
+            "--mix",
+            "GET /=8, GET /kv?prefix=a, POST /upload=2",
+        ]
+        .map(String::from);
+        let config = BenchConfig::build(args.into_iter()).unwrap();
+        assert_eq!(config.target, Target::Http);
+        assert_eq!(config.address, "127.0.0.1:9000");
+        assert_eq!(config.connections, 32);
+        assert_eq!(config.duration, Duration::from_secs(60));
+        let op = |method: &str, target: &str| Op::Http {
+            method: method.to_string(),
+            target: target.to_string(),
+        };
+        assert_eq!(
+            config.mix,
+            Mix(vec![
+                (op("GET", "/"), 8),
+                (op("GET", "/kv?prefix=a"), 1),
+                (op("POST", "/upload"), 2)
+            ])
+        );
+        let picks: Vec<usize> = (0..11).map(|roll| config.mix.pick(roll)).collect();
+        assert_eq!(picks, [0, 0, 0, 0, 0, 0, 0, 0, 1, 2, 2]);
+
+        let config = BenchConfig::build(["redis".to_string()].into_iter()).unwrap();
+        assert_eq!(config.address, kv::DEFAULT_ADDRESS);
+        assert_eq!(config.mix, Mix(vec![(Op::Get, 9), (Op::Set, 1)]));
+        assert_eq!(
+            Mix::parse(Target::Chat, "say=3,history"),
+            Some(Mix(vec![(Op::Say, 3), (Op::History, 1)]))
+        );
+        assert_eq!(Mix::parse(Target::Chat, "get"), None);
+        assert_eq!(Mix::parse(Target::Redis, "get=0"), None);
+        assert_eq!(Mix::parse(Target::Http, "get /"), None);
+        assert!(BenchConfig::build(["ftp".to_string()].into_iter()).is_err());
+    }
+
+    #[test]
+    fn percentiles() {
+        let mut stats = Stats::default();
+        for ms in (1..=100).rev() {
+            stats.record(Duration::from_millis(ms));
+        }
+        stats.error("status 500".to_string());
+        stats.latencies.sort_unstable();
+        let ms = |ms| Some(Duration::from_millis(ms));
+        assert_eq!(stats.percentile(50.0), ms(50));
+        assert_eq!(stats.percentile(95.0), ms(95));
+        assert_eq!(stats.percentile(99.0), ms(99));
+        assert_eq!(stats.percentile(100.0), ms(100));
+        assert_eq!(stats.percentile(0.0), ms(1));
+        assert_eq!(Stats::default().percentile(50.0), None);
+
+        let report = Report {
+            title: "http 127.0.0.1:7878: 1 connections".to_string(),
+            elapsed: Duration::from_secs(2),
+            ops: vec![("GET /".to_string(), stats.clone())],
+            total: stats,
+        };
+        let text = report.to_string();
+        assert!(text.contains(
+            "GET /                      100       1   50.00ms   95.00ms   99.00ms  100.00ms"
+        ));
+        assert!(text.contains("throughput: 50.0 requests/s"));
+        assert!(text.contains("error: status 500 x1"));
+        assert!(!text.contains("total"));
+    }
+
+    #[test]
+    fn bench_http_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                thread::spawn(move || {
+                    let mut reader = HttpReader::new(&stream);
+                    while let Ok(Some(request)) = reader.read_request() {
+                        let response = match request.path.as_str() {
+                            "/" => Response::text(200, "ok"),
+                            _ => Response::error(404),
+                        };
+                        if response.write_to(&stream, true).is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        let config = BenchConfig {
+            target: Target::Http,
+            address,
+            connections: 2,
+            duration: Duration::from_millis(300),
+            mix: Mix::parse(Target::Http, "GET /=3,GET /missing").unwrap(),
+            keys: DEFAULT_KEYS,
+        };
+        let report = run(&config);
+        let (ok, missing) = (&report.ops[0].1, &report.ops[1].1);
+        assert!(ok.count() > 0 && ok.error_count() == 0);
+        assert_eq!(missing.count(), 0);
+        assert_eq!(missing.errors.keys().collect::<Vec<_>>(), ["status 404"]);
+        assert_eq!(report.total.count(), ok.count());
+        assert!(report.total.percentile(99.0) >= report.total.percentile(50.0));
+    }
+}
@@ -0,0 +1,456 @@
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    io::{self, Read, Write},
+    os::unix::process::CommandExt,
+    path::PathBuf,
+    process::{Child, Command, Stdio},
+    str,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::http::{find_head_end, parse_headers, Request, Response};
+use crate::router::parse_prefix;
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+/// 脚本的输出整个读进内存，超过这个大小回 502
+pub const MAX_OUTPUT_SIZE: u64 = 64 * 1024 * 1024;
+const SERVER_SOFTWARE: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+/// `--cgi /report=scripts/report.sh`
+#[derive(Debug, Clone, PartialEq)]
+pub struct CgiRoute {
+    /// 不带结尾的 `/`，它后面的部分放进 `PATH_INFO`
+    pub prefix: String,
+    pub script: PathBuf,
+}
+
+impl CgiRoute {
+    pub fn parse(spec: &str) -> Option<CgiRoute> {
+        let (prefix, script) = spec.split_once('=')?;
+        if script.is_empty() {
+            return None;
+        }
+        Some(CgiRoute {
+            prefix: parse_prefix(prefix)?,
+            script: PathBuf::from(script),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CgiConfig {
+    /// 脚本运行超过这么久就杀掉，回 504
+    pub timeout: Duration,
+    /// 同时运行的脚本数，`None` 表示比 http 工作线程少一个，留一个线程处理其他请求
+    pub max_running: Option<usize>,
+}
+
+impl Default for CgiConfig {
+    fn default() -> CgiConfig {
+        CgiConfig {
+            timeout: DEFAULT_TIMEOUT,
+            max_running: None,
+        }
+    }
+}
+
+/// 所有 CGI 路由共用的并发计数，满了直接回 503，不在工作线程里排队
+pub struct Slots {
+    running: AtomicUsize,
+    max: usize,
+}
+
+struct Slot<'a>(&'a Slots);
+
+impl Slots {
+    pub fn new(max: usize) -> Slots {
+        Slots {
+            running: AtomicUsize::new(0),
+            max,
+        }
+    }
+
+    fn acquire(&self) -> Option<Slot<'_>> {
+        self.running
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |running| {
+                (running < self.max).then_some(running + 1)
+            })
+            .ok()
+            .map(|_| Slot(self))
+    }
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        self.0.running.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// 按 CGI/1.1 运行一个脚本：请求信息放在环境变量里，正文从 stdin 传入，
+/// stdout 上的头部和正文就是响应。脚本的 stderr 直接输出到服务器的 stderr
+pub struct Cgi {
+    prefix: String,
+    script: PathBuf,
+    timeout: Duration,
+    slots: Arc<Slots>,
+}
+
+impl Cgi {
+    /// 脚本路径在启动时转成绝对路径，脚本以自己所在的目录为工作目录运行
+    pub fn new(route: &CgiRoute, timeout: Duration, slots: Arc<Slots>) -> io::Result<Cgi> {
+        let script = fs::canonicalize(&route.script)?;
+        if !script.is_file() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a file"));
+        }
+        Ok(Cgi {
+            prefix: route.prefix.clone(),
+            script,
+            timeout,
+            slots,
+        })
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    pub fn run(&self, request: &Request) -> Response {
+        let Some(_slot) = self.slots.acquire() else {
+            return Response::error(503).with_header("Retry-After", "1");
+        };
+        let mut child = match self.command(request).spawn() {
+            Ok(child) => child,
+            Err(err) => {
+                eprintln!("ERROR: could not run {}: {err}", self.script.display());
+                return Response::error(500);
+            }
+        };
+        // 正文在单独的线程里写，脚本不读 stdin 或者边读边写都不会卡住
+        if let Some(mut stdin) = child.stdin.take() {
+            let body = request.body.clone();
+            thread::spawn(move || stdin.write_all(&body));
+        }
+        let Some(stdout) = child.stdout.take() else {
+            return Response::error(500);
+        };
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut output = Vec::new();
+            let result = stdout
+                .take(MAX_OUTPUT_SIZE + 1)
+                .read_to_end(&mut output)
+                .map(|_| output);
+            let _ = sender.send(result);
+        });
+        let output = receiver.recv_timeout(self.timeout);
+        self.reap(child, output.is_err());
+        let output = match output {
+            Ok(Ok(output)) if output.len() as u64 <= MAX_OUTPUT_SIZE => output,
+            Ok(Ok(_)) => {
+                eprintln!("ERROR: {} wrote too much output", self.script.display());
+                return Response::error(502);
+            }
+            Ok(Err(err)) => {
+                eprintln!(
+                    "ERROR: could not read {} output: {err}",
+                    self.script.display()
+                );
+                return Response::error(502);
+            }
+            Err(_) => {
+                eprintln!("ERROR: {} timed out", self.script.display());
+                return Response::error(504);
+            }
+        };
+        parse_output(&output).unwrap_or_else(|| {
+            eprintln!(
+                "ERROR: {} wrote a malformed response",
+                self.script.display()
+            );
+            Response::error(502)
+        })
+    }
+
+    /// 输出读完后脚本还没退出，或者已经超时，都直接杀掉。超时的时候脚本自己可能
+    /// 已经退出了，是它留下的子进程还拿着 stdout，所以杀的是整个进程组
+    fn reap(&self, mut child: Child, timed_out: bool) {
+        if timed_out || matches!(child.try_wait(), Ok(None)) {
+            // 脚本还没被 wait，进程组号不会被别的进程复用
+            if unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) } != 0 {
+                let _ = child.kill();
+            }
+            let _ = child.wait();
+            return;
+        }
+        match child.wait() {
+            Ok(status) if !status.success() => {
+                eprintln!("ERROR: {} exited with {status}", self.script.display())
+            }
+            Ok(_) => {}
+            Err(err) => eprintln!("ERROR: could not wait for {}: {err}", self.script.display()),
+        }
+    }
+
+    fn command(&self, request: &Request) -> Command {
+        let mut command = Command::new(&self.script);
+        command
+            .env_clear()
+            .envs(self.environment(request))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .process_group(0);
+        if let Some(dir) = self.script.parent() {
+            command.current_dir(dir);
+        }
+        command
+    }
+
+    fn environment(&self, request: &Request) -> BTreeMap<String, String> {
+        let mut vars = BTreeMap::new();
+        let mut set = |name: &str, value: &str| {
+            vars.insert(name.to_string(), value.to_string());
+        };
+        if let Ok(path) = env::var("PATH") {
+            set("PATH", &path);
+        }
+        set("GATEWAY_INTERFACE", "CGI/1.1");
+        set("SERVER_SOFTWARE", SERVER_SOFTWARE);
+        set("SERVER_PROTOCOL", &request.version.to_string());
+        set("REQUEST_METHOD", &request.method);
+        set("REQUEST_URI", &request.target);
+        set("SCRIPT_NAME", &self.prefix);
+        let path_info = request.path.strip_prefix(&self.prefix).unwrap_or_default();
+        set("PATH_INFO", path_info);
+        let query = request.target.split_once('?').map(|(_, query)| query);
+        set("QUERY_STRING", query.unwrap_or_default());
+        let host = request.header("Host").unwrap_or_default();
+        let (name, port) = match host.rsplit_once(':') {
+            Some((name, port)) if port.parse::<u16>().is_ok() => (name, port),
+            _ => (host, "80"),
+        };
+        set("SERVER_NAME", name);
+        set("SERVER_PORT", port);
+        if let Some(peer) = request.peer {
+            set("REMOTE_ADDR", &peer.ip().to_string());
+            set("REMOTE_PORT", &peer.port().to_string());
+        }
+        if !request.body.is_empty() {
+            set("CONTENT_LENGTH", &request.body.len().to_string());
+        }
+        if let Some(content_type) = request.header("Content-Type") {
+            set("CONTENT_TYPE", content_type);
+        }
+        for (name, value) in &request.headers {
+            // 带下划线的头部名会和 `-` 转换后的名字混淆；`Proxy` 会变成 `HTTP_PROXY`，
+            // 被脚本里的 http 客户端当成代理设置
+            let skip = ["Content-Type", "Content-Length", "Authorization", "Proxy"]
+                .iter()
+                .any(|skip| name.eq_ignore_ascii_case(skip));
+            if skip || name.contains('_') {
+                continue;
+            }
+            let var = format!("HTTP_{}", name.to_ascii_uppercase().replace('-', "_"));
+            vars.entry(var)
+                .and_modify(|existing: &mut String| {
+                    existing.push_str(", ");
+                    existing.push_str(value);
+                })
+                .or_insert_with(|| value.clone());
+        }
+        vars
+    }
+}
+
+/// 头部和正文之间用空行隔开。`Status` 头部给出状态码，没有时有 `Location` 就是 302，否则是 200。
+/// 正文长度和连接由服务器管，脚本给的 `Content-Length` 之类的头部丢掉
+fn parse_output(output: &[u8]) -> Option<Response> {
+    let (head_len, consumed) = find_head_end(output)?;
+    let head = str::from_utf8(&output[..head_len]).ok()?;
+    let lines = head
+        .split('\n')
+        .map(|line| line.strip_suffix('\r').unwrap_or(line));
+    let mut response = Response::new(200);
+    let mut status = None;
+    for (name, value) in parse_headers(lines).ok()? {
+        if name.eq_ignore_ascii_case("Status") {
+            let code: u16 = value.split(' ').next()?.parse().ok()?;
+            if !(100..600).contains(&code) {
+                return None;
+            }
+            status = Some(code);
+        } else if !["Content-Length", "Connection", "Transfer-Encoding"]
+            .iter()
+            .any(|skip| name.eq_ignore_ascii_case(skip))
+        {
+            response.headers.push((name, value));
+        }
+    }
+    response.status = match status {
+        Some(status) => status,
+        None if response.header("Location").is_some() => 302,
+        None => 200,
+    };
+    Some(response.with_body(output[consumed..].to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{os::unix::fs::PermissionsExt, path::Path, time::Instant};
+
+    use super::*;
+    use crate::http::{parse_head, HttpConfig};
+
+    fn script(dir: &Path, name: &str, body: &str) -> CgiRoute {
+        let path = dir.join(name);
+        fs::write(&path, format!("#!/bin/sh\n{body}\n")).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        CgiRoute {
+            prefix: format!("/{name}"),
+            script: path,
+        }
+    }
+
+    fn body(response: Response) -> String {
+        String::from_utf8(response.body.into_bytes().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn run_scripts() {
+        let dir = std::env::temp_dir().join(format!("cgi-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let slots = Arc::new(Slots::new(2));
+        let env = script(
+            &dir,
+            "env",
+            r#"printf 'Content-Type: text/plain\r\nStatus: 201 Created\r\nContent-Length: 1\r\n\r\n'
+echo "$REQUEST_METHOD $SCRIPT_NAME $PATH_INFO $QUERY_STRING"
+echo "$REMOTE_ADDR $SERVER_NAME $SERVER_PORT $CONTENT_LENGTH $CONTENT_TYPE"
+echo "$HTTP_USER_AGENT|$HTTP_X_TAG|${HTTP_PROXY-none}|$(pwd)"
+cat"#,
+        );
+        let cgi = Cgi::new(&env, DEFAULT_TIMEOUT, slots.clone()).unwrap();
+        let mut request = parse_head(
+            "POST /env/a/b?x=1&y HTTP/1.1\r\nHost: example.com:8080\r\nUser-Agent: test\r\nX-Tag: 1\r\nX-Tag: 2\r\nProxy: evil\r\nContent-Type: text/plain",
+        )
+        .unwrap();
+        request.body = b"posted".to_vec();
+        request.peer = Some("10.0.0.9:5000".parse().unwrap());
+        let response = cgi.run(&request);
+        assert_eq!(response.status, 201);
+        assert_eq!(response.header("Content-Type"), Some("text/plain"));
+        assert_eq!(response.header("Content-Length"), None);
+        assert_eq!(
+            body(response),
+            format!(
+                "POST /env /a/b x=1&y\n10.0.0.9 example.com 8080 6 text/plain\ntest|1, 2|none|{}\nposted",
+                fs::canonicalize(&dir).unwrap().display()
+            )
+        );
+
+        let redirect = script(&dir, "redirect", "echo 'Location: /elsewhere'\necho");
+        let cgi = Cgi::new(&redirect, DEFAULT_TIMEOUT, slots.clone()).unwrap();
+        let request = parse_head("GET /redirect HTTP/1.1\r\nHost: h").unwrap();
+        let response = cgi.run(&request);
+        assert_eq!(response.status, 302);
+        assert_eq!(response.header("Location"), Some("/elsewhere"));
+
+        let garbage = script(&dir, "garbage", "echo no headers here");
+        let cgi = Cgi::new(&garbage, DEFAULT_TIMEOUT, slots.clone()).unwrap();
+        assert_eq!(cgi.run(&request).status, 502);
+
+        let slow = script(&dir, "slow", "exec sleep 5");
+        let cgi = Cgi::new(&slow, Duration::from_millis(200), slots.clone()).unwrap();
+        let started = Instant::now();
+        assert_eq!(cgi.run(&request).status, 504);
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        // 后台进程继承了 stdout，超时后要和脚本一起被杀掉
+        let orphan = script(&dir, "orphan", "sleep 30 &\necho $! > \"$0.pid\"");
+        let cgi = Cgi::new(&orphan, Duration::from_millis(200), slots.clone()).unwrap();
+        let started = Instant::now();
+        assert_eq!(cgi.run(&request).status, 504);
+        assert!(started.elapsed() < Duration::from_secs(2));
+        let pid = fs::read_to_string(dir.join("orphan.pid")).unwrap();
+        let stat = Path::new("/proc").join(pid.trim()).join("stat");
+        // 被杀掉的进程交给 init 回收之前是僵尸状态
+        let alive = || fs::read_to_string(&stat).is_ok_and(|stat| !stat.contains(") Z "));
+        while alive() && started.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(!alive());
+
+        let first = slots.acquire().unwrap();
+        let second = slots.acquire().unwrap();
+        let response = Cgi::new(&redirect, DEFAULT_TIMEOUT, slots.clone())
+            .unwrap()
+            .run(&request);
+        assert_eq!(response.status, 503);
+        drop(first);
+        assert!(slots.acquire().is_some());
+        drop(second);
+
+        assert!(Cgi::new(
+            &CgiRoute::parse("/x=missing.sh").unwrap(),
+            DEFAULT_TIMEOUT,
+            slots
+        )
+        .is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn parse_routes_and_output() {
+        assert_eq!(
+            CgiRoute::parse("/report/=scripts/report.sh"),
+            Some(CgiRoute {
+                prefix: "/report".to_string(),
+                script: PathBuf::from("scripts/report.sh"),
+            })
+        );
+        assert_eq!(CgiRoute::parse("/report="), None);
+        assert_eq!(CgiRoute::parse("report=x"), None);
+
+        let response = parse_output(b"Status: 404\nX-A: b\n\nmissing").unwrap();
+        assert_eq!(response.status, 404);
+        assert_eq!(response.header("X-A"), Some("b"));
+        assert_eq!(body(response), "missing");
+        assert!(parse_output(b"Status: abc\n\n").is_none());
+        assert!(parse_output(b"Status: 999\n\n").is_none());
+        assert!(parse_output(b"bad header\n\n").is_none());
+    }
+
+    #[test]
+    fn cgi_options() {
+        let args = [
+            "--cgi",
+            "/report=scripts/report.sh",
+            "--cgi-timeout",
+            "10s",
+            "--cgi-max",
+            "2",
+        ]
+        .map(String::from);
+        let config = HttpConfig::build(args.into_iter()).unwrap();
+        assert_eq!(
+            config.site.cgi,
+            [CgiRoute {
+                prefix: "/report".to_string(),
+                script: PathBuf::from("scripts/report.sh"),
+            }]
+        );
+        assert_eq!(
+            config.cgi,
+            CgiConfig {
+                timeout: Duration::from_secs(10),
+                max_running: Some(2),
+            }
+        );
+    }
+}
@@ -0,0 +1,1677 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Seek, SeekFrom, Write},
+    net::{IpAddr, Shutdown, SocketAddr},
+    path::PathBuf,
+    result,
+    sync::{
+        atomic::Ordering,
+        mpsc::{Receiver, Sender},
+        Arc,
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::auth::Credentials;
+use crate::conn::Conn;
+use crate::irc::{self, IrcMessage};
+use crate::metrics::ChatMetrics;
+use crate::moderation::{format_duration, BanList};
+use crate::tls::TlsOptions;
+use crate::transfer::{self, FileFrame};
+use crate::units::{parse_duration, parse_size};
+
+pub const DEFAULT_ROOM: &str = "general";
+pub const DEFAULT_HISTORY_SIZE: usize = 100;
+pub const DEFAULT_ADDRESS: &str = "127.0.0.1:6969";
+pub const DEFAULT_HEARTBEAT: Duration = Duration::from_secs(60);
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+pub struct ChatConfig {
+    pub address: String,
+    pub history_size: usize,
+    pub history_log: Option<PathBuf>,
+    pub credentials: Option<PathBuf>,
+    pub require_auth: bool,
+    pub admins: Vec<String>,
+    pub bans: Option<PathBuf>,
+    /// 超过这么久没有收到任何数据就断开，`None` 表示不限制
+    pub idle_timeout: Option<Duration>,
+    /// 客户端沉默超过这个间隔时发送 PING
+    pub heartbeat: Option<Duration>,
+    /// 同时启动 http 服务，在 `/ws` 上提供 WebSocket 入口
+    pub http_address: Option<String>,
+    /// 同时监听 IRC 协议，标准 IRC 客户端可以直接连接
+    pub irc_address: Option<String>,
+    /// 单个文件传输的大小上限，字节
+    pub max_file_size: u64,
+    pub tls: TlsOptions,
+}
+
+impl ChatConfig {
+    pub fn build(mut args: impl Iterator<Item = String>) -> result::Result<ChatConfig, &'static str> {
+        let mut config = ChatConfig {
+            address: DEFAULT_ADDRESS.to_string(),
+            history_size: DEFAULT_HISTORY_SIZE,
+            history_log: None,
+            credentials: None,
+            require_auth: false,
+            admins: Vec::new(),
+            bans: None,
+            idle_timeout: Some(DEFAULT_IDLE_TIMEOUT),
+            heartbeat: Some(DEFAULT_HEARTBEAT),
+            http_address: None,
+            irc_address: None,
+            max_file_size: transfer::DEFAULT_MAX_SIZE,
+            tls: TlsOptions::default(),
+        };
+
+        while let Some(arg) = args.next() {
+            if config.tls.parse_arg(&arg, &mut args)? {
+                continue;
+            }
+            match arg.as_str() {
+                "--address" => {
+                    config.address = match args.next() {
+                        Some(address) => address,
+                        None => return Err("--address expects host:port"),
+                    }
+                }
+                "--history" => {
+                    config.history_size = match args.next().map(|n| n.parse()) {
+                        Some(Ok(n)) => n,
+                        _ => return Err("--history expects a number of messages"),
+                    }
+                }
+                "--history-log" => {
+                    config.history_log = match args.next() {
+                        Some(path) => Some(PathBuf::from(path)),
+                        None => return Err("--history-log expects a file path"),
+                    }
+                }
+                "--credentials" => {
+                    config.credentials = match args.next() {
+                        Some(path) => Some(PathBuf::from(path)),
+                        None => return Err("--credentials expects a file path"),
+                    }
+                }
+                "--require-auth" => config.require_auth = true,
+                "--admin" => match args.next() {
+                    Some(user) => config.admins.push(user),
+                    None => return Err("--admin expects a user name"),
+                },
+                "--idle-timeout" => {
+                    config.idle_timeout = match args.next().map(|d| parse_duration(&d)) {
+                        Some(Some(duration)) => Some(duration).filter(|d| !d.is_zero()),
+                        _ => {
+                            return Err("--idle-timeout expects a duration such as 5m, 0 disables")
+                        }
+                    }
+                }
+                "--heartbeat" => {
+                    config.heartbeat = match args.next().map(|d| parse_duration(&d)) {
+                        Some(Some(duration)) => Some(duration).filter(|d| !d.is_zero()),
+                        _ => return Err("--heartbeat expects a duration such as 30s, 0 disables"),
+                    }
+                }
+                "--http" => {
+                    config.http_address = match args.next() {
+                        Some(address) => Some(address),
+                        None => return Err("--http expects host:port"),
+                    }
+                }
+                "--irc" => {
+                    config.irc_address = match args.next() {
+                        Some(address) => Some(address),
+                        None => return Err("--irc expects host:port"),
+                    }
+                }
+                "--max-file-size" => {
+                    config.max_file_size = match args.next().map(|size| parse_size(&size)) {
+                        Some(Some(size)) => size,
+                        _ => return Err("--max-file-size expects a size such as 10M"),
+                    }
+                }
+                "--bans" => {
+                    config.bans = match args.next() {
+                        Some(path) => Some(PathBuf::from(path)),
+                        None => return Err("--bans expects a file path"),
+                    }
+                }
+                _ => return Err("unknown tcpserver option"),
+            }
+        }
+        if (config.require_auth || !config.admins.is_empty()) && config.credentials.is_none() {
+            return Err("--require-auth and --admin need --credentials");
+        }
+        config.tls.validate()?;
+        Ok(config)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub room: String,
+    pub author: String,
+    pub text: String,
+    pub at: SystemTime,
+}
+
+impl Entry {
+    pub fn new(room: &str, author: &str, text: &str) -> Entry {
+        Entry {
+            room: room.to_string(),
+            author: author.to_string(),
+            text: text.to_string(),
+            at: SystemTime::now(),
+        }
+    }
+
+    // 日志格式: 秒数\t房间\t作者\t内容
+    fn to_log_line(&self) -> String {
+        let secs = self
+            .at
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        format!("{secs}\t{}\t{}\t{}", self.room, self.author, self.text)
+    }
+
+    fn from_log_line(line: &str) -> Option<Entry> {
+        let mut parts = line.splitn(4, '\t');
+        let secs = parts.next()?.parse().ok()?;
+        Some(Entry {
+            room: parts.next()?.to_string(),
+            author: parts.next()?.to_string(),
+            text: parts.next()?.to_string(),
+            at: UNIX_EPOCH + Duration::from_secs(secs),
+        })
+    }
+}
+
+/// 服务器发给行协议客户端的心跳是 `PING <秒数>`，返回其中的秒数。
+/// 别的行即使以 PING 开头也是聊天内容，不能吞掉
+pub fn heartbeat_token(line: &str) -> Option<&str> {
+    let token = line.strip_prefix("PING ")?;
+    Some(token).filter(|token| !token.is_empty() && token.bytes().all(|b| b.is_ascii_digit()))
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.author, self.text)
+    }
+}
+
+/// 每个房间保留最近 `capacity` 条消息，可选地追加写入日志文件以便重启后恢复。
+pub struct History {
+    capacity: usize,
+    rooms: HashMap<String, VecDeque<Entry>>,
+    log_path: Option<PathBuf>,
+    log: Option<File>,
+    /// 每个房间的消息在日志文件里的起始位置，`/history` 只读需要的那几行
+    offsets: HashMap<String, Vec<u64>>,
+    log_len: u64,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> History {
+        History {
+            capacity,
+            rooms: HashMap::new(),
+            log_path: None,
+            log: None,
+            offsets: HashMap::new(),
+            log_len: 0,
+        }
+    }
+
+    pub fn with_log(capacity: usize, path: PathBuf) -> io::Result<History> {
+        let mut history = History::new(capacity);
+        if path.exists() {
+            let mut reader = BufReader::new(File::open(&path)?);
+            let mut line = String::new();
+            loop {
+                line.clear();
+                let n = reader.read_line(&mut line)?;
+                if n == 0 {
+                    break;
+                }
+                if let Some(entry) = Entry::from_log_line(line.trim_end_matches(['\r', '\n'])) {
+                    history.index(&entry.room);
+                    history.remember(entry);
+                }
+                history.log_len += n as u64;
+            }
+        }
+        history.log = Some(OpenOptions::new().create(true).append(true).open(&path)?);
+        history.log_path = Some(path);
+        Ok(history)
+    }
+
+    pub fn push(&mut self, entry: Entry) -> io::Result<()> {
+        if let Some(log) = self.log.as_mut() {
+            let line = format!("{}\n", entry.to_log_line());
+            log.write_all(line.as_bytes())?;
+            self.index(&entry.room);
+            self.log_len += line.len() as u64;
+        }
+        self.remember(entry);
+        Ok(())
+    }
+
+    fn index(&mut self, room: &str) {
+        let offsets = self.offsets.entry(room.to_string()).or_default();
+        offsets.push(self.log_len);
+    }
+
+    fn remember(&mut self, entry: Entry) {
+        if self.capacity == 0 {
+            return;
+        }
+        let buffer = self.rooms.entry(entry.room.clone()).or_default();
+        if buffer.len() == self.capacity {
+            buffer.pop_front();
+        }
+        buffer.push_back(entry);
+    }
+
+    /// 新加入房间时回放的消息
+    pub fn recent(&self, room: &str) -> impl Iterator<Item = &Entry> {
+        self.rooms.get(room).into_iter().flatten()
+    }
+
+    /// 最近 `n` 条消息，内存里不够时从日志文件中读取更早的记录
+    pub fn last(&self, room: &str, n: usize) -> io::Result<Vec<Entry>> {
+        let buffered = self.rooms.get(room).map_or(0, |buffer| buffer.len());
+        let (path, offsets) = match (&self.log_path, self.offsets.get(room)) {
+            (Some(path), Some(offsets)) if n > buffered => (path, offsets),
+            _ => {
+                let skip = buffered.saturating_sub(n);
+                return Ok(self.recent(room).skip(skip).cloned().collect());
+            }
+        };
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut entries = Vec::new();
+        let mut line = String::new();
+        for &offset in &offsets[offsets.len().saturating_sub(n)..] {
+            reader.seek(SeekFrom::Start(offset))?;
+            line.clear();
+            reader.read_line(&mut line)?;
+            entries.extend(Entry::from_log_line(line.trim_end_matches(['\r', '\n'])));
+        }
+        Ok(entries)
+    }
+}
+
+const BANNED_LIMIT: Duration = Duration::from_secs(10 * 60);
+const STRIKE_LIMIT: u32 = 3;
+/// 同一个地址两次登录尝试之间至少间隔这么久
+const LOGIN_INTERVAL: Duration = Duration::from_secs(1);
+
+pub enum Message {
+    // 客户端连接
+    ClientConnected {
+        author: Arc<Conn>,
+        protocol: Protocol,
+    },
+    // 断开连接
+    ClientDisconected {
+        author: Arc<Conn>,
+        reason: String,
+    },
+    // 消息
+    New {
+        author: Arc<Conn>,
+        msg: Payload,
+    },
+    // 管理控制台输入
+    Admin {
+        line: String,
+    },
+    // 后台线程校验完密码
+    LoginResult {
+        author: Arc<Conn>,
+        user: String,
+        verified: bool,
+    },
+    // 定时检查心跳和空闲连接
+    Tick,
+}
+
+/// 客户端线程按行解析好的输入，文件帧在这里就和聊天文本区分开
+pub enum Payload {
+    Text(String),
+    File(result::Result<FileFrame, &'static str>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    /// 一行一条消息，命令以 `/` 开头，自带客户端、WebSocket 都走这个
+    Line,
+    Irc,
+}
+
+#[derive(Debug)]
+struct Client {
+    conn: Arc<Conn>,
+    protocol: Protocol,
+    last_message: SystemTime,
+    last_seen: SystemTime,
+    last_ping: Option<SystemTime>,
+    strike_count: u32,
+    /// 空字符串表示不在任何房间，只有 IRC 客户端 PART 之后会这样
+    room: String,
+    nick: Option<String>,
+    authenticated: bool,
+    /// IRC 客户端收到 NICK 和 USER 之后才算注册完成，行协议客户端一直是 true
+    registered: bool,
+    /// IRC 的 USER 和 PASS
+    user: Option<String>,
+    password: Option<String>,
+    /// 密码正在后台校验，结果回来之前不接受新的尝试
+    login_pending: bool,
+}
+
+impl Client {
+    fn name(&self, addr: &SocketAddr) -> String {
+        self.nick.clone().unwrap_or_else(|| addr.to_string())
+    }
+
+    /// IRC 消息来源 `nick!user@host`
+    fn prefix(&self, addr: &SocketAddr) -> String {
+        let name = self.name(addr);
+        let user = self.user.as_deref().unwrap_or(&name);
+        format!("{name}!{user}@{}", addr.ip())
+    }
+
+    fn irc_target(&self) -> &str {
+        self.nick.as_deref().unwrap_or("*")
+    }
+
+    /// 服务器提示，IRC 客户端收到的是 NOTICE
+    fn notice(&self, text: &str) -> String {
+        match self.protocol {
+            Protocol::Line => text.to_string(),
+            Protocol::Irc => {
+                IrcMessage::new(Some(irc::SERVER_NAME), "NOTICE", &[self.irc_target(), text])
+                    .to_string()
+            }
+        }
+    }
+
+    fn render(&self, entry: &Entry) -> String {
+        match self.protocol {
+            Protocol::Line => entry.to_string(),
+            Protocol::Irc => IrcMessage::new(
+                Some(&entry.author),
+                "PRIVMSG",
+                &[&irc::channel(&entry.room), &entry.text],
+            )
+            .to_string(),
+        }
+    }
+}
+
+enum NickError {
+    Reserved,
+    Taken,
+}
+
+const HISTORY_PAGE: usize = 20;
+const ADMIN_COMMANDS: &[&str] = &["/kick", "/ban", "/unban", "/bans", "/stats"];
+/// 一条 353 回复里最多放这么多昵称，避免超过 IRC 的 512 字节行长
+const NAMES_PER_REPLY: usize = 20;
+
+/// 经过服务器中转的文件传输，接收方看到的编号是 `ChatServer::transfers` 的 key
+struct Transfer {
+    sender: SocketAddr,
+    sender_id: u64,
+    recipient: SocketAddr,
+    size: u64,
+    received: u64,
+    accepted: bool,
+}
+
+pub struct ChatServer {
+    config: ChatConfig,
+    clients: HashMap<SocketAddr, Client>,
+    history: History,
+    credentials: Option<Arc<Credentials>>,
+    banned_mfs: BanList,
+    metrics: Arc<ChatMetrics>,
+    started: SystemTime,
+    transfers: HashMap<u64, Transfer>,
+    next_transfer: u64,
+    /// 登录校验的结果通过它发回主循环
+    sender: Sender<Message>,
+    /// 每个地址最近一次登录尝试的时间
+    login_attempts: HashMap<IpAddr, SystemTime>,
+}
+
+impl ChatServer {
+    pub fn new(
+        config: ChatConfig,
+        history: History,
+        credentials: Option<Credentials>,
+        banned_mfs: BanList,
+        metrics: Arc<ChatMetrics>,
+        sender: Sender<Message>,
+    ) -> ChatServer {
+        ChatServer {
+            config,
+            clients: HashMap::new(),
+            history,
+            credentials: credentials.map(Arc::new),
+            banned_mfs,
+            metrics,
+            started: SystemTime::now(),
+            transfers: HashMap::new(),
+            next_transfer: 1,
+            sender,
+            login_attempts: HashMap::new(),
+        }
+    }
+
+    fn find_addr(&self, author: &Arc<Conn>) -> Option<SocketAddr> {
+        self.clients
+            .iter()
+            .find(|(_, client)| Arc::ptr_eq(&client.conn, author))
+            .map(|(addr, _)| *addr)
+    }
+
+    fn nick_taken(&self, current: &SocketAddr, nick: &str) -> bool {
+        self.clients
+            .iter()
+            .any(|(addr, client)| addr != current && client.nick.as_deref() == Some(nick))
+    }
+
+    fn is_reserved(&self, nick: &str) -> bool {
+        self.credentials
+            .as_ref()
+            .is_some_and(|credentials| credentials.is_reserved(nick))
+    }
+
+    /// 所有移除客户端的地方都走这里
+    fn disconnect(&mut self, addr: &SocketAddr, reason: &str) {
+        if let Some(client) = self.clients.remove(addr) {
+            eprintln!("[INFO] {addr} disconnected: {reason}");
+            let _ = client.conn.shutdown(Shutdown::Both);
+            if client.registered {
+                let quit = IrcMessage::new(Some(&client.prefix(addr)), "QUIT", &[reason]);
+                self.announce(&client.room, &quit);
+            }
+            let transfers: Vec<u64> = self
+                .transfers
+                .iter()
+                .filter(|(_, transfer)| transfer.sender == *addr || transfer.recipient == *addr)
+                .map(|(id, _)| *id)
+                .collect();
+            for id in transfers {
+                self.end_transfer(id, "peer disconnected");
+            }
+        }
+    }
+
+    fn kick(&mut self, addr: &SocketAddr, notice: &str) {
+        if let Some(client) = self.clients.get(addr) {
+            let line = match client.protocol {
+                Protocol::Line => notice.to_string(),
+                Protocol::Irc => {
+                    IrcMessage::new(None, "ERROR", &[&format!("Closing link: {notice}")])
+                        .to_string()
+                }
+            };
+            let _ = writeln!(client.conn.as_ref(), "{line}");
+        }
+        self.disconnect(addr, notice);
+    }
+
+    /// 写入失败的客户端视为已经断开
+    fn send_line(&mut self, addr: &SocketAddr, line: &str) {
+        let Some(client) = self.clients.get(addr) else {
+            return;
+        };
+        if let Err(err) = writeln!(client.conn.as_ref(), "{line}") {
+            self.disconnect(addr, &format!("write failed: {err}"));
+        }
+    }
+
+    fn tell(&mut self, addr: &SocketAddr, text: &str) {
+        if let Some(client) = self.clients.get(addr) {
+            let line = client.notice(text);
+            self.send_line(addr, &line);
+        }
+    }
+
+    fn reply(&mut self, addr: &SocketAddr, code: &str, params: &[&str]) {
+        if let Some(client) = self.clients.get(addr) {
+            let line = irc::reply(code, client.irc_target(), params).to_string();
+            self.send_line(addr, &line);
+        }
+    }
+
+    fn replay(&mut self, addr: &SocketAddr, entries: &[Entry]) {
+        for entry in entries {
+            let Some(client) = self.clients.get(addr) else {
+                return;
+            };
+            let line = client.render(entry);
+            self.send_line(addr, &line);
+        }
+    }
+
+    /// 进出房间和改名只通知房间里的 IRC 客户端，行协议客户端看不到
+    fn announce(&mut self, room: &str, message: &IrcMessage) {
+        if room.is_empty() {
+            return;
+        }
+        let line = message.to_string();
+        let receivers: Vec<SocketAddr> = self
+            .clients
+            .iter()
+            .filter(|(_, client)| {
+                client.protocol == Protocol::Irc && client.registered && client.room == room
+            })
+            .map(|(addr, _)| *addr)
+            .collect();
+        for addr in receivers {
+            self.send_line(&addr, &line);
+        }
+    }
+
+    fn connected(&mut self, author: Arc<Conn>, protocol: Protocol) {
+        let Ok(author_addr) = author.peer_addr() else {
+            return;
+        };
+        let now = SystemTime::now();
+        if self.banned_mfs.is_banned(&author_addr.ip(), now) {
+            let _ = match protocol {
+                Protocol::Line => writeln!(author.as_ref(), "You are banned"),
+                Protocol::Irc => writeln!(author.as_ref(), "ERROR :Closing link: You are banned"),
+            };
+            let _ = author.as_ref().shutdown(Shutdown::Both);
+            return;
+        }
+        author.count_traffic(self.metrics.traffic.clone());
+        self.metrics
+            .connections_total
+            .fetch_add(1, Ordering::Relaxed);
+        let registered = protocol == Protocol::Line;
+        self.clients.insert(
+            author_addr,
+            Client {
+                conn: author,
+                protocol,
+                last_message: now,
+                last_seen: now,
+                last_ping: None,
+                strike_count: 0,
+                room: String::new(),
+                nick: None,
+                authenticated: false,
+                registered,
+                user: None,
+                password: None,
+                login_pending: false,
+            },
+        );
+        if registered {
+            if self.config.require_auth {
+                self.tell(
+                    &author_addr,
+                    "Please authenticate: /login <user> <password>",
+                );
+            }
+            self.join(&author_addr, DEFAULT_ROOM);
+        }
+    }
+
+    /// 换房间时先离开原来的房间，再回放新房间的最近消息
+    fn join(&mut self, addr: &SocketAddr, room: &str) {
+        self.part(addr);
+        let Some(client) = self.clients.get_mut(addr) else {
+            return;
+        };
+        client.room = room.to_string();
+        let protocol = client.protocol;
+        let replay = !self.config.require_auth || client.authenticated;
+        let join = IrcMessage::new(Some(&client.prefix(addr)), "JOIN", &[&irc::channel(room)]);
+        self.announce(room, &join);
+        if protocol == Protocol::Irc {
+            self.names(addr, room);
+        }
+        if replay {
+            let recent: Vec<Entry> = self.history.recent(room).cloned().collect();
+            self.replay(addr, &recent);
+        }
+    }
+
+    fn part(&mut self, addr: &SocketAddr) {
+        let Some(client) = self.clients.get(addr) else {
+            return;
+        };
+        let room = client.room.clone();
+        let part = IrcMessage::new(Some(&client.prefix(addr)), "PART", &[&irc::channel(&room)]);
+        // 先通知再离开，这样离开的 IRC 客户端自己也能收到 PART
+        self.announce(&room, &part);
+        if let Some(client) = self.clients.get_mut(addr) {
+            client.room.clear();
+        }
+    }
+
+    fn names(&mut self, addr: &SocketAddr, room: &str) {
+        let mut nicks: Vec<String> = self
+            .clients
+            .iter()
+            .filter(|(_, client)| client.registered && client.room == room)
+            .map(|(addr, client)| client.name(addr))
+            .collect();
+        nicks.sort();
+        let channel = irc::channel(room);
+        for chunk in nicks.chunks(NAMES_PER_REPLY) {
+            self.reply(addr, irc::RPL_NAMREPLY, &["=", &channel, &chunk.join(" ")]);
+        }
+        self.reply(addr, irc::RPL_ENDOFNAMES, &[&channel, "End of /NAMES list"]);
+    }
+
+    fn change_nick(&mut self, addr: &SocketAddr, nick: &str) -> result::Result<(), NickError> {
+        let reserved = self.is_reserved(nick);
+        let taken = self.nick_taken(addr, nick);
+        let Some(client) = self.clients.get_mut(addr) else {
+            return Ok(());
+        };
+        if reserved && client.nick.as_deref() != Some(nick) {
+            return Err(NickError::Reserved);
+        }
+        if taken {
+            return Err(NickError::Taken);
+        }
+        // 改名不影响登录状态，保留的昵称只有登录成这个用户才能用，不会借此拿到别人的权限
+        let message = IrcMessage::new(Some(&client.prefix(addr)), "NICK", &[nick]);
+        client.nick = Some(nick.to_string());
+        let (room, protocol) = (client.room.clone(), client.protocol);
+        self.announce(&room, &message);
+        if room.is_empty() && protocol == Protocol::Irc {
+            self.send_line(addr, &message.to_string());
+        }
+        Ok(())
+    }
+
+    /// 密码错误记一次 strike，达到上限后封禁并断开，返回是否已经断开
+    fn strike(&mut self, addr: &SocketAddr, user: &str) -> bool {
+        let Some(client) = self.clients.get_mut(addr) else {
+            return true;
+        };
+        client.strike_count += 1;
+        self.metrics.strikes_total.fetch_add(1, Ordering::Relaxed);
+        eprintln!("[INFO] failed login for {user} from {addr}");
+        if client.strike_count < STRIKE_LIMIT {
+            return false;
+        }
+        if let Err(err) = self.banned_mfs.ban(addr.ip(), Some(BANNED_LIMIT)) {
+            eprintln!("ERROR: could not save bans: {err}");
+        }
+        self.kick(addr, "You are banned");
+        true
+    }
+
+    /// PBKDF2 很慢，放到后台线程里算，结果通过 `Message::LoginResult` 发回来。
+    /// 同一个地址的尝试太频繁时直接拒绝，不去算哈希
+    fn start_login(&mut self, addr: &SocketAddr, user: &str, password: &str) {
+        let Some(credentials) = self.credentials.clone() else {
+            return;
+        };
+        let now = SystemTime::now();
+        let recent = self.login_attempts.get(&addr.ip()).is_some_and(|at| {
+            now.duration_since(*at)
+                .is_ok_and(|elapsed| elapsed < LOGIN_INTERVAL)
+        });
+        let Some(client) = self.clients.get_mut(addr) else {
+            return;
+        };
+        if client.login_pending || recent {
+            self.tell(addr, "Too many login attempts, try again later");
+            return;
+        }
+        client.login_pending = true;
+        self.login_attempts.insert(addr.ip(), now);
+        let author = client.conn.clone();
+        let sender = self.sender.clone();
+        let (user, password) = (user.to_string(), password.to_string());
+        thread::spawn(move || {
+            let verified = credentials.verify(&user, &password);
+            let _ = sender.send(Message::LoginResult {
+                author,
+                user,
+                verified,
+            });
+        });
+    }
+
+    fn login_result(&mut self, author: &Arc<Conn>, user: &str, verified: bool) {
+        // 校验期间已经断开的客户端找不到，结果直接丢掉
+        let Some(addr) = self.find_addr(author) else {
+            return;
+        };
+        let Some(client) = self.clients.get_mut(&addr) else {
+            return;
+        };
+        client.login_pending = false;
+        match client.protocol {
+            Protocol::Line => self.line_login(addr, user, verified),
+            Protocol::Irc => self.irc_login(addr, user, verified),
+        }
+    }
+
+    /// 广播到发送者所在的房间并记录历史
+    fn say(&mut self, addr: &SocketAddr, text: &str) {
+        let Some(sender) = self.clients.get_mut(addr) else {
+            return;
+        };
+        sender.last_message = SystemTime::now();
+        self.metrics.messages_total.fetch_add(1, Ordering::Relaxed);
+        let entry = Entry::new(&sender.room, &sender.name(addr), text);
+        let receivers: Vec<SocketAddr> = self
+            .clients
+            .iter()
+            .filter(|(other, client)| {
+                addr != *other && client.registered && client.room == entry.room
+            })
+            .map(|(addr, _)| *addr)
+            .collect();
+        for receiver in receivers {
+            if let Some(client) = self.clients.get(&receiver) {
+                let line = client.render(&entry);
+                self.send_line(&receiver, &line);
+            }
+        }
+        if let Err(err) = self.history.push(entry) {
+            eprintln!("ERROR: could not write history: {err}");
+        }
+    }
+
+    fn is_admin(&self, addr: &SocketAddr) -> bool {
+        self.clients.get(addr).is_some_and(|client| {
+            client.authenticated
+                && client
+                    .nick
+                    .as_ref()
+                    .is_some_and(|nick| self.config.admins.contains(nick))
+        })
+    }
+
+    fn received(&mut self, author: &Arc<Conn>, msg: Payload) {
+        let Some(current_addr) = self.find_addr(author) else {
+            return;
+        };
+        let Some(sender) = self.clients.get_mut(&current_addr) else {
+            return;
+        };
+        sender.last_seen = SystemTime::now();
+        sender.last_ping = None;
+        match (sender.protocol, msg) {
+            (Protocol::Irc, Payload::Text(text)) => self.irc_message(current_addr, &text),
+            (_, Payload::Text(text)) => self.line_message(current_addr, &text),
+            (_, Payload::File(Ok(frame))) => self.file_frame(current_addr, frame),
+            (_, Payload::File(Err(err))) => {
+                self.tell(&current_addr, &format!("invalid file frame: {err}"))
+            }
+        }
+    }
+
+    /// 后台校验完 `/login` 之后回到主循环
+    fn line_login(&mut self, current_addr: SocketAddr, user: &str, verified: bool) {
+        if verified && !irc::valid_nick(user) {
+            self.tell(&current_addr, &format!("{user} is not a valid nick"));
+        } else if verified && !self.nick_taken(&current_addr, user) {
+            let Some(sender) = self.clients.get_mut(&current_addr) else {
+                return;
+            };
+            let first_login = !sender.authenticated;
+            let renamed = sender.nick.as_deref() != Some(user);
+            let nick_change = IrcMessage::new(Some(&sender.prefix(&current_addr)), "NICK", &[user]);
+            let room = sender.room.clone();
+            sender.nick = Some(user.to_string());
+            sender.authenticated = true;
+            sender.strike_count = 0;
+            if renamed {
+                self.announce(&room, &nick_change);
+            }
+            self.tell(&current_addr, &format!("Welcome, {user}"));
+            if self.config.require_auth && first_login {
+                let recent: Vec<Entry> = self.history.recent(&room).cloned().collect();
+                self.replay(&current_addr, &recent);
+            }
+        } else if verified {
+            self.tell(&current_addr, &format!("{user} is already connected"));
+        } else if !self.strike(&current_addr, user) {
+            self.tell(&current_addr, "Invalid credentials");
+        }
+    }
+
+    fn line_message(&mut self, current_addr: SocketAddr, text: &str) {
+        let mut words = text.split_whitespace();
+        let command = words.next();
+
+        if let Some("PONG") = command {
+            return;
+        }
+        if let Some("/login") = command {
+            let (Some(user), Some(password)) = (words.next(), words.next()) else {
+                self.tell(&current_addr, "Usage: /login <user> <password>");
+                return;
+            };
+            if self.credentials.is_none() {
+                self.tell(&current_addr, "login is not enabled on this server");
+                return;
+            }
+            self.start_login(&current_addr, user, password);
+            return;
+        }
+
+        let Some(sender) = self.clients.get(&current_addr) else {
+            return;
+        };
+        if self.config.require_auth && !sender.authenticated {
+            self.tell(
+                &current_addr,
+                "Please authenticate: /login <user> <password>",
+            );
+            return;
+        }
+        match command {
+            Some(command) if ADMIN_COMMANDS.contains(&command) => {
+                self.run_admin(&current_addr, text);
+            }
+            Some("/nick") => {
+                let Some(nick) = words.next() else {
+                    self.tell(&current_addr, "Usage: /nick <name>");
+                    return;
+                };
+                // 昵称会出现在 IRC 客户端收到的消息前缀里，按 IRC 的规则检查
+                if !irc::valid_nick(nick) {
+                    self.tell(&current_addr, &format!("{nick} is not a valid nick"));
+                    return;
+                }
+                match self.change_nick(&current_addr, nick) {
+                    Ok(()) => {}
+                    Err(NickError::Reserved) => {
+                        self.tell(&current_addr, &format!("{nick} is reserved, use /login"))
+                    }
+                    Err(NickError::Taken) => {
+                        self.tell(&current_addr, &format!("{nick} is already in use"))
+                    }
+                }
+            }
+            Some("/history") => {
+                let n = words
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .unwrap_or(HISTORY_PAGE);
+                match self.history.last(&sender.room, n) {
+                    Ok(entries) => self.replay(&current_addr, &entries),
+                    Err(err) => eprintln!("ERROR: could not read history: {err}"),
+                }
+            }
+            Some("/join") => {
+                let room = words.next().unwrap_or(DEFAULT_ROOM);
+                self.join(&current_addr, room);
+            }
+            // 控制字符会改动别人终端的状态
+            _ if text.contains(char::is_control) => {
+                self.tell(&current_addr, "messages may not contain control characters")
+            }
+            _ => self.say(&current_addr, text),
+        }
+    }
+
+    fn run_admin(&mut self, addr: &SocketAddr, line: &str) {
+        let command = line.split_whitespace().next().unwrap_or_default();
+        if !self.is_admin(addr) {
+            match self.clients.get(addr).map(|client| client.protocol) {
+                Some(Protocol::Irc) => {
+                    self.reply(addr, irc::ERR_NOPRIVILEGES, &["Permission Denied"])
+                }
+                _ => self.tell(addr, &format!("{command}: permission denied")),
+            }
+            return;
+        }
+        eprintln!("[INFO] {addr} ran {line}");
+        for reply in self.admin_command(line) {
+            self.tell(addr, &reply);
+        }
+    }
+
+    fn irc_message(&mut self, addr: SocketAddr, line: &str) {
+        let Some(message) = IrcMessage::parse(line) else {
+            return;
+        };
+        let Some(client) = self.clients.get_mut(&addr) else {
+            return;
+        };
+        let registered = client.registered;
+        let params: Vec<&str> = message.params.iter().map(String::as_str).collect();
+        match (message.command.as_str(), params.as_slice()) {
+            ("PONG" | "NOTICE", _) => {}
+            ("PING", [token, ..]) => {
+                let pong =
+                    IrcMessage::new(Some(irc::SERVER_NAME), "PONG", &[irc::SERVER_NAME, token]);
+                self.send_line(&addr, &pong.to_string());
+            }
+            ("CAP", [subcommand, ..]) if subcommand.eq_ignore_ascii_case("LS") => {
+                let caps = IrcMessage::new(Some(irc::SERVER_NAME), "CAP", &["*", "LS", ""]);
+                self.send_line(&addr, &caps.to_string());
+            }
+            ("CAP", _) => {}
+            ("QUIT", params) => {
+                let reason = format!("Quit: {}", params.first().unwrap_or(&"Client quit"));
+                self.kick(&addr, &reason);
+            }
+            ("PASS" | "USER", _) if registered => self.reply(
+                &addr,
+                irc::ERR_ALREADYREGISTRED,
+                &["You may not reregister"],
+            ),
+            ("PASS", [password, ..]) => client.password = Some(password.to_string()),
+            ("USER", [user, ..]) => {
+                client.user = Some(user.to_string());
+                self.register(&addr);
+            }
+            ("PASS" | "USER", _) => self.reply(
+                &addr,
+                irc::ERR_NEEDMOREPARAMS,
+                &[&message.command, "Not enough parameters"],
+            ),
+            ("NICK", []) => self.reply(&addr, irc::ERR_NONICKNAMEGIVEN, &["No nickname given"]),
+            ("NICK", [nick, ..]) if !irc::valid_nick(nick) => self.reply(
+                &addr,
+                irc::ERR_ERRONEUSNICKNAME,
+                &[nick, "Erroneous nickname"],
+            ),
+            ("NICK", [nick, ..]) if !registered => {
+                if self.nick_taken(&addr, nick) {
+                    self.reply(
+                        &addr,
+                        irc::ERR_NICKNAMEINUSE,
+                        &[nick, "Nickname is already in use"],
+                    );
+                } else if let Some(client) = self.clients.get_mut(&addr) {
+                    client.nick = Some(nick.to_string());
+                    self.register(&addr);
+                }
+            }
+            ("NICK", [nick, ..]) => match self.change_nick(&addr, nick) {
+                Ok(()) => {}
+                Err(NickError::Reserved) => self.reply(
+                    &addr,
+                    irc::ERR_NICKNAMEINUSE,
+                    &[nick, "Nickname is reserved, reconnect with PASS"],
+                ),
+                Err(NickError::Taken) => self.reply(
+                    &addr,
+                    irc::ERR_NICKNAMEINUSE,
+                    &[nick, "Nickname is already in use"],
+                ),
+            },
+            _ if !registered => {
+                self.reply(&addr, irc::ERR_NOTREGISTERED, &["You have not registered"])
+            }
+            ("JOIN", ["0", ..]) => self.part(&addr),
+            ("JOIN", [channels, ..]) => {
+                // 每个连接只能在一个房间，只取第一个频道
+                let channel = channels.split(',').next().unwrap_or_default();
+                match irc::room(channel) {
+                    Some(room) if room == client.room => {}
+                    Some(room) => self.join(&addr, room),
+                    None => {
+                        self.reply(&addr, irc::ERR_NOSUCHCHANNEL, &[channel, "No such channel"])
+                    }
+                }
+            }
+            ("PART", [channels, ..]) => {
+                let current = irc::channel(&client.room);
+                if channels.split(',').any(|channel| channel == current) {
+                    self.part(&addr);
+                } else {
+                    self.reply(
+                        &addr,
+                        irc::ERR_NOTONCHANNEL,
+                        &[channels, "You're not on that channel"],
+                    );
+                }
+            }
+            ("PRIVMSG", []) => self.reply(
+                &addr,
+                irc::ERR_NORECIPIENT,
+                &["No recipient given (PRIVMSG)"],
+            ),
+            ("PRIVMSG", [_]) => self.reply(&addr, irc::ERR_NOTEXTTOSEND, &["No text to send"]),
+            ("PRIVMSG", [target, text, ..]) => match irc::room(target) {
+                Some(room) if room == client.room => self.say(&addr, text),
+                Some(_) => self.reply(
+                    &addr,
+                    irc::ERR_CANNOTSENDTOCHAN,
+                    &[target, "Cannot send to channel"],
+                ),
+                None => self.private_message(&addr, target, text),
+            },
+            ("NAMES", []) => {
+                let room = client.room.clone();
+                if !room.is_empty() {
+                    self.names(&addr, &room);
+                }
+            }
+            ("NAMES", [channels, ..]) => {
+                for room in channels.split(',').filter_map(irc::room) {
+                    self.names(&addr, room);
+                }
+            }
+            ("MODE", [target, ..]) if irc::room(target).is_some() => {
+                self.reply(&addr, irc::RPL_CHANNELMODEIS, &[target, "+"])
+            }
+            ("MODE", _) => self.reply(&addr, irc::RPL_UMODEIS, &["+"]),
+            ("WHO", params) => {
+                let mask = params.first().unwrap_or(&"*");
+                self.reply(&addr, irc::RPL_ENDOFWHO, &[mask, "End of WHO list"]);
+            }
+            // 管理命令用原始命令发送，例如 irssi 里的 `/quote KICK alice`
+            (command, params)
+                if ADMIN_COMMANDS
+                    .contains(&format!("/{}", command.to_ascii_lowercase()).as_str()) =>
+            {
+                let mut words = vec![format!("/{}", command.to_ascii_lowercase())];
+                words.extend(
+                    params
+                        .iter()
+                        .filter(|param| irc::room(param).is_none())
+                        .map(|param| param.to_string()),
+                );
+                self.run_admin(&addr, &words.join(" "));
+            }
+            (command, _) => self.reply(
+                &addr,
+                irc::ERR_UNKNOWNCOMMAND,
+                &[command, "Unknown command"],
+            ),
+        }
+    }
+
+    /// NICK 和 USER 都收到后完成注册，保留的昵称和 `--require-auth` 都要求先发 PASS
+    fn register(&mut self, addr: &SocketAddr) {
+        let Some(client) = self.clients.get(addr) else {
+            return;
+        };
+        let (Some(nick), Some(_)) = (client.nick.clone(), &client.user) else {
+            return;
+        };
+        // 密码还在后台校验，结果回来时会再走一次这里
+        if client.login_pending {
+            return;
+        }
+        if !self.is_reserved(&nick) && !self.config.require_auth {
+            self.welcome(addr, false);
+            return;
+        }
+        let password = client.password.clone();
+        if self.credentials.is_none() {
+            self.tell(addr, "login is not enabled on this server");
+        } else if let Some(password) = password {
+            self.start_login(addr, &nick, &password);
+        } else {
+            self.reply(addr, irc::ERR_PASSWDMISMATCH, &["Password incorrect"]);
+        }
+    }
+
+    /// 后台校验完 PASS 之后回到主循环，校验期间换了昵称就按新昵称重新注册
+    fn irc_login(&mut self, addr: SocketAddr, user: &str, verified: bool) {
+        let Some(client) = self.clients.get(&addr) else {
+            return;
+        };
+        if client.registered {
+            return;
+        }
+        if client.nick.as_deref() != Some(user) {
+            self.register(&addr);
+        } else if verified {
+            self.welcome(&addr, true);
+        } else if !self.strike(&addr, user) {
+            self.reply(&addr, irc::ERR_PASSWDMISMATCH, &["Password incorrect"]);
+        }
+    }
+
+    /// 注册完成，发送欢迎消息并加入默认房间
+    fn welcome(&mut self, addr: &SocketAddr, authenticated: bool) {
+        let Some(client) = self.clients.get_mut(addr) else {
+            return;
+        };
+        client.registered = true;
+        client.authenticated = authenticated;
+        if authenticated {
+            client.strike_count = 0;
+        }
+        let prefix = client.prefix(addr);
+        let created = self
+            .started
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.reply(
+            addr,
+            irc::RPL_WELCOME,
+            &[&format!("Welcome to the chat {prefix}")],
+        );
+        self.reply(
+            addr,
+            irc::RPL_YOURHOST,
+            &[&format!(
+                "Your host is {}, running version {}",
+                irc::SERVER_NAME,
+                env!("CARGO_PKG_VERSION")
+            )],
+        );
+        self.reply(
+            addr,
+            irc::RPL_CREATED,
+            &[&format!("This server was created {created}")],
+        );
+        self.reply(
+            addr,
+            irc::RPL_MYINFO,
+            &[irc::SERVER_NAME, env!("CARGO_PKG_VERSION"), "o", "o"],
+        );
+        self.reply(addr, irc::ERR_NOMOTD, &["MOTD File is missing"]);
+        self.join(addr, DEFAULT_ROOM);
+    }
+
+    fn file_frame(&mut self, addr: SocketAddr, frame: FileFrame) {
+        let Some(client) = self.clients.get(&addr) else {
+            return;
+        };
+        if self.config.require_auth && !client.authenticated {
+            self.tell(&addr, "Please authenticate: /login <user> <password>");
+            return;
+        }
+        match frame {
+            FileFrame::Offer {
+                id,
+                peer,
+                size,
+                sha256,
+                name,
+            } => {
+                let name = transfer::sanitize_name(&name);
+                let sender_name = client.name(&addr);
+                let recipient = self
+                    .clients
+                    .iter()
+                    .find(|(_, client)| client.registered && client.nick.as_deref() == Some(&peer));
+                let refusal = match recipient {
+                    _ if size > self.config.max_file_size => Some(format!(
+                        "file too large, limit is {}",
+                        transfer::format_size(self.config.max_file_size)
+                    )),
+                    None => Some(format!("no such user: {peer}")),
+                    Some((recipient, _)) if *recipient == addr => {
+                        Some("cannot send a file to yourself".to_string())
+                    }
+                    Some((_, client))
+                        if client.protocol == Protocol::Irc || client.conn.is_websocket() =>
+                    {
+                        Some(format!("{peer} cannot receive files"))
+                    }
+                    Some(_) => None,
+                };
+                if let Some(reason) = refusal {
+                    self.send_line(&addr, &FileFrame::Reject { id, reason }.to_string());
+                    return;
+                }
+                let Some((&recipient, _)) = recipient else {
+                    return;
+                };
+                let server_id = self.next_transfer;
+                self.next_transfer += 1;
+                self.transfers.insert(
+                    server_id,
+                    Transfer {
+                        sender: addr,
+                        sender_id: id,
+                        recipient,
+                        size,
+                        received: 0,
+                        accepted: false,
+                    },
+                );
+                eprintln!("[INFO] {addr} offered {name} ({size} bytes) to {recipient}");
+                let offer = FileFrame::Offer {
+                    id: server_id,
+                    peer: sender_name,
+                    size,
+                    sha256,
+                    name,
+                };
+                self.send_line(&recipient, &offer.to_string());
+            }
+            FileFrame::Accept { id } => {
+                let Some(transfer) = self
+                    .transfers
+                    .get_mut(&id)
+                    .filter(|transfer| transfer.recipient == addr && !transfer.accepted)
+                else {
+                    let reason = "no such transfer".to_string();
+                    self.send_line(&addr, &FileFrame::Cancel { id, reason }.to_string());
+                    return;
+                };
+                transfer.accepted = true;
+                let (sender, sender_id) = (transfer.sender, transfer.sender_id);
+                self.send_line(&sender, &FileFrame::Accept { id: sender_id }.to_string());
+            }
+            FileFrame::Reject { id, reason } => {
+                let is_recipient = self
+                    .transfers
+                    .get(&id)
+                    .is_some_and(|transfer| transfer.recipient == addr);
+                if let (true, Some(transfer)) = (is_recipient, self.transfers.remove(&id)) {
+                    let reject = FileFrame::Reject {
+                        id: transfer.sender_id,
+                        reason,
+                    };
+                    self.send_line(&transfer.sender, &reject.to_string());
+                }
+            }
+            FileFrame::Chunk { id, .. } | FileFrame::Done { id } => {
+                let Some((&server_id, transfer)) = self
+                    .transfers
+                    .iter_mut()
+                    .find(|(_, transfer)| transfer.sender == addr && transfer.sender_id == id)
+                else {
+                    let reason = "no such transfer".to_string();
+                    self.send_line(&addr, &FileFrame::Reject { id, reason }.to_string());
+                    return;
+                };
+                if !transfer.accepted {
+                    self.end_transfer(server_id, "transfer was not accepted");
+                    return;
+                }
+                let recipient = transfer.recipient;
+                if let FileFrame::Chunk { data, .. } = &frame {
+                    transfer.received += data.len() as u64;
+                    if transfer.received > transfer.size {
+                        self.end_transfer(server_id, "more data than offered");
+                        return;
+                    }
+                } else if transfer.received != transfer.size {
+                    self.end_transfer(server_id, "less data than offered");
+                    return;
+                } else {
+                    eprintln!("[INFO] {addr} sent {} bytes to {recipient}", transfer.size);
+                    self.transfers.remove(&server_id);
+                }
+                self.send_line(&recipient, &frame.with_id(server_id).to_string());
+            }
+            FileFrame::Cancel { id, reason } => {
+                let Some(server_id) = self
+                    .transfers
+                    .iter()
+                    .find(|(_, transfer)| transfer.sender == addr && transfer.sender_id == id)
+                    .map(|(server_id, _)| *server_id)
+                else {
+                    return;
+                };
+                if let Some(transfer) = self.transfers.remove(&server_id) {
+                    let cancel = FileFrame::Cancel {
+                        id: server_id,
+                        reason,
+                    };
+                    self.send_line(&transfer.recipient, &cancel.to_string());
+                }
+            }
+        }
+    }
+
+    /// 服务器结束一次传输，发送方收到 reject，接收方收到 cancel，各自带自己那一侧的编号
+    fn end_transfer(&mut self, server_id: u64, reason: &str) {
+        let Some(transfer) = self.transfers.remove(&server_id) else {
+            return;
+        };
+        let reject = FileFrame::Reject {
+            id: transfer.sender_id,
+            reason: reason.to_string(),
+        };
+        let cancel = FileFrame::Cancel {
+            id: server_id,
+            reason: reason.to_string(),
+        };
+        self.send_line(&transfer.sender, &reject.to_string());
+        self.send_line(&transfer.recipient, &cancel.to_string());
+    }
+
+    fn private_message(&mut self, addr: &SocketAddr, target: &str, text: &str) {
+        let Some(receiver) = self
+            .clients
+            .iter()
+            .find(|(_, client)| client.registered && client.nick.as_deref() == Some(target))
+            .map(|(addr, _)| *addr)
+        else {
+            self.reply(addr, irc::ERR_NOSUCHNICK, &[target, "No such nick/channel"]);
+            return;
+        };
+        let Some(sender) = self.clients.get(addr) else {
+            return;
+        };
+        let (name, prefix) = (sender.name(addr), sender.prefix(addr));
+        let line = match self.clients[&receiver].protocol {
+            Protocol::Line => format!("*{name}*: {text}"),
+            Protocol::Irc => IrcMessage::new(Some(&prefix), "PRIVMSG", &[target, text]).to_string(),
+        };
+        self.send_line(&receiver, &line);
+    }
+
+    fn heartbeat(&mut self, now: SystemTime) {
+        self.login_attempts.retain(|_, at| {
+            now.duration_since(*at)
+                .is_ok_and(|elapsed| elapsed < LOGIN_INTERVAL)
+        });
+        let addrs: Vec<SocketAddr> = self.clients.keys().copied().collect();
+        for addr in addrs {
+            let Some(client) = self.clients.get_mut(&addr) else {
+                continue;
+            };
+            let silent = now.duration_since(client.last_seen).unwrap_or_default();
+            if self
+                .config
+                .idle_timeout
+                .is_some_and(|timeout| silent >= timeout)
+            {
+                self.kick(&addr, "Idle timeout");
+                continue;
+            }
+            let Some(interval) = self.config.heartbeat else {
+                continue;
+            };
+            let since_ping = client
+                .last_ping
+                .map_or(silent, |at| now.duration_since(at).unwrap_or_default());
+            if silent >= interval && since_ping >= interval {
+                client.last_ping = Some(now);
+                let secs = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+                let ping = match client.protocol {
+                    Protocol::Line => format!("PING {secs}"),
+                    Protocol::Irc => format!("PING :{secs}"),
+                };
+                self.send_line(&addr, &ping);
+            }
+        }
+    }
+
+    fn admin_command(&mut self, line: &str) -> Vec<String> {
+        let mut words = line.split_whitespace();
+        let now = SystemTime::now();
+        match words.next() {
+            Some("/kick") => {
+                let Some(target) = words.next() else {
+                    return vec!["Usage: /kick <nick|addr>".to_string()];
+                };
+                let addrs: Vec<SocketAddr> = self
+                    .clients
+                    .iter()
+                    .filter(|(addr, client)| {
+                        client.nick.as_deref() == Some(target) || addr.to_string() == target
+                    })
+                    .map(|(addr, _)| *addr)
+                    .collect();
+                if addrs.is_empty() {
+                    return vec![format!("no such client: {target}")];
+                }
+                addrs
+                    .iter()
+                    .map(|addr| {
+                        self.kick(addr, "You have been kicked");
+                        format!("kicked {addr}")
+                    })
+                    .collect()
+            }
+            Some("/ban") => {
+                let Some(ip) = words.next().and_then(|ip| ip.parse::<IpAddr>().ok()) else {
+                    return vec!["Usage: /ban <ip> [duration]".to_string()];
+                };
+                let duration = match words.next() {
+                    Some(text) => match parse_duration(text) {
+                        Some(duration) => Some(duration),
+                        None => return vec![format!("invalid duration: {text}")],
+                    },
+                    None => None,
+                };
+                let mut replies = Vec::new();
+                match self.banned_mfs.ban(ip, duration) {
+                    Ok(()) => {}
+                    Err(err) if err.kind() == io::ErrorKind::InvalidInput => {
+                        return vec![format!("could not ban {ip}: {err}")];
+                    }
+                    Err(err) => replies.push(format!("could not save bans: {err}")),
+                }
+                let addrs: Vec<SocketAddr> = self
+                    .clients
+                    .keys()
+                    .filter(|addr| addr.ip() == ip)
+                    .copied()
+                    .collect();
+                for addr in addrs {
+                    self.kick(&addr, "You are banned");
+                }
+                replies.push(match duration {
+                    Some(duration) => format!("banned {ip} for {}", format_duration(duration)),
+                    None => format!("banned {ip} permanently"),
+                });
+                replies
+            }
+            Some("/unban") => {
+                let Some(ip) = words.next().and_then(|ip| ip.parse::<IpAddr>().ok()) else {
+                    return vec!["Usage: /unban <ip>".to_string()];
+                };
+                match self.banned_mfs.unban(&ip) {
+                    Ok(true) => vec![format!("unbanned {ip}")],
+                    Ok(false) => vec![format!("{ip} is not banned")],
+                    Err(err) => vec![format!("unbanned {ip}, but could not save bans: {err}")],
+                }
+            }
+            Some("/bans") => {
+                let bans = self.banned_mfs.active(now);
+                if bans.is_empty() {
+                    return vec!["no active bans".to_string()];
+                }
+                bans.iter()
+                    .map(|(ip, ban)| match ban.until {
+                        Some(until) => {
+                            let left = until.duration_since(now).unwrap_or_default();
+                            format!("{ip} expires in {}", format_duration(left))
+                        }
+                        None => format!("{ip} permanent"),
+                    })
+                    .collect()
+            }
+            Some("/stats") => {
+                let mut rooms = HashMap::<&str, usize>::new();
+                for client in self
+                    .clients
+                    .values()
+                    .filter(|client| !client.room.is_empty())
+                {
+                    *rooms.entry(&client.room).or_default() += 1;
+                }
+                let mut rooms: Vec<String> = rooms
+                    .iter()
+                    .map(|(room, count)| format!("{room}({count})"))
+                    .collect();
+                rooms.sort();
+                let uptime = now.duration_since(self.started).unwrap_or_default();
+                let mut stats = vec![
+                    format!("rooms: {}", rooms.join(" ")),
+                    format!("uptime: {}", format_duration(uptime)),
+                ];
+                stats.extend(self.metrics.summary());
+                stats
+            }
+            _ => vec![format!("admin commands: {}", ADMIN_COMMANDS.join(" "))],
+        }
+    }
+}
+
+/// 聊天服务的主循环，客户端、控制台和定时器都通过 `message` 发来消息，状态只在这个线程里修改
+pub fn server(message: Receiver<Message>, mut chat: ChatServer) {
+    loop {
+        let msg = message.recv().expect("ERROR: could not hung up");
+        let now = SystemTime::now();
+        match msg {
+            Message::ClientConnected { author, protocol } => chat.connected(author, protocol),
+            Message::ClientDisconected { author, reason } => {
+                if let Some(addr) = chat.find_addr(&author) {
+                    chat.disconnect(&addr, &reason);
+                }
+            }
+            Message::New { author, msg } => chat.received(&author, msg),
+            Message::LoginResult {
+                author,
+                user,
+                verified,
+            } => chat.login_result(&author, &user, verified),
+            Message::Admin { line } => {
+                let line = if line.starts_with('/') {
+                    line
+                } else {
+                    format!("/{line}")
+                };
+                for reply in chat.admin_command(&line) {
+                    println!("{reply}");
+                }
+            }
+            Message::Tick => {
+                chat.metrics.tick();
+                chat.heartbeat(now);
+            }
+        };
+        // 处理完再更新，`/metrics` 才能马上反映这次的连接、断开和封禁
+        chat.metrics
+            .active_bans
+            .store(chat.banned_mfs.active(now).len() as u64, Ordering::Relaxed);
+        chat.metrics
+            .connected_clients
+            .store(chat.clients.len() as u64, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_log(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("chat-{name}-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn ring_buffer_keeps_latest() {
+        let mut history = History::new(2);
+        for text in ["a", "b", "c"] {
+            history.push(Entry::new(DEFAULT_ROOM, "me", text)).unwrap();
+        }
+        history.push(Entry::new("other", "me", "d")).unwrap();
+
+        let texts: Vec<&str> = history.recent(DEFAULT_ROOM).map(|e| e.text.as_str()).collect();
+        assert_eq!(texts, vec!["b", "c"]);
+        assert_eq!(history.recent("other").count(), 1);
+        assert_eq!(history.recent("nobody").count(), 0);
+    }
+
+    #[test]
+    fn log_survives_restart() {
+        let path = temp_log("restart");
+        {
+            let mut history = History::with_log(2, path.clone()).unwrap();
+            for text in ["one", "two", "three\twith tab"] {
+                history.push(Entry::new(DEFAULT_ROOM, "me", text)).unwrap();
+            }
+        }
+
+        let history = History::with_log(2, path.clone()).unwrap();
+        let texts: Vec<String> = history.recent(DEFAULT_ROOM).map(|e| e.text.clone()).collect();
+        assert_eq!(texts, vec!["two", "three\twith tab"]);
+
+        let older = history.last(DEFAULT_ROOM, 10).unwrap();
+        assert_eq!(older.len(), 3);
+        assert_eq!(older[0].text, "one");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn last_reads_indexed_log() {
+        let path = temp_log("index");
+        let mut history = History::with_log(1, path.clone()).unwrap();
+        for (room, text) in [("a", "a1"), ("b", "b1"), ("a", "a2"), ("b", "b2")] {
+            history.push(Entry::new(room, "me", text)).unwrap();
+        }
+        let mut history = History::with_log(1, path.clone()).unwrap();
+        history.push(Entry::new("a", "me", "a3")).unwrap();
+
+        let texts = |entries: Vec<Entry>| -> Vec<String> {
+            entries.into_iter().map(|entry| entry.text).collect()
+        };
+        assert_eq!(texts(history.last("a", 2).unwrap()), vec!["a2", "a3"]);
+        assert_eq!(
+            texts(history.last("a", 10).unwrap()),
+            vec!["a1", "a2", "a3"]
+        );
+        assert_eq!(texts(history.last("b", 1).unwrap()), vec!["b2"]);
+        assert!(history.last("c", 5).unwrap().is_empty());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn heartbeat_lines() {
+        assert_eq!(heartbeat_token("PING 1700000000"), Some("1700000000"));
+        assert_eq!(heartbeat_token("PINGU: hi"), None);
+        assert_eq!(heartbeat_token("PING: 12"), None);
+        assert_eq!(heartbeat_token("PING "), None);
+        assert_eq!(heartbeat_token("PING 12 and more"), None);
+    }
+
+    #[test]
+    fn build_config() {
+        let args = [
+            "--history",
+            "5",
+            "--history-log",
+            "chat.log",
+            "--credentials",
+            "users",
+            "--admin",
+            "root",
+            "--idle-timeout",
+            "0",
+            "--heartbeat",
+            "15s",
+            "--irc",
+            "0.0.0.0:6667",
+            "--max-file-size",
+            "1M",
+        ]
+        .map(String::from);
+        let config = ChatConfig::build(args.into_iter()).unwrap();
+        assert_eq!(config.history_size, 5);
+        assert_eq!(config.history_log, Some(PathBuf::from("chat.log")));
+
+        assert!(!config.require_auth);
+        assert_eq!(config.admins, vec!["root"]);
+        assert_eq!(config.idle_timeout, None);
+        assert_eq!(config.heartbeat, Some(Duration::from_secs(15)));
+        assert_eq!(config.address, DEFAULT_ADDRESS);
+        assert_eq!(config.irc_address.as_deref(), Some("0.0.0.0:6667"));
+        assert_eq!(config.max_file_size, 1024 * 1024);
+
+        assert!(ChatConfig::build(["--history".to_string()].into_iter()).is_err());
+        assert!(ChatConfig::build(["--require-auth".to_string()].into_iter()).is_err());
+    }
+}
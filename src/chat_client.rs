@@ -0,0 +1,792 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Read, Stdout, Write},
+    net::TcpStream,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use crossterm::{
+    cursor::MoveTo,
+    event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    execute, queue,
+    style::{Attribute, Color, Print, SetAttribute, SetForegroundColor},
+    terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use sha2::{Digest, Sha256};
+
+use crate::auth::to_hex;
+use crate::chat;
+use crate::transfer::{self, format_size, FileFrame, CHUNK_SIZE};
+
+const MAX_MESSAGES: usize = 1000;
+const NICK_COLORS: [Color; 6] = [
+    Color::Cyan,
+    Color::Green,
+    Color::Yellow,
+    Color::Magenta,
+    Color::Blue,
+    Color::Red,
+];
+
+/// 重连等待时间，每次失败翻倍，直到 `max`
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration) -> Backoff {
+        Backoff {
+            min,
+            max,
+            current: min,
+        }
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = (self.current * 2).min(self.max);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.current = self.min;
+    }
+}
+
+/// 输入行，支持光标移动和上下翻阅已发送的内容
+#[derive(Default)]
+pub struct Input {
+    line: Vec<char>,
+    cursor: usize,
+    history: Vec<String>,
+    browsing: Option<usize>,
+}
+
+impl Input {
+    pub fn text(&self) -> String {
+        self.line.iter().collect()
+    }
+
+    pub fn insert(&mut self, c: char) {
+        self.line.insert(self.cursor, c);
+        self.cursor += 1;
+    }
+
+    pub fn backspace(&mut self) {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+            self.line.remove(self.cursor);
+        }
+    }
+
+    pub fn left(&mut self) {
+        self.cursor = self.cursor.saturating_sub(1);
+    }
+
+    pub fn right(&mut self) {
+        self.cursor = (self.cursor + 1).min(self.line.len());
+    }
+
+    pub fn submit(&mut self) -> Option<String> {
+        let text = self.text();
+        self.set(String::new());
+        self.browsing = None;
+        if text.is_empty() {
+            return None;
+        }
+        if self.history.last() != Some(&text) {
+            self.history.push(text.clone());
+        }
+        Some(text)
+    }
+
+    pub fn previous(&mut self) {
+        let index = match self.browsing {
+            Some(0) => return,
+            Some(index) => index - 1,
+            None if self.history.is_empty() => return,
+            None => self.history.len() - 1,
+        };
+        self.browsing = Some(index);
+        self.set(self.history[index].clone());
+    }
+
+    pub fn next(&mut self) {
+        match self.browsing {
+            Some(index) if index + 1 < self.history.len() => {
+                self.browsing = Some(index + 1);
+                self.set(self.history[index + 1].clone());
+            }
+            Some(_) => {
+                self.browsing = None;
+                self.set(String::new());
+            }
+            None => {}
+        }
+    }
+
+    fn set(&mut self, text: String) {
+        self.line = text.chars().collect();
+        self.cursor = self.line.len();
+    }
+}
+
+/// 同一个昵称总是同一种颜色
+pub fn nick_color(nick: &str) -> Color {
+    let hash = nick.bytes().fold(0usize, |hash, b| {
+        hash.wrapping_mul(31).wrapping_add(b as usize)
+    });
+    NICK_COLORS[hash % NICK_COLORS.len()]
+}
+
+/// 服务端消息格式为 `作者: 内容`
+pub fn split_author(line: &str) -> Option<(&str, &str)> {
+    let (author, text) = line.split_once(": ")?;
+    if author.is_empty() || author.contains(char::is_whitespace) {
+        return None;
+    }
+    Some((author, text))
+}
+
+/// 服务端转发来的内容可能带 ESC 之类的控制字符，存下来之前换成占位符，免得改动终端状态
+fn printable(line: &str) -> String {
+    line.chars()
+        .map(|c| {
+            if c.is_control() {
+                char::REPLACEMENT_CHARACTER
+            } else {
+                c
+            }
+        })
+        .collect()
+}
+
+/// 界面和上传线程共用一个连接，整行加锁写入，两边的行不会交错
+type Writer = Arc<Mutex<TcpStream>>;
+
+fn send_line(writer: &Writer, line: &str) -> io::Result<()> {
+    let line = format!("{line}\n");
+    writer.lock().unwrap().write_all(line.as_bytes())
+}
+
+enum NetEvent {
+    Connected(TcpStream),
+    Line(String),
+    Status(String),
+    UploadProgress { id: u64, sent: u64 },
+    UploadDone { id: u64, result: io::Result<()> },
+}
+
+/// 自己发出的文件，对方接受后由上传线程分块发送
+struct Outgoing {
+    path: PathBuf,
+    name: String,
+    peer: String,
+    size: u64,
+    cancelled: Arc<AtomicBool>,
+}
+
+/// 对方发来的文件，接受之后才创建本地文件，边收边算校验和
+struct Incoming {
+    from: String,
+    name: String,
+    size: u64,
+    sha256: String,
+    file: Option<(PathBuf, File)>,
+    received: u64,
+    hasher: Sha256,
+}
+
+fn percent(done: u64, total: u64) -> u64 {
+    match total {
+        0 => 100,
+        total => done.min(total) * 100 / total,
+    }
+}
+
+fn upload(
+    id: u64,
+    path: &Path,
+    writer: &Writer,
+    cancelled: &AtomicBool,
+    events: &Sender<NetEvent>,
+) -> io::Result<()> {
+    let mut file = File::open(path)?;
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut sent = 0;
+    loop {
+        if cancelled.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        let data = buffer[..n].to_vec();
+        send_line(writer, &FileFrame::Chunk { id, data }.to_string())?;
+        sent += n as u64;
+        let _ = events.send(NetEvent::UploadProgress { id, sent });
+    }
+    send_line(writer, &FileFrame::Done { id }.to_string())
+}
+
+fn network(address: String, events: Sender<NetEvent>) {
+    let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(30));
+    loop {
+        let status = match TcpStream::connect(&address).and_then(|s| Ok((s.try_clone()?, s))) {
+            Ok((writer, reader)) => {
+                backoff.reset();
+                if events.send(NetEvent::Connected(writer)).is_err() {
+                    return;
+                }
+                for line in BufReader::new(reader).lines() {
+                    let Ok(line) = line else { break };
+                    if events.send(NetEvent::Line(line)).is_err() {
+                        return;
+                    }
+                }
+                "connection closed".to_string()
+            }
+            Err(err) => format!("could not connect: {err}"),
+        };
+        let delay = backoff.next_delay();
+        let status = format!("{status}, reconnecting in {}s", delay.as_secs());
+        if events.send(NetEvent::Status(status)).is_err() {
+            return;
+        }
+        thread::sleep(delay);
+    }
+}
+
+struct Screen {
+    address: String,
+    messages: Vec<String>,
+    scroll: usize,
+    input: Input,
+    writer: Option<Writer>,
+    status: String,
+    /// 最近一次文件传输的进度，显示在状态栏
+    progress: Option<String>,
+    /// 上传线程通过它报告进度
+    events: Sender<NetEvent>,
+    next_id: u64,
+    outgoing: HashMap<u64, Outgoing>,
+    incoming: HashMap<u64, Incoming>,
+    download_dir: PathBuf,
+}
+
+impl Screen {
+    fn push(&mut self, line: String) {
+        if self.messages.len() == MAX_MESSAGES {
+            self.messages.remove(0);
+        }
+        self.messages.push(printable(&line));
+    }
+
+    /// 未连接或写入失败时把原因显示在状态栏
+    fn send(&mut self, line: &str) -> bool {
+        let Some(writer) = &self.writer else {
+            self.status = "not connected".to_string();
+            return false;
+        };
+        match send_line(writer, line) {
+            Ok(()) => true,
+            Err(err) => {
+                self.status = format!("could not send: {err}");
+                false
+            }
+        }
+    }
+
+    fn handle_net(&mut self, event: NetEvent) {
+        match event {
+            NetEvent::Connected(writer) => {
+                self.writer = Some(Arc::new(Mutex::new(writer)));
+                self.status = "connected".to_string();
+            }
+            NetEvent::Line(line) => match chat::heartbeat_token(&line) {
+                // 心跳由客户端自动回复，不显示
+                Some(token) => {
+                    self.send(&format!("PONG {token}"));
+                }
+                None => match FileFrame::decode(&line) {
+                    Some(Ok(frame)) => self.handle_file(frame),
+                    Some(Err(err)) => self.push(format!("invalid file frame: {err}")),
+                    None => self.push(line),
+                },
+            },
+            NetEvent::Status(status) => {
+                self.writer = None;
+                self.status = status;
+                self.drop_transfers();
+            }
+            NetEvent::UploadProgress { id, sent } => {
+                if let Some(outgoing) = self.outgoing.get(&id) {
+                    let done = percent(sent, outgoing.size);
+                    self.progress = Some(format!("sending {} {done}%", outgoing.name));
+                }
+            }
+            NetEvent::UploadDone { id, result } => {
+                let Some(outgoing) = self.outgoing.remove(&id) else {
+                    return;
+                };
+                self.progress = None;
+                match result {
+                    Ok(()) => self.push(format!("sent {} to {}", outgoing.name, outgoing.peer)),
+                    Err(err) => self.push(format!("could not send {}: {err}", outgoing.name)),
+                }
+            }
+        }
+    }
+
+    fn handle_file(&mut self, frame: FileFrame) {
+        match frame {
+            FileFrame::Offer {
+                id,
+                peer,
+                size,
+                sha256,
+                name,
+            } => {
+                self.push(format!(
+                    "{peer} wants to send you {name} ({}), /accept {id} or /reject {id}",
+                    format_size(size)
+                ));
+                let incoming = Incoming {
+                    from: peer,
+                    name,
+                    size,
+                    sha256,
+                    file: None,
+                    received: 0,
+                    hasher: Sha256::new(),
+                };
+                self.incoming.insert(id, incoming);
+            }
+            FileFrame::Accept { id } => {
+                let (Some(outgoing), Some(writer)) = (self.outgoing.get(&id), self.writer.clone())
+                else {
+                    return;
+                };
+                let notice = format!("{} accepted {}", outgoing.peer, outgoing.name);
+                let path = outgoing.path.clone();
+                let cancelled = outgoing.cancelled.clone();
+                self.push(notice);
+                let events = self.events.clone();
+                thread::spawn(move || {
+                    let result = upload(id, &path, &writer, &cancelled, &events);
+                    if let Err(err) = &result {
+                        let reason = format!("upload failed: {err}");
+                        let _ = send_line(&writer, &FileFrame::Cancel { id, reason }.to_string());
+                    }
+                    let _ = events.send(NetEvent::UploadDone { id, result });
+                });
+            }
+            FileFrame::Reject { id, reason } => {
+                if let Some(outgoing) = self.outgoing.remove(&id) {
+                    outgoing.cancelled.store(true, Ordering::Relaxed);
+                    self.progress = None;
+                    self.push(format!("{} was not sent: {reason}", outgoing.name));
+                }
+            }
+            FileFrame::Chunk { id, data } => {
+                let Some(incoming) = self.incoming.get_mut(&id) else {
+                    return;
+                };
+                let Some((_, file)) = incoming.file.as_mut() else {
+                    return;
+                };
+                if let Err(err) = file.write_all(&data) {
+                    let reason = format!("could not write file: {err}");
+                    self.send(&FileFrame::Reject { id, reason }.to_string());
+                    self.abort_download(id, &format!("could not write file: {err}"));
+                    return;
+                }
+                incoming.hasher.update(&data);
+                incoming.received += data.len() as u64;
+                let done = percent(incoming.received, incoming.size);
+                self.progress = Some(format!("receiving {} {done}%", incoming.name));
+            }
+            FileFrame::Done { id } => self.finish_download(id),
+            FileFrame::Cancel { id, reason } => self.abort_download(id, &reason),
+        }
+    }
+
+    fn finish_download(&mut self, id: u64) {
+        let Some(incoming) = self.incoming.remove(&id) else {
+            return;
+        };
+        self.progress = None;
+        let Some((path, file)) = incoming.file else {
+            return;
+        };
+        drop(file);
+        let sha256 = to_hex(&incoming.hasher.finalize());
+        if incoming.received == incoming.size && sha256 == incoming.sha256 {
+            self.push(format!(
+                "received {} from {}, saved to {}",
+                incoming.name,
+                incoming.from,
+                path.display()
+            ));
+        } else {
+            let _ = fs::remove_file(&path);
+            self.push(format!(
+                "{} from {} failed the checksum check and was discarded",
+                incoming.name, incoming.from
+            ));
+        }
+    }
+
+    /// 删除没有收完的文件
+    fn abort_download(&mut self, id: u64, reason: &str) {
+        let Some(incoming) = self.incoming.remove(&id) else {
+            return;
+        };
+        if let Some((path, file)) = incoming.file {
+            drop(file);
+            let _ = fs::remove_file(path);
+            self.progress = None;
+        }
+        self.push(format!(
+            "{} from {} was cancelled: {reason}",
+            incoming.name, incoming.from
+        ));
+    }
+
+    /// 断线后服务器那边的传输都已经结束
+    fn drop_transfers(&mut self) {
+        for (_, outgoing) in self.outgoing.drain() {
+            outgoing.cancelled.store(true, Ordering::Relaxed);
+        }
+        let ids: Vec<u64> = self.incoming.keys().copied().collect();
+        for id in ids {
+            self.abort_download(id, "connection lost");
+        }
+        self.progress = None;
+    }
+
+    /// 文件传输命令在本地处理，返回 false 表示不是这些命令
+    fn file_command(&mut self, line: &str) -> bool {
+        let mut parts = line.splitn(3, ' ');
+        let command = parts.next().unwrap_or_default();
+        match command {
+            "/send" => match (parts.next(), parts.next()) {
+                (Some(peer), Some(path)) => self.offer(peer, Path::new(path.trim())),
+                _ => self.push("Usage: /send <nick> <path>".to_string()),
+            },
+            "/accept" | "/reject" | "/cancel" => {
+                let Some(id) = parts.next().and_then(|id| id.trim().parse().ok()) else {
+                    self.push(format!("Usage: {command} <id>"));
+                    return true;
+                };
+                match command {
+                    "/accept" => self.accept(id),
+                    "/reject" => self.reject(id),
+                    _ => self.cancel(id),
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    fn offer(&mut self, peer: &str, path: &Path) {
+        let size = match fs::metadata(path) {
+            Ok(metadata) if metadata.is_file() => metadata.len(),
+            Ok(_) => return self.push(format!("{} is not a file", path.display())),
+            Err(err) => return self.push(format!("could not read {}: {err}", path.display())),
+        };
+        let sha256 = match transfer::file_sha256(path) {
+            Ok(sha256) => sha256,
+            Err(err) => return self.push(format!("could not read {}: {err}", path.display())),
+        };
+        let name = path
+            .file_name()
+            .map(|name| transfer::sanitize_name(&name.to_string_lossy()))
+            .unwrap_or_default();
+        let id = self.next_id;
+        self.next_id += 1;
+        let offer = FileFrame::Offer {
+            id,
+            peer: peer.to_string(),
+            size,
+            sha256,
+            name: name.clone(),
+        };
+        if self.send(&offer.to_string()) {
+            self.push(format!(
+                "offering {name} ({}) to {peer}, /cancel {id} to stop",
+                format_size(size)
+            ));
+            let outgoing = Outgoing {
+                path: path.to_path_buf(),
+                name,
+                peer: peer.to_string(),
+                size,
+                cancelled: Arc::default(),
+            };
+            self.outgoing.insert(id, outgoing);
+        }
+    }
+
+    fn accept(&mut self, id: u64) {
+        let Some(incoming) = self
+            .incoming
+            .get_mut(&id)
+            .filter(|incoming| incoming.file.is_none())
+        else {
+            return self.push(format!("no pending file {id}"));
+        };
+        let name = transfer::sanitize_name(&incoming.name);
+        let path = transfer::unique_path(&self.download_dir, &name);
+        match File::create(&path) {
+            Ok(file) => {
+                incoming.file = Some((path, file));
+                self.send(&FileFrame::Accept { id }.to_string());
+            }
+            Err(err) => self.push(format!("could not create {}: {err}", path.display())),
+        }
+    }
+
+    fn reject(&mut self, id: u64) {
+        if !self.incoming.contains_key(&id) {
+            return self.push(format!("no pending file {id}"));
+        }
+        let reason = "rejected by recipient".to_string();
+        self.send(&FileFrame::Reject { id, reason }.to_string());
+        self.abort_download(id, "rejected by you");
+    }
+
+    fn cancel(&mut self, id: u64) {
+        let Some(outgoing) = self.outgoing.remove(&id) else {
+            return self.push(format!("no outgoing file {id}"));
+        };
+        outgoing.cancelled.store(true, Ordering::Relaxed);
+        self.progress = None;
+        let reason = "cancelled by sender".to_string();
+        self.send(&FileFrame::Cancel { id, reason }.to_string());
+        self.push(format!("cancelled {}", outgoing.name));
+    }
+
+    /// 返回 false 表示退出
+    fn handle_key(&mut self, key: KeyEvent) -> bool {
+        match key.code {
+            KeyCode::Esc => return false,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return false,
+            KeyCode::Char(c) => self.input.insert(c),
+            KeyCode::Backspace => self.input.backspace(),
+            KeyCode::Left => self.input.left(),
+            KeyCode::Right => self.input.right(),
+            KeyCode::Up => self.input.previous(),
+            KeyCode::Down => self.input.next(),
+            KeyCode::PageUp => self.scroll = (self.scroll + 10).min(self.messages.len()),
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(10),
+            KeyCode::Enter => {
+                let Some(line) = self.input.submit() else {
+                    return true;
+                };
+                if line == "/quit" {
+                    return false;
+                }
+                if self.file_command(&line) {
+                    self.scroll = 0;
+                } else if self.send(&line) {
+                    if !line.starts_with('/') {
+                        self.push(format!("me: {line}"));
+                    }
+                    self.scroll = 0;
+                }
+            }
+            _ => {}
+        }
+        true
+    }
+
+    fn draw(&self, out: &mut Stdout) -> io::Result<()> {
+        let (cols, rows) = terminal::size()?;
+        let (cols, rows) = (cols as usize, rows as usize);
+        let pane = rows.saturating_sub(2);
+        let end = self.messages.len().saturating_sub(self.scroll);
+        let start = end.saturating_sub(pane);
+
+        let visible = &self.messages[start..end];
+
+        for y in 0..pane {
+            queue!(out, MoveTo(0, y as u16), Clear(ClearType::CurrentLine))?;
+            let Some(line) = visible.get(y) else { continue };
+            let line: String = line.chars().take(cols).collect();
+            match split_author(&line) {
+                Some((author, text)) => queue!(
+                    out,
+                    SetForegroundColor(nick_color(author)),
+                    Print(author),
+                    SetForegroundColor(Color::Reset),
+                    Print(": "),
+                    Print(text)
+                )?,
+                None => queue!(out, Print(line))?,
+            }
+        }
+
+        let status = match &self.progress {
+            Some(progress) => format!(" {} - {} - {progress}", self.address, self.status),
+            None => format!(" {} - {}", self.address, self.status),
+        };
+        queue!(
+            out,
+            MoveTo(0, pane as u16),
+            Clear(ClearType::CurrentLine),
+            SetAttribute(Attribute::Reverse),
+            Print(format!(
+                "{:<cols$}",
+                status.chars().take(cols).collect::<String>()
+            )),
+            SetAttribute(Attribute::Reset),
+            MoveTo(0, rows.saturating_sub(1) as u16),
+            Clear(ClearType::CurrentLine),
+            Print("> "),
+            Print(self.input.text()),
+            MoveTo(
+                (2 + self.input.cursor) as u16,
+                rows.saturating_sub(1) as u16
+            )
+        )?;
+        out.flush()
+    }
+}
+
+/// 进入备用屏幕和 raw 模式，离开时恢复终端
+struct RawTerminal;
+
+impl RawTerminal {
+    fn enter() -> io::Result<RawTerminal> {
+        terminal::enable_raw_mode()?;
+        execute!(io::stdout(), EnterAlternateScreen)?;
+        Ok(RawTerminal)
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        let _ = execute!(io::stdout(), LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+pub fn run(address: &str) -> io::Result<()> {
+    let (sender, events) = mpsc::channel();
+    let net_address = address.to_string();
+    let net_sender = sender.clone();
+    thread::spawn(move || network(net_address, net_sender));
+
+    let mut screen = Screen {
+        address: address.to_string(),
+        messages: Vec::new(),
+        scroll: 0,
+        input: Input::default(),
+        writer: None,
+        status: "connecting".to_string(),
+        progress: None,
+        events: sender,
+        next_id: 1,
+        outgoing: HashMap::new(),
+        incoming: HashMap::new(),
+        download_dir: PathBuf::from("."),
+    };
+    let _terminal = RawTerminal::enter()?;
+    let mut out = io::stdout();
+    let mut dirty = true;
+    loop {
+        while let Ok(event) = events.try_recv() {
+            screen.handle_net(event);
+            dirty = true;
+        }
+        if dirty {
+            screen.draw(&mut out)?;
+            dirty = false;
+        }
+        if event::poll(Duration::from_millis(50))? {
+            match event::read()? {
+                Event::Key(key) if key.kind == KeyEventKind::Press && !screen.handle_key(key) => {
+                    return Ok(());
+                }
+                Event::Resize(_, _) => execute!(out, Clear(ClearType::All))?,
+                _ => {}
+            }
+            dirty = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_until_max() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5));
+        let delays: Vec<u64> = (0..5).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 5, 5]);
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn input_history() {
+        let mut input = Input::default();
+        for c in "hi".chars() {
+            input.insert(c);
+        }
+        assert_eq!(input.submit(), Some("hi".to_string()));
+        for c in "yo".chars() {
+            input.insert(c);
+        }
+        input.left();
+        input.insert('!');
+        assert_eq!(input.submit(), Some("y!o".to_string()));
+        assert_eq!(input.submit(), None);
+
+        input.previous();
+        assert_eq!(input.text(), "y!o");
+        input.previous();
+        input.previous();
+        assert_eq!(input.text(), "hi");
+        input.next();
+        assert_eq!(input.text(), "y!o");
+        input.next();
+        assert_eq!(input.text(), "");
+    }
+
+    #[test]
+    fn authors() {
+        assert_eq!(
+            split_author("alice: hi: there"),
+            Some(("alice", "hi: there"))
+        );
+        assert_eq!(split_author("You are banned"), None);
+        assert_eq!(
+            printable("bob: \x1b[2Jhi\x07"),
+            "bob: \u{fffd}[2Jhi\u{fffd}"
+        );
+        assert_eq!(nick_color("alice"), nick_color("alice"));
+    }
+
+    #[test]
+    fn progress_percent() {
+        assert_eq!(percent(0, 200), 0);
+        assert_eq!(percent(50, 200), 25);
+        assert_eq!(percent(300, 200), 100);
+        assert_eq!(percent(0, 0), 100);
+    }
+}
@@ -0,0 +1,295 @@
+use std::io::{self, Write};
+
+use flate2::{
+    write::{GzEncoder, ZlibEncoder},
+    Compression,
+};
+
+use crate::http::{Body, Request, Response};
+
+/// 小于这个大小的正文压缩后省不了多少，直接原样发送
+pub const DEFAULT_MIN_SIZE: u64 = 1024;
+/// 即时压缩要把正文读进内存，更大的文件原样发送，需要压缩时请准备 `.gz`/`.br` 文件
+pub const MAX_SIZE: u64 = 8 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    /// 客户端给的权重相同时按这个顺序选
+    pub const ALL: [Encoding; 3] = [Encoding::Brotli, Encoding::Gzip, Encoding::Deflate];
+
+    /// `Content-Encoding` 里的名字
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    /// 预压缩文件的后缀，deflate 没有约定俗成的后缀
+    pub fn extension(self) -> Option<&'static str> {
+        match self {
+            Encoding::Brotli => Some("br"),
+            Encoding::Gzip => Some("gz"),
+            Encoding::Deflate => None,
+        }
+    }
+
+    pub fn compress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Encoding::Brotli => {
+                let mut out = Vec::new();
+                {
+                    let mut writer = brotli::CompressorWriter::new(&mut out, 4096, 5, 22);
+                    writer.write_all(data)?;
+                }
+                Ok(out)
+            }
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            // HTTP 里的 deflate 指的是 zlib 格式
+            Encoding::Deflate => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// 按 `Accept-Encoding` 的权重从 `available` 里选一个，`q=0` 表示不接受。没有可用的编码时返回 `None`
+pub fn negotiate(accept_encoding: &str, available: &[Encoding]) -> Option<Encoding> {
+    let mut weights = Vec::new();
+    for item in accept_encoding.split(',') {
+        let mut params = item.split(';');
+        let name = params
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        let q = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        weights.push((name, q));
+    }
+    let weight = |encoding: Encoding| {
+        let names: &[&str] = match encoding {
+            Encoding::Gzip => &["gzip", "x-gzip"],
+            _ => &[encoding.name()],
+        };
+        let find = |wanted: &[&str]| {
+            weights
+                .iter()
+                .find(|(name, _)| wanted.contains(&name.as_str()))
+                .map(|(_, q)| *q)
+        };
+        find(names).or_else(|| find(&["*"])).unwrap_or(0.0)
+    };
+    let mut best: Option<(Encoding, f32)> = None;
+    for &encoding in available {
+        let q = weight(encoding);
+        if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+            best = Some((encoding, q));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+/// 文本类内容才值得压缩，图片、压缩包本身已经压缩过
+pub fn is_compressible(content_type: &str) -> bool {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    mime.starts_with("text/")
+        || mime.ends_with("+xml")
+        || mime.ends_with("+json")
+        || matches!(
+            mime.as_str(),
+            "application/json"
+                | "application/javascript"
+                | "application/xml"
+                | "application/wasm"
+                | "image/x-icon"
+        )
+}
+
+/// 压缩后的表示要换一个 ETag: `"abc"` 变成 `"abc-gzip"`
+pub fn encoded_etag(etag: &str, encoding: Encoding) -> String {
+    match etag.strip_suffix('"') {
+        Some(tag) => format!("{tag}-{}\"", encoding.name()),
+        None => etag.to_string(),
+    }
+}
+
+/// 即时压缩 200 响应。只要内容类型可压缩就加上 `Vary`，表示响应随 `Accept-Encoding` 变化
+pub fn compress_response(request: &Request, response: Response, min_size: u64) -> Response {
+    let compressible = response.status == 200
+        && response.header("Content-Encoding").is_none()
+        && response.header("Content-Type").is_some_and(is_compressible);
+    if !compressible {
+        return response;
+    }
+    let response = add_vary(response, "Accept-Encoding");
+    let len = response.body.len();
+    if len < min_size || len > MAX_SIZE {
+        return response;
+    }
+    let Some(encoding) = request
+        .header("Accept-Encoding")
+        .and_then(|accept| negotiate(accept, &Encoding::ALL))
+    else {
+        return response;
+    };
+    let Response {
+        status,
+        mut headers,
+        body,
+    } = response;
+    let compressed = body
+        .into_bytes()
+        .and_then(|bytes| encoding.compress(&bytes));
+    let compressed = match compressed {
+        Ok(compressed) => compressed,
+        Err(err) => {
+            eprintln!("ERROR: could not compress response: {err}");
+            return Response::error(500);
+        }
+    };
+    // 压缩后的正文不能再按原始文件的区间取
+    headers.retain(|(name, _)| !name.eq_ignore_ascii_case("Accept-Ranges"));
+    for (name, value) in &mut headers {
+        if name.eq_ignore_ascii_case("ETag") {
+            *value = encoded_etag(value, encoding);
+        }
+    }
+    Response {
+        status,
+        headers,
+        body: Body::Bytes(compressed),
+    }
+    .with_header("Content-Encoding", encoding.name())
+}
+
+/// 在已有的 `Vary` 后面追加，不重复
+pub fn add_vary(response: Response, header: &str) -> Response {
+    let vary = match response.header("Vary") {
+        Some(vary)
+            if vary
+                .split(',')
+                .any(|name| name.trim().eq_ignore_ascii_case(header)) =>
+        {
+            return response
+        }
+        Some(vary) => format!("{vary}, {header}"),
+        None => header.to_string(),
+    };
+    response.with_header("Vary", vary)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::{GzDecoder, ZlibDecoder};
+
+    use super::*;
+    use crate::http::{parse_head, HttpConfig};
+
+    fn decompress(encoding: Encoding, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        match encoding {
+            Encoding::Brotli => brotli::Decompressor::new(data, 4096)
+                .read_to_end(&mut out)
+                .unwrap(),
+            Encoding::Gzip => GzDecoder::new(data).read_to_end(&mut out).unwrap(),
+            Encoding::Deflate => ZlibDecoder::new(data).read_to_end(&mut out).unwrap(),
+        };
+        out
+    }
+
+    #[test]
+    fn negotiate_encodings() {
+        use Encoding::*;
+        assert_eq!(negotiate("gzip, deflate, br", &Encoding::ALL), Some(Brotli));
+        assert_eq!(negotiate("gzip;q=1, br;q=0.5", &Encoding::ALL), Some(Gzip));
+        assert_eq!(negotiate("br;q=0, *", &Encoding::ALL), Some(Gzip));
+        assert_eq!(negotiate("x-gzip", &Encoding::ALL), Some(Gzip));
+        assert_eq!(negotiate("identity", &Encoding::ALL), None);
+        assert_eq!(negotiate("*;q=0", &Encoding::ALL), None);
+        assert_eq!(negotiate("", &Encoding::ALL), None);
+        assert_eq!(negotiate("br, gzip", &[Gzip]), Some(Gzip));
+    }
+
+    #[test]
+    fn compress_roundtrip() {
+        let text = "hello compression ".repeat(200);
+        for encoding in Encoding::ALL {
+            let compressed = encoding.compress(text.as_bytes()).unwrap();
+            assert!(compressed.len() < text.len() / 10);
+            assert_eq!(decompress(encoding, &compressed), text.as_bytes());
+        }
+        assert!(is_compressible("text/css; charset=utf-8"));
+        assert!(is_compressible("image/svg+xml"));
+        assert!(!is_compressible("image/png"));
+        assert_eq!(encoded_etag("\"abc\"", Encoding::Gzip), "\"abc-gzip\"");
+    }
+
+    #[test]
+    fn compress_responses() {
+        let request = |accept: &str| {
+            let head = format!("GET / HTTP/1.1\r\nHost: h\r\nAccept-Encoding: {accept}");
+            parse_head(&head).unwrap()
+        };
+        let text = "a".repeat(2000);
+        let page = || {
+            Response::text(200, text.clone())
+                .with_header("ETag", "\"v1\"")
+                .with_header("Vary", "Origin")
+        };
+
+        let response = compress_response(&request("gzip"), page(), DEFAULT_MIN_SIZE);
+        assert_eq!(response.header("Content-Encoding"), Some("gzip"));
+        assert_eq!(response.header("Vary"), Some("Origin, Accept-Encoding"));
+        assert_eq!(response.header("ETag"), Some("\"v1-gzip\""));
+        let body = response.body.into_bytes().unwrap();
+        assert_eq!(decompress(Encoding::Gzip, &body), text.as_bytes());
+
+        let response = compress_response(&request("identity"), page(), DEFAULT_MIN_SIZE);
+        assert_eq!(response.header("Content-Encoding"), None);
+        assert_eq!(response.header("Vary"), Some("Origin, Accept-Encoding"));
+        let response = compress_response(&request("br"), page(), 4096);
+        assert_eq!(response.header("Content-Encoding"), None);
+        let image = Response::new(200)
+            .with_header("Content-Type", "image/png")
+            .with_body(vec![0; 4096]);
+        let response = compress_response(&request("br"), image, DEFAULT_MIN_SIZE);
+        assert_eq!(response.header("Vary"), None);
+        let missing = compress_response(&request("br"), Response::error(404), 0);
+        assert_eq!(missing.header("Content-Encoding"), None);
+    }
+
+    #[test]
+    fn compress_options() {
+        let args = ["--compress-min", "2k"].map(String::from);
+        let config = HttpConfig::build(args.into_iter()).unwrap();
+        assert_eq!(config.compress_min, Some(2048));
+        let args = ["--no-compress"].map(String::from);
+        assert_eq!(
+            HttpConfig::build(args.into_iter()).unwrap().compress_min,
+            None
+        );
+    }
+}
@@ -0,0 +1,300 @@
+use std::{
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+};
+
+use rustls::{ServerConfig, ServerConnection};
+
+use crate::websocket::{self, CLOSE, PING, PONG, TEXT};
+
+/// 明文或 TLS 连接，可以再升级为 WebSocket。读写都通过 `&Conn` 完成，和 `&TcpStream` 一样可以在线程间共享:
+/// 只有读线程会读底层 socket，TLS 状态由锁保护，所以读阻塞时其它线程仍然可以写。
+#[derive(Debug)]
+pub struct Conn {
+    stream: TcpStream,
+    tls: Option<Mutex<ServerConnection>>,
+    websocket: Option<WebSocket>,
+    traffic: OnceLock<Arc<Traffic>>,
+}
+
+/// 应用层收发的字节数，多个连接可以共用一个
+#[derive(Debug, Default)]
+pub struct Traffic {
+    pub bytes_in: AtomicU64,
+    pub bytes_out: AtomicU64,
+}
+
+/// WebSocket 模式下，读到的每条文本消息以 `\n` 结尾交给调用方，
+/// 写入的内容按行缓冲，每一行作为一个文本帧发出，这样聊天服务不用关心底层协议。
+#[derive(Debug, Default)]
+struct WebSocket {
+    incoming: Mutex<Incoming>,
+    /// 还没凑成一行的输出。所有帧都在这把锁里写出，控制帧不会插进别的帧中间
+    outgoing: Mutex<Vec<u8>>,
+    /// 关闭帧只发一次
+    closed: AtomicBool,
+}
+
+#[derive(Debug, Default)]
+struct Incoming {
+    raw: Vec<u8>,
+    message: Vec<u8>,
+    decoded: Vec<u8>,
+}
+
+impl Conn {
+    pub fn plain(stream: TcpStream) -> Conn {
+        Conn {
+            stream,
+            tls: None,
+            websocket: None,
+            traffic: OnceLock::new(),
+        }
+    }
+
+    pub fn tls(stream: TcpStream, config: Arc<ServerConfig>) -> io::Result<Conn> {
+        let session = ServerConnection::new(config).map_err(io::Error::other)?;
+        Ok(Conn {
+            stream,
+            tls: Some(Mutex::new(session)),
+            websocket: None,
+            traffic: OnceLock::new(),
+        })
+    }
+
+    /// 握手完成后切换到 WebSocket 帧格式，`leftover` 是握手请求之后已经读到的数据
+    pub fn into_websocket(self, leftover: &[u8]) -> Conn {
+        let websocket = WebSocket::default();
+        websocket
+            .incoming
+            .lock()
+            .unwrap()
+            .raw
+            .extend_from_slice(leftover);
+        Conn {
+            websocket: Some(websocket),
+            ..self
+        }
+    }
+
+    /// 之后的读写都计入 `traffic`，只能设置一次
+    pub fn count_traffic(&self, traffic: Arc<Traffic>) {
+        let _ = self.traffic.set(traffic);
+    }
+
+    fn record(&self, counter: fn(&Traffic) -> &AtomicU64, n: usize) {
+        if let Some(traffic) = self.traffic.get() {
+            counter(traffic).fetch_add(n as u64, Ordering::Relaxed);
+        }
+    }
+
+    pub fn is_tls(&self) -> bool {
+        self.tls.is_some()
+    }
+
+    pub fn is_websocket(&self) -> bool {
+        self.websocket.is_some()
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    pub fn stream(&self) -> &TcpStream {
+        &self.stream
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        let _ = self.send_close(&[]);
+        if let Some(tls) = &self.tls {
+            let mut session = tls.lock().unwrap();
+            session.send_close_notify();
+            let _ = flush_tls(&mut session, &self.stream);
+        }
+        self.stream.shutdown(how)
+    }
+
+    fn read_raw(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.read_session(buf)?;
+        self.record(|traffic| &traffic.bytes_in, n);
+        Ok(n)
+    }
+
+    fn read_session(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(tls) = &self.tls else {
+            return (&self.stream).read(buf);
+        };
+        let mut incoming = [0; 4096];
+        loop {
+            {
+                let mut session = tls.lock().unwrap();
+                match session.reader().read(buf) {
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                    result => return result,
+                }
+            }
+            // 不持锁阻塞读取，拿到数据后再交给 TLS 状态机处理
+            let n = (&self.stream).read(&mut incoming)?;
+            if n == 0 {
+                return Ok(0);
+            }
+            let mut session = tls.lock().unwrap();
+            let mut data = &incoming[..n];
+            while !data.is_empty() {
+                session.read_tls(&mut data)?;
+                if let Err(err) = session.process_new_packets() {
+                    let _ = flush_tls(&mut session, &self.stream);
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+                }
+            }
+            flush_tls(&mut session, &self.stream)?;
+        }
+    }
+
+    fn write_raw(&self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.write_session(buf)?;
+        self.record(|traffic| &traffic.bytes_out, n);
+        Ok(n)
+    }
+
+    fn write_session(&self, buf: &[u8]) -> io::Result<usize> {
+        let Some(tls) = &self.tls else {
+            return (&self.stream).write(buf);
+        };
+        let mut session = tls.lock().unwrap();
+        let n = session.writer().write(buf)?;
+        // 握手完成前写入的数据由 rustls 缓存，握手结束后由读线程发出
+        if !session.is_handshaking() {
+            flush_tls(&mut session, &self.stream)?;
+        }
+        Ok(n)
+    }
+
+    fn write_raw_all(&self, mut buf: &[u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.write_raw(buf)? {
+                0 => return Err(io::ErrorKind::WriteZero.into()),
+                n => buf = &buf[n..],
+            }
+        }
+        Ok(())
+    }
+
+    fn read_websocket(&self, websocket: &WebSocket, buf: &mut [u8]) -> io::Result<usize> {
+        // 只有读线程会拿这把锁
+        let mut incoming = websocket.incoming.lock().unwrap();
+        loop {
+            if !incoming.decoded.is_empty() {
+                let n = buf.len().min(incoming.decoded.len());
+                buf[..n].copy_from_slice(&incoming.decoded[..n]);
+                incoming.decoded.drain(..n);
+                return Ok(n);
+            }
+            let frame = match websocket::decode_frame(&incoming.raw) {
+                Ok(frame) => frame,
+                Err(err) => return Err(self.close_websocket(err)),
+            };
+            let Some((frame, used)) = frame else {
+                let mut chunk = [0; 4096];
+                let n = self.read_raw(&mut chunk)?;
+                if n == 0 {
+                    return Ok(0);
+                }
+                incoming.raw.extend_from_slice(&chunk[..n]);
+                continue;
+            };
+            incoming.raw.drain(..used);
+            match frame.opcode {
+                CLOSE => {
+                    let _ = self.send_close(&frame.payload);
+                    return Ok(0);
+                }
+                PING => self.send_frame(websocket, PONG, &frame.payload)?,
+                PONG => {}
+                _ => {
+                    // 单帧有上限，不断发不带 FIN 的分片也不能让消息无限变大
+                    if incoming.message.len() + frame.payload.len() > websocket::MAX_MESSAGE {
+                        return Err(self.close_websocket(websocket::TOO_LARGE));
+                    }
+                    incoming.message.extend_from_slice(&frame.payload);
+                    if frame.fin {
+                        let mut message = std::mem::take(&mut incoming.message);
+                        message.push(b'\n');
+                        incoming.decoded = message;
+                    }
+                }
+            }
+        }
+    }
+
+    /// 出错时先发关闭帧再断开，消息太大用 1009，其余协议错误用 1002
+    fn close_websocket(&self, err: &'static str) -> io::Error {
+        let code = match err {
+            websocket::TOO_LARGE => websocket::CLOSE_TOO_BIG,
+            _ => websocket::CLOSE_PROTOCOL_ERROR,
+        };
+        let _ = self.send_close(&websocket::close_payload(code, err));
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+
+    fn send_close(&self, payload: &[u8]) -> io::Result<()> {
+        match &self.websocket {
+            Some(websocket) if !websocket.closed.swap(true, Ordering::Relaxed) => {
+                self.send_frame(websocket, CLOSE, payload)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn send_frame(&self, websocket: &WebSocket, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let _outgoing = websocket.outgoing.lock().unwrap();
+        self.write_raw_all(&websocket::encode_frame(opcode, payload))
+    }
+}
+
+fn flush_tls(session: &mut ServerConnection, mut stream: &TcpStream) -> io::Result<()> {
+    while session.wants_write() {
+        session.write_tls(&mut stream)?;
+    }
+    Ok(())
+}
+
+impl Read for &Conn {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &self.websocket {
+            Some(websocket) => self.read_websocket(websocket, buf),
+            None => self.read_raw(buf),
+        }
+    }
+}
+
+impl Write for &Conn {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Some(websocket) = &self.websocket else {
+            return self.write_raw(buf);
+        };
+        // 和 `send_frame` 用同一把锁
+        let mut outgoing = websocket.outgoing.lock().unwrap();
+        outgoing.extend_from_slice(buf);
+        while let Some(end) = outgoing.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = outgoing.drain(..=end).collect();
+            self.write_raw_all(&websocket::encode_frame(TEXT, &line[..end]))?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let Some(tls) = &self.tls else {
+            return (&self.stream).flush();
+        };
+        let mut session = tls.lock().unwrap();
+        if !session.is_handshaking() {
+            flush_tls(&mut session, &self.stream)?;
+        }
+        Ok(())
+    }
+}
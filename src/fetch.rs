@@ -0,0 +1,483 @@
+use std::{
+    fmt, fs,
+    io::{self, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    path::{Path, PathBuf},
+    result,
+    time::Duration,
+};
+
+use crate::http::{
+    find_header, parse_head, reason, HttpError, HttpReader, Request, Response, MAX_RESPONSE_SIZE,
+};
+use crate::units::parse_duration;
+
+/// 连接和每次读写的超时
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_MAX_REDIRECTS: usize = 10;
+const USER_AGENT: &str = concat!("rust_commandlines-fetch/", env!("CARGO_PKG_VERSION"));
+
+/// 只支持 `http://host[:port][/path][?query]`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Url {
+    pub host: String,
+    pub port: u16,
+    /// 请求行里的目标，以 `/` 开头
+    pub target: String,
+}
+
+impl Url {
+    pub fn parse(text: &str) -> Option<Url> {
+        let rest = text.strip_prefix("http://")?;
+        let rest = rest.split('#').next().unwrap_or_default();
+        let (authority, target) = match rest.find(['/', '?']) {
+            Some(index) => rest.split_at(index),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            // IPv6 字面量里的 `:` 在方括号里面
+            Some((host, port)) if !port.contains(']') => (host, port.parse().ok()?),
+            _ => (authority, 80),
+        };
+        let valid = |c: char| c.is_ascii_alphanumeric() || "-._[]:".contains(c);
+        if host.is_empty() || !host.chars().all(valid) || target.contains(char::is_whitespace) {
+            return None;
+        }
+        let target = match target.starts_with('?') {
+            true => format!("/{target}"),
+            false => target.to_string(),
+        };
+        Some(Url {
+            host: host.to_string(),
+            port,
+            target,
+        })
+    }
+
+    /// `Host` 头部的值，默认端口省略
+    pub fn authority(&self) -> String {
+        match self.port {
+            80 => self.host.clone(),
+            port => format!("{}:{port}", self.host),
+        }
+    }
+
+    /// 按 `Location` 算出重定向的地址，可以是完整的 URL、绝对路径或相对路径
+    pub fn join(&self, location: &str) -> Option<Url> {
+        if location.contains("://") {
+            return Url::parse(location);
+        }
+        if let Some(rest) = location.strip_prefix("//") {
+            return Url::parse(&format!("http://{rest}"));
+        }
+        let target = if location.starts_with('/') {
+            location.to_string()
+        } else {
+            let path = self.target.split('?').next().unwrap_or_default();
+            let dir = &path[..path.rfind('/').map_or(0, |index| index + 1)];
+            format!("{dir}{location}")
+        };
+        Url::parse(&format!("http://{}{target}", self.authority()))
+    }
+}
+
+impl fmt::Display for Url {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "http://{}{}", self.authority(), self.target)
+    }
+}
+
+pub struct FetchConfig {
+    pub url: Url,
+    pub method: String,
+    pub headers: Vec<(String, String)>,
+    /// 请求正文从这个文件读，`-` 表示标准输入
+    pub body: Option<PathBuf>,
+    /// 跟随重定向时最多跳几次，`None` 表示直接返回 3xx 响应
+    pub max_redirects: Option<usize>,
+    pub timeout: Duration,
+    /// 把请求和响应的头部打印到标准错误
+    pub verbose: bool,
+}
+
+impl FetchConfig {
+    pub fn build(
+        mut args: impl Iterator<Item = String>,
+    ) -> result::Result<FetchConfig, &'static str> {
+        let mut url = None;
+        let mut method = None;
+        let mut headers = Vec::new();
+        let mut body = None;
+        let mut follow = false;
+        let mut max_redirects = DEFAULT_MAX_REDIRECTS;
+        let mut timeout = DEFAULT_TIMEOUT;
+        let mut verbose = false;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-X" | "--method" => {
+                    method = match args.next() {
+                        Some(name) if valid_token(&name) => Some(name.to_ascii_uppercase()),
+                        _ => return Err("--method expects a method such as POST"),
+                    }
+                }
+                "-H" | "--header" => {
+                    let header = args.next().and_then(|header| parse_header(&header));
+                    headers.push(header.ok_or("--header expects \"Name: value\"")?);
+                }
+                "-d" | "--body" => {
+                    let path = args.next().ok_or("--body expects a file, - for stdin")?;
+                    body = Some(PathBuf::from(path));
+                }
+                "-L" | "--location" => follow = true,
+                "--max-redirects" => {
+                    max_redirects = match args.next().and_then(|n| n.parse().ok()) {
+                        Some(n) => n,
+                        None => return Err("--max-redirects expects a number"),
+                    }
+                }
+                "--timeout" => {
+                    timeout = match args.next().and_then(|d| parse_duration(&d)) {
+                        Some(duration) if !duration.is_zero() => duration,
+                        _ => return Err("--timeout expects a duration such as 10s"),
+                    }
+                }
+                "-v" | "--verbose" => verbose = true,
+                _ if arg.starts_with('-') => return Err("unknown fetch option"),
+                _ if url.is_some() => return Err("fetch expects a single url"),
+                _ if arg.starts_with("https://") => return Err("https is not supported"),
+                _ => url = Some(Url::parse(&arg).ok_or("fetch expects http://host[:port]/path")?),
+            }
+        }
+        Ok(FetchConfig {
+            url: url.ok_or("fetch expects a url")?,
+            // 和 curl 一样，带正文时默认 POST
+            method: method.unwrap_or_else(|| match body {
+                Some(_) => "POST".to_string(),
+                None => "GET".to_string(),
+            }),
+            headers,
+            body,
+            max_redirects: follow.then_some(max_redirects),
+            timeout,
+            verbose,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum FetchError {
+    Connect(String, io::Error),
+    Http(HttpError),
+    TooManyRedirects(usize),
+    BadRedirect(String),
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FetchError::Connect(address, err) => write!(f, "could not connect to {address}: {err}"),
+            FetchError::Http(err) => write!(f, "{err}"),
+            FetchError::TooManyRedirects(n) => write!(f, "stopped after {n} redirects"),
+            FetchError::BadRedirect(location) => {
+                write!(f, "could not follow redirect to {location}")
+            }
+        }
+    }
+}
+
+impl From<HttpError> for FetchError {
+    fn from(err: HttpError) -> FetchError {
+        FetchError::Http(err)
+    }
+}
+
+impl From<io::Error> for FetchError {
+    fn from(err: io::Error) -> FetchError {
+        FetchError::Http(err.into())
+    }
+}
+
+/// 读 `--body` 指定的文件，`-` 读标准输入
+pub fn read_body(path: &Path) -> io::Result<Vec<u8>> {
+    if path == Path::new("-") {
+        let mut body = Vec::new();
+        io::stdin().lock().read_to_end(&mut body)?;
+        return Ok(body);
+    }
+    fs::read(path)
+}
+
+/// 发请求，需要时跟随重定向，返回最后一个响应。每一跳都新开一个连接
+pub fn fetch(config: &FetchConfig, mut body: Vec<u8>) -> result::Result<Response, FetchError> {
+    let mut url = config.url.clone();
+    let mut method = config.method.clone();
+    let mut headers = config.headers.clone();
+    let mut redirects = 0;
+    loop {
+        let request = build_request(&url, &method, &headers, body.clone())?;
+        let response = send(&url, &request, config)?;
+        let location = match (config.max_redirects, response.status) {
+            (Some(_), 301 | 302 | 303 | 307 | 308) => response.header("Location"),
+            _ => None,
+        };
+        let Some(location) = location else {
+            return Ok(response);
+        };
+        if Some(redirects) == config.max_redirects {
+            return Err(FetchError::TooManyRedirects(redirects));
+        }
+        redirects += 1;
+        let next = url
+            .join(location)
+            .ok_or_else(|| FetchError::BadRedirect(location.to_string()))?;
+        // 301、302、303 改成不带正文的 GET，307 和 308 原样重发
+        if matches!(response.status, 301..=303) && method != "HEAD" {
+            method = "GET".to_string();
+            body.clear();
+            headers.retain(|(name, _)| {
+                !name.eq_ignore_ascii_case("Content-Type")
+                    && !name.eq_ignore_ascii_case("Content-Length")
+            });
+        }
+        // 凭据不带到别的主机上
+        if next.authority() != url.authority() {
+            headers.retain(|(name, _)| {
+                !name.eq_ignore_ascii_case("Authorization") && !name.eq_ignore_ascii_case("Cookie")
+            });
+        }
+        if config.verbose {
+            eprintln!("* following redirect to {next}");
+        }
+        url = next;
+    }
+}
+
+/// 借用服务端的解析检查请求行和头部，再补上缺的 `Host`、`User-Agent` 和 `Connection`
+fn build_request(
+    url: &Url,
+    method: &str,
+    headers: &[(String, String)],
+    body: Vec<u8>,
+) -> result::Result<Request, HttpError> {
+    let mut head = format!("{method} {} HTTP/1.1", url.target);
+    for (name, value) in headers {
+        head.push_str(&format!("\r\n{name}: {value}"));
+    }
+    let defaults = [
+        ("Host", url.authority()),
+        ("User-Agent", USER_AGENT.to_string()),
+        ("Accept", "*/*".to_string()),
+        ("Connection", "close".to_string()),
+    ];
+    for (name, value) in defaults {
+        if find_header(headers, name).is_none() {
+            head.push_str(&format!("\r\n{name}: {value}"));
+        }
+    }
+    let mut request = parse_head(&head)?;
+    request.body = body;
+    Ok(request)
+}
+
+fn send(
+    url: &Url,
+    request: &Request,
+    config: &FetchConfig,
+) -> result::Result<Response, FetchError> {
+    let address = format!("{}:{}", url.host.trim_matches(['[', ']']), url.port);
+    let stream = connect(&address, config.timeout)
+        .map_err(|err| FetchError::Connect(address.clone(), err))?;
+    stream.set_read_timeout(Some(config.timeout))?;
+    stream.set_write_timeout(Some(config.timeout))?;
+    // 整个请求一次写出去
+    let mut bytes = Vec::new();
+    request.write_to(&mut bytes)?;
+    if config.verbose {
+        let head = String::from_utf8_lossy(&bytes);
+        for line in head.lines().take_while(|line| !line.is_empty()) {
+            eprintln!("> {line}");
+        }
+    }
+    (&stream).write_all(&bytes)?;
+    let response = HttpReader::new(&stream)
+        .with_max_body(MAX_RESPONSE_SIZE)
+        .read_response(request.method == "HEAD")?;
+    if config.verbose {
+        eprintln!("< HTTP/1.1 {} {}", response.status, reason(response.status));
+        for (name, value) in &response.headers {
+            eprintln!("< {name}: {value}");
+        }
+    }
+    Ok(response)
+}
+
+fn connect(address: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_err = io::Error::new(io::ErrorKind::NotFound, "address did not resolve");
+    for addr in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = err,
+        }
+    }
+    Err(last_err)
+}
+
+fn valid_token(text: &str) -> bool {
+    !text.is_empty()
+        && text
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// `Name: value`，名字必须是 token，值里不能有换行
+fn parse_header(text: &str) -> Option<(String, String)> {
+    let (name, value) = text.split_once(':')?;
+    let value = value.trim();
+    if !valid_token(name) || value.contains(['\r', '\n']) {
+        return None;
+    }
+    Some((name.to_string(), value.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::TcpListener, thread};
+
+    #[test]
+    fn parse_urls_and_config() {
+        let url = Url::parse("http://example.com:8080/a/b?x=1#top").unwrap();
+        assert_eq!(
+            url,
+            Url {
+                host: "example.com".to_string(),
+                port: 8080,
+                target: "/a/b?x=1".to_string(),
+            }
+        );
+        assert_eq!(url.to_string(), "http://example.com:8080/a/b?x=1");
+        assert_eq!(Url::parse("http://h").unwrap().target, "/");
+        assert_eq!(Url::parse("http://h?q").unwrap().target, "/?q");
+        assert_eq!(Url::parse("http://[::1]:81/").unwrap().host, "[::1]");
+        assert_eq!(Url::parse("http://[::1]/").unwrap().port, 80);
+        assert_eq!(Url::parse("ftp://h/"), None);
+        assert_eq!(Url::parse("http://h:x/"), None);
+        assert_eq!(Url::parse("http://a b/"), None);
+
+        let join = |location| url.join(location).unwrap().to_string();
+        assert_eq!(join("c"), "http://example.com:8080/a/c");
+        assert_eq!(join("/c?y"), "http://example.com:8080/c?y");
+        assert_eq!(join("//other/"), "http://other/");
+        assert_eq!(join("http://other:81/d"), "http://other:81/d");
+
+        let args = [
+            "-H",
+            "X-Token: abc",
+            "-d",
+            "-",
+            "-L",
+            "--timeout",
+            "5s",
+            "-v",
+            "http://127.0.0.1:7878/upload",
+        ]
+        .map(String::from);
+        let config = FetchConfig::build(args.into_iter()).unwrap();
+        assert_eq!(config.method, "POST");
+        assert_eq!(config.headers, [("X-Token".to_string(), "abc".to_string())]);
+        assert_eq!(config.body, Some(PathBuf::from("-")));
+        assert_eq!(config.max_redirects, Some(DEFAULT_MAX_REDIRECTS));
+        assert_eq!(config.timeout, Duration::from_secs(5));
+        assert!(config.verbose);
+        let build = |args: &[&str]| FetchConfig::build(args.iter().map(|arg| arg.to_string()));
+        assert_eq!(
+            build(&["-X", "delete", "http://h/"]).unwrap().method,
+            "DELETE"
+        );
+        assert!(build(&["-H", "Bad Name: x", "http://h/"]).is_err());
+        assert!(build(&["https://h/"]).is_err());
+        assert!(build(&[]).is_err());
+    }
+
+    /// 按路径回应的小服务器，每个连接只处理一个请求
+    fn server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                let request = HttpReader::new(&stream).read_request().unwrap().unwrap();
+                let response = match request.path.as_str() {
+                    "/moved" => Response::new(302).with_header("Location", "echo?from=moved"),
+                    "/temporary" => Response::new(307).with_header("Location", "/echo"),
+                    "/loop" => Response::new(301).with_header("Location", "/loop"),
+                    _ => Response::text(
+                        200,
+                        format!(
+                            "{} {} host={} token={} body={}",
+                            request.method,
+                            request.target,
+                            request.header("Host").unwrap_or_default(),
+                            request.header("X-Token").unwrap_or("-"),
+                            String::from_utf8_lossy(&request.body)
+                        ),
+                    ),
+                };
+                response.write_to(&stream, true).unwrap();
+            }
+        });
+        address
+    }
+
+    #[test]
+    fn fetch_from_server() {
+        let address = server();
+        let config = |method: &str, path: &str, follow: Option<usize>| FetchConfig {
+            url: Url::parse(&format!("http://{address}{path}")).unwrap(),
+            method: method.to_string(),
+            headers: vec![("X-Token".to_string(), "t".to_string())],
+            body: None,
+            max_redirects: follow,
+            timeout: Duration::from_secs(5),
+            verbose: false,
+        };
+        let text =
+            |response: Response| String::from_utf8(response.body.into_bytes().unwrap()).unwrap();
+
+        let response = fetch(&config("PUT", "/echo", None), b"hi".to_vec()).unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            text(response),
+            format!("PUT /echo host={address} token=t body=hi")
+        );
+
+        // 不跟随时直接拿到 3xx
+        let response = fetch(&config("POST", "/moved", None), b"hi".to_vec()).unwrap();
+        assert_eq!(response.status, 302);
+
+        let response = fetch(&config("POST", "/moved", Some(5)), b"hi".to_vec()).unwrap();
+        assert_eq!(
+            text(response),
+            format!("GET /echo?from=moved host={address} token=t body=")
+        );
+        let response = fetch(&config("POST", "/temporary", Some(5)), b"hi".to_vec()).unwrap();
+        assert_eq!(
+            text(response),
+            format!("POST /echo host={address} token=t body=hi")
+        );
+
+        let err = fetch(&config("GET", "/loop", Some(3)), Vec::new()).unwrap_err();
+        assert!(matches!(err, FetchError::TooManyRedirects(3)));
+
+        // 先占一个端口再释放，保证连不上
+        let dead = TcpListener::bind("127.0.0.1:0").unwrap();
+        let dead_address = dead.local_addr().unwrap().to_string();
+        drop(dead);
+        let mut unreachable = config("GET", "/", None);
+        unreachable.url = Url::parse(&format!("http://{dead_address}/")).unwrap();
+        assert!(matches!(
+            fetch(&unreachable, Vec::new()),
+            Err(FetchError::Connect(..))
+        ));
+    }
+}
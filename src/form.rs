@@ -0,0 +1,617 @@
+use std::{
+    env, fmt,
+    fs::{self, OpenOptions},
+    io::{self, Read, Write},
+    path::PathBuf,
+    str::{self, FromStr},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::http::{
+    find_header, parse_headers, parse_query, reason, HttpError, HttpReader, Request, Response,
+    MAX_HEAD_SIZE,
+};
+
+/// 单个上传文件默认最大
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 100 * 1024 * 1024;
+/// 整个 multipart 正文默认最大，文件和字段都算在内
+pub const DEFAULT_MAX_FORM_SIZE: u64 = 200 * 1024 * 1024;
+/// 一个表单里的文字字段加起来最多这么多字节
+pub const MAX_FIELDS_SIZE: usize = 1024 * 1024;
+/// 一个表单最多这么多个部分
+pub const MAX_PARTS: usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UploadConfig {
+    /// 上传的文件写到这里，目录在第一次收到文件时创建
+    pub dir: PathBuf,
+    pub max_file_size: u64,
+    pub max_form_size: u64,
+}
+
+impl Default for UploadConfig {
+    fn default() -> UploadConfig {
+        UploadConfig {
+            dir: env::temp_dir().join("uploads"),
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            max_form_size: DEFAULT_MAX_FORM_SIZE,
+        }
+    }
+}
+
+/// 解析好的表单。文件已经写进上传目录，处理函数负责移走或者删掉
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Form {
+    pub fields: Vec<(String, String)>,
+    pub files: Vec<Upload>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Upload {
+    /// 表单里的字段名
+    pub name: String,
+    /// 清理过的原始文件名
+    pub filename: String,
+    pub content_type: String,
+    /// 保存的位置，文件名前面加了唯一的前缀，不会覆盖已有的文件
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+/// 字段缺失或者不能转换成需要的类型，回给客户端 400
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldError {
+    Missing(String),
+    Invalid(String),
+}
+
+impl fmt::Display for FieldError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FieldError::Missing(name) => write!(f, "missing field {name}"),
+            FieldError::Invalid(name) => write!(f, "invalid value for field {name}"),
+        }
+    }
+}
+
+impl From<&FieldError> for Response {
+    fn from(err: &FieldError) -> Response {
+        Response::text(400, format!("400 {}: {err}\n", reason(400)))
+    }
+}
+
+/// 把表单转换成自己的类型，处理函数里用 `form.decode::<T>()`
+pub trait FromForm: Sized {
+    fn from_form(form: &Form) -> Result<Self, FieldError>;
+}
+
+impl Form {
+    pub fn urlencoded(body: &[u8]) -> Result<Form, HttpError> {
+        let body = str::from_utf8(body).map_err(|_| HttpError::BadRequest("form is not utf-8"))?;
+        Ok(Form {
+            fields: parse_query(body)?,
+            files: Vec::new(),
+        })
+    }
+
+    /// 同名字段取第一个
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// 多选框这类同名的多个字段
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.fields
+            .iter()
+            .filter(move |(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn file(&self, name: &str) -> Option<&Upload> {
+        self.files.iter().find(|file| file.name == name)
+    }
+
+    /// 必填字段，按 `FromStr` 转换，前后的空白会去掉
+    pub fn value<T: FromStr>(&self, name: &str) -> Result<T, FieldError> {
+        self.optional(name)?
+            .ok_or_else(|| FieldError::Missing(name.to_string()))
+    }
+
+    /// 没有这个字段或者值为空时返回 `None`
+    pub fn optional<T: FromStr>(&self, name: &str) -> Result<Option<T>, FieldError> {
+        match self.get(name).map(str::trim) {
+            None | Some("") => Ok(None),
+            Some(value) => value
+                .parse()
+                .map(Some)
+                .map_err(|_| FieldError::Invalid(name.to_string())),
+        }
+    }
+
+    pub fn decode<T: FromForm>(&self) -> Result<T, FieldError> {
+        T::from_form(self)
+    }
+}
+
+/// 按 `Content-Type` 读取请求正文：urlencoded 读进内存解析，multipart 边读边把文件写进上传目录。
+/// 出错时已经写下的文件会删掉
+pub fn read_form<R: Read>(
+    reader: &mut HttpReader<R>,
+    request: &Request,
+    config: &UploadConfig,
+) -> Result<Form, HttpError> {
+    let content_type = request.header("Content-Type").unwrap_or_default();
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    match mime.as_str() {
+        "application/x-www-form-urlencoded" => {
+            Form::urlencoded(&reader.read_body(&request.headers)?)
+        }
+        "multipart/form-data" => {
+            let boundary = header_param(content_type, "boundary")
+                .filter(|boundary| (1..=70).contains(&boundary.len()))
+                .ok_or(HttpError::BadRequest("missing multipart boundary"))?;
+            read_multipart(reader.body_reader(&request.headers)?, &boundary, config)
+        }
+        _ => Err(HttpError::UnsupportedMediaType),
+    }
+}
+
+pub fn read_multipart(
+    body: impl Read,
+    boundary: &str,
+    config: &UploadConfig,
+) -> Result<Form, HttpError> {
+    let mut form = Form::default();
+    let mut multipart = Multipart {
+        body,
+        // 第一个分隔行前面没有换行，补上以后所有分隔行都是同一个样子
+        buffer: b"\r\n".to_vec(),
+        delimiter: format!("\r\n--{boundary}").into_bytes(),
+        remaining: config.max_form_size,
+    };
+    match multipart.read_parts(&mut form, config) {
+        Ok(()) => Ok(form),
+        Err(err) => {
+            for file in &form.files {
+                let _ = fs::remove_file(&file.path);
+            }
+            Err(err)
+        }
+    }
+}
+
+struct Multipart<R> {
+    body: R,
+    buffer: Vec<u8>,
+    delimiter: Vec<u8>,
+    /// 还能从正文读多少字节
+    remaining: u64,
+}
+
+impl<R: Read> Multipart<R> {
+    fn read_parts(&mut self, form: &mut Form, config: &UploadConfig) -> Result<(), HttpError> {
+        // 第一个分隔行之前的内容不要
+        self.read_until_delimiter(&mut |_| Ok(()))?;
+        let mut fields_size = 0;
+        let mut parts = 0;
+        while self.after_delimiter()? {
+            parts += 1;
+            if parts > MAX_PARTS {
+                return Err(HttpError::BodyTooLarge);
+            }
+            let headers = self.read_part_headers()?;
+            let disposition = find_header(&headers, "Content-Disposition")
+                .ok_or(HttpError::BadRequest("part without Content-Disposition"))?;
+            let name = header_param(disposition, "name")
+                .ok_or(HttpError::BadRequest("part without a name"))?;
+            match header_param(disposition, "filename") {
+                // 没有选择文件时浏览器也会发一个文件名为空的部分
+                Some(filename) if filename.is_empty() => {
+                    self.read_until_delimiter(&mut |_| Ok(()))?
+                }
+                Some(filename) => {
+                    let content_type =
+                        find_header(&headers, "Content-Type").unwrap_or("application/octet-stream");
+                    self.save_file(form, config, name, &filename, content_type)?;
+                }
+                None => {
+                    let mut value = Vec::new();
+                    self.read_until_delimiter(&mut |data| {
+                        fields_size += data.len();
+                        if fields_size > MAX_FIELDS_SIZE {
+                            return Err(HttpError::BodyTooLarge);
+                        }
+                        value.extend_from_slice(data);
+                        Ok(())
+                    })?;
+                    let value = String::from_utf8(value)
+                        .map_err(|_| HttpError::BadRequest("form field is not utf-8"))?;
+                    form.fields.push((name, value));
+                }
+            }
+        }
+        // 结束分隔行后面的内容也要读完，连接才能接着用
+        let epilogue = io::copy(
+            &mut (&mut self.body).take(MAX_HEAD_SIZE as u64 + 1),
+            &mut io::sink(),
+        )?;
+        if epilogue > MAX_HEAD_SIZE as u64 {
+            return Err(HttpError::BadRequest("multipart epilogue too long"));
+        }
+        Ok(())
+    }
+
+    fn save_file(
+        &mut self,
+        form: &mut Form,
+        config: &UploadConfig,
+        name: String,
+        filename: &str,
+        content_type: &str,
+    ) -> Result<(), HttpError> {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        fs::create_dir_all(&config.dir)?;
+        let filename = sanitize_filename(filename);
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_nanos());
+        let unique = format!("{nanos:x}-{:x}", NEXT.fetch_add(1, Ordering::Relaxed));
+        let path = config.dir.join(format!("{unique}-{filename}"));
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        // 先登记，中途出错时一起删掉
+        form.files.push(Upload {
+            name,
+            filename,
+            content_type: content_type.to_string(),
+            path,
+            size: 0,
+        });
+        let mut size = 0;
+        self.read_until_delimiter(&mut |data| {
+            size += data.len() as u64;
+            if size > config.max_file_size {
+                return Err(HttpError::BodyTooLarge);
+            }
+            file.write_all(data)?;
+            Ok(())
+        })?;
+        if let Some(upload) = form.files.last_mut() {
+            upload.size = size;
+        }
+        Ok(())
+    }
+
+    fn fill(&mut self) -> Result<(), HttpError> {
+        let mut chunk = [0; 8192];
+        let n = self.body.read(&mut chunk)?;
+        if n == 0 {
+            return Err(HttpError::BadRequest("multipart body ended early"));
+        }
+        self.remaining = self
+            .remaining
+            .checked_sub(n as u64)
+            .ok_or(HttpError::BodyTooLarge)?;
+        self.buffer.extend_from_slice(&chunk[..n]);
+        Ok(())
+    }
+
+    /// 分隔行之前的数据交给 `sink`，分隔行本身丢掉
+    fn read_until_delimiter(
+        &mut self,
+        sink: &mut impl FnMut(&[u8]) -> Result<(), HttpError>,
+    ) -> Result<(), HttpError> {
+        loop {
+            if let Some(at) = find(&self.buffer, &self.delimiter) {
+                sink(&self.buffer[..at])?;
+                self.buffer.drain(..at + self.delimiter.len());
+                return Ok(());
+            }
+            // 末尾可能是被截断的分隔行，先留着
+            let safe = self.buffer.len().saturating_sub(self.delimiter.len() - 1);
+            if safe > 0 {
+                sink(&self.buffer[..safe])?;
+                self.buffer.drain(..safe);
+            }
+            self.fill()?;
+        }
+    }
+
+    /// 分隔行后面是 `--` 表示没有更多部分，否则是可选的空白和换行
+    fn after_delimiter(&mut self) -> Result<bool, HttpError> {
+        while self.buffer.len() < 2 {
+            self.fill()?;
+        }
+        if self.buffer.starts_with(b"--") {
+            return Ok(false);
+        }
+        loop {
+            if let Some(end) = find(&self.buffer, b"\r\n") {
+                if self.buffer[..end].iter().any(|&b| b != b' ' && b != b'\t') {
+                    return Err(HttpError::BadRequest("malformed multipart delimiter"));
+                }
+                self.buffer.drain(..end + 2);
+                return Ok(true);
+            }
+            if self.buffer.len() > MAX_HEAD_SIZE {
+                return Err(HttpError::BadRequest("malformed multipart delimiter"));
+            }
+            self.fill()?;
+        }
+    }
+
+    fn read_part_headers(&mut self) -> Result<Vec<(String, String)>, HttpError> {
+        loop {
+            if self.buffer.starts_with(b"\r\n") {
+                self.buffer.drain(..2);
+                return Ok(Vec::new());
+            }
+            if let Some(end) = find(&self.buffer, b"\r\n\r\n") {
+                let head = str::from_utf8(&self.buffer[..end])
+                    .map_err(|_| HttpError::BadRequest("part headers are not utf-8"))?;
+                let headers = parse_headers(head.split("\r\n"))?;
+                self.buffer.drain(..end + 4);
+                return Ok(headers);
+            }
+            if self.buffer.len() > MAX_HEAD_SIZE {
+                return Err(HttpError::HeadTooLarge);
+            }
+            self.fill()?;
+        }
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// `form-data; name="a"; filename="b;c.txt"` 里的参数，名字不区分大小写，值可以带引号
+fn header_param(value: &str, wanted: &str) -> Option<String> {
+    let mut rest = value.split_once(';')?.1;
+    loop {
+        rest = rest.trim_start_matches([' ', '\t', ';']);
+        let equals = rest.find('=')?;
+        if let Some(semicolon) = rest[..equals].find(';') {
+            // 没有值的参数
+            rest = &rest[semicolon..];
+            continue;
+        }
+        let name = rest[..equals].trim();
+        let after = &rest[equals + 1..];
+        let (param, next) = match after.strip_prefix('"') {
+            Some(quoted) => {
+                let end = quoted.find('"')?;
+                (&quoted[..end], &quoted[end + 1..])
+            }
+            None => {
+                let end = after.find(';').unwrap_or(after.len());
+                (after[..end].trim(), &after[end..])
+            }
+        };
+        if name.eq_ignore_ascii_case(wanted) {
+            return Some(param.to_string());
+        }
+        rest = next;
+    }
+}
+
+/// 只保留路径的最后一段，字母、数字和 `.-_` 以外的字符换成 `_`，去掉开头的点，最长 100 个字符
+pub fn sanitize_filename(filename: &str) -> String {
+    let base = filename.rsplit(['/', '\\']).next().unwrap_or_default();
+    let cleaned: String = base
+        .chars()
+        .map(|c| match c.is_alphanumeric() || ".-_".contains(c) {
+            true => c,
+            false => '_',
+        })
+        .collect();
+    let cleaned: String = cleaned.trim_start_matches('.').chars().take(100).collect();
+    match cleaned.is_empty() {
+        true => "upload".to_string(),
+        false => cleaned,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::HttpConfig;
+
+    /// 每次最多读出几个字节，分隔行会被切在任意位置
+    struct Trickle<'a> {
+        data: &'a [u8],
+        step: usize,
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.step).min(self.data.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            Ok(n)
+        }
+    }
+
+    fn upload_dir(name: &str) -> UploadConfig {
+        UploadConfig {
+            dir: env::temp_dir().join(format!("form-{name}-{}", std::process::id())),
+            max_file_size: 64,
+            max_form_size: 4096,
+        }
+    }
+
+    const BODY: &str = "preamble\r\n--XyZ\r\nContent-Disposition: form-data; name=\"title\"\r\n\r\nhello\r\nworld\r\n--XyZ  \r\nContent-Disposition: form-data; name=\"doc\"; filename=\"../../etc/pa ss;wd.txt\"\r\nContent-Type: text/plain\r\n\r\nline one\r\n--Xy partial\r\n--XyZ\r\nContent-Disposition: form-data; name=\"empty\"; filename=\"\"\r\n\r\n\r\n--XyZ\r\nContent-Disposition: form-data; name=\"tag\"\r\n\r\na\r\n--XyZ\r\nContent-Disposition: form-data; name=\"tag\"\r\n\r\nb\r\n--XyZ--\r\nepilogue";
+
+    #[test]
+    fn stream_multipart_uploads() {
+        let config = upload_dir("ok");
+        for step in [1, 3, 7, 4096] {
+            let body = Trickle {
+                data: BODY.as_bytes(),
+                step,
+            };
+            let form = read_multipart(body, "XyZ", &config).unwrap();
+            assert_eq!(form.get("title"), Some("hello\r\nworld"));
+            assert_eq!(form.get_all("tag").collect::<Vec<_>>(), ["a", "b"]);
+            assert_eq!(form.files.len(), 1);
+            let doc = form.file("doc").unwrap();
+            assert_eq!(doc.filename, "pa_ss_wd.txt");
+            assert_eq!(doc.content_type, "text/plain");
+            assert_eq!(doc.size, 22);
+            assert!(doc.path.starts_with(&config.dir));
+            assert_eq!(fs::read(&doc.path).unwrap(), b"line one\r\n--Xy partial");
+        }
+
+        let small = UploadConfig {
+            max_file_size: 10,
+            ..upload_dir("small")
+        };
+        let err = read_multipart(BODY.as_bytes(), "XyZ", &small).unwrap_err();
+        assert!(matches!(err, HttpError::BodyTooLarge));
+        assert_eq!(fs::read_dir(&small.dir).unwrap().count(), 0);
+        // 每个文件都没超，整个正文超了
+        let limited = UploadConfig {
+            max_form_size: BODY.find("--XyZ--").unwrap() as u64,
+            ..upload_dir("limited")
+        };
+        let body = Trickle {
+            data: BODY.as_bytes(),
+            step: 7,
+        };
+        let err = read_multipart(body, "XyZ", &limited).unwrap_err();
+        assert!(matches!(err, HttpError::BodyTooLarge));
+        assert_eq!(fs::read_dir(&limited.dir).unwrap().count(), 0);
+
+        let truncated = &BODY[..BODY
+            .find("--XyZ\r\nContent-Disposition: form-data; name=\"tag\"")
+            .unwrap()];
+        let err = read_multipart(truncated.as_bytes(), "XyZ", &config).unwrap_err();
+        assert_eq!(err.status(), Some(400));
+        let err = read_multipart(
+            "--XyZ\r\nX: y\r\n\r\nv\r\n--XyZ--".as_bytes(),
+            "XyZ",
+            &config,
+        );
+        assert_eq!(err.unwrap_err().status(), Some(400));
+
+        fs::remove_dir_all(config.dir).unwrap();
+        fs::remove_dir_all(small.dir).unwrap();
+        fs::remove_dir_all(limited.dir).unwrap();
+    }
+
+    #[test]
+    fn read_forms_from_requests() {
+        let config = upload_dir("request");
+        let data = format!(
+            "POST /up HTTP/1.1\r\nHost: h\r\nContent-Type: multipart/form-data; boundary=\"XyZ\"\r\nTransfer-Encoding: chunked\r\n\r\n{:x}\r\n{BODY}\r\n0\r\n\r\nGET /next HTTP/1.1\r\nHost: h\r\n\r\n",
+            BODY.len()
+        );
+        let mut reader = HttpReader::new(data.as_bytes());
+        let request = reader.read_request_head().unwrap().unwrap();
+        let form = read_form(&mut reader, &request, &config).unwrap();
+        assert_eq!(form.get("title"), Some("hello\r\nworld"));
+        assert_eq!(reader.read_request().unwrap().unwrap().path, "/next");
+
+        let data = "POST / HTTP/1.1\r\nHost: h\r\nContent-Type: application/x-www-form-urlencoded\r\nContent-Length: 17\r\n\r\nage=42&name=a+b&x";
+        let mut reader = HttpReader::new(data.as_bytes());
+        let request = reader.read_request_head().unwrap().unwrap();
+        let form = read_form(&mut reader, &request, &config).unwrap();
+        assert_eq!(form.get("name"), Some("a b"));
+        assert_eq!(form.get("x"), Some(""));
+
+        let data = "POST / HTTP/1.1\r\nHost: h\r\nContent-Type: application/json\r\nContent-Length: 2\r\n\r\n{}";
+        let mut reader = HttpReader::new(data.as_bytes());
+        let request = reader.read_request_head().unwrap().unwrap();
+        let err = read_form(&mut reader, &request, &config).unwrap_err();
+        assert_eq!(err.status(), Some(415));
+        fs::remove_dir_all(config.dir).unwrap();
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Signup {
+        name: String,
+        age: u8,
+        referrer: Option<String>,
+    }
+
+    impl FromForm for Signup {
+        fn from_form(form: &Form) -> Result<Signup, FieldError> {
+            Ok(Signup {
+                name: form.value("name")?,
+                age: form.value("age")?,
+                referrer: form.optional("referrer")?,
+            })
+        }
+    }
+
+    #[test]
+    fn typed_fields() {
+        let form = Form::urlencoded(b"name=Ann&age=+42+&referrer=").unwrap();
+        assert_eq!(
+            form.decode::<Signup>(),
+            Ok(Signup {
+                name: "Ann".to_string(),
+                age: 42,
+                referrer: None
+            })
+        );
+        let form = Form::urlencoded(b"name=Ann&age=old").unwrap();
+        assert_eq!(
+            form.decode::<Signup>(),
+            Err(FieldError::Invalid("age".to_string()))
+        );
+        let err = Form::default().decode::<Signup>().unwrap_err();
+        assert_eq!(err, FieldError::Missing("name".to_string()));
+        assert_eq!(Response::from(&err).status, 400);
+        assert!(Form::urlencoded(b"a=%zz").is_err());
+
+        assert_eq!(
+            header_param("form-data; name=\"a\"; filename=\"b;c.txt\"", "filename").as_deref(),
+            Some("b;c.txt")
+        );
+        assert_eq!(
+            header_param("multipart/form-data; charset; BOUNDARY=abc", "boundary").as_deref(),
+            Some("abc")
+        );
+        assert_eq!(header_param("form-data; name=\"a", "name"), None);
+        assert_eq!(sanitize_filename("C:\\Users\\me\\报告 1.pdf"), "报告_1.pdf");
+        assert_eq!(sanitize_filename("..."), "upload");
+        assert_eq!(sanitize_filename(".bashrc"), "bashrc");
+        assert_eq!(sanitize_filename(&"a".repeat(300)).len(), 100);
+    }
+
+    #[test]
+    fn upload_options() {
+        let args = [
+            "--upload-dir",
+            "incoming",
+            "--upload-max",
+            "5M",
+            "--upload-form-max",
+            "20M",
+        ]
+        .map(String::from);
+        let config = HttpConfig::build(args.into_iter()).unwrap();
+        assert_eq!(
+            config.uploads,
+            UploadConfig {
+                dir: PathBuf::from("incoming"),
+                max_file_size: 5 * 1024 * 1024,
+                max_form_size: 20 * 1024 * 1024,
+            }
+        );
+    }
+}
@@ -0,0 +1,1464 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+    net::SocketAddr,
+    path::PathBuf,
+    result, str,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::cgi::CgiConfig;
+use crate::compress;
+use crate::form::{Form, UploadConfig};
+use crate::tls::TlsOptions;
+use crate::units::{parse_duration, parse_size};
+use crate::vhost::SiteConfig;
+
+pub const DEFAULT_ADDRESS: &str = "127.0.0.1:7878";
+pub const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(5);
+pub const DEFAULT_MAX_REQUESTS: usize = 100;
+pub const DEFAULT_THREADS: usize = 4;
+
+pub struct HttpConfig {
+    pub address: String,
+    pub keep_alive: KeepAlive,
+    /// 处理连接的线程数，一个连接占一个线程直到关闭
+    pub threads: usize,
+    /// 正文不小于这个大小时按 `Accept-Encoding` 即时压缩，`None` 表示关闭
+    pub compress_min: Option<u64>,
+    /// `Host` 不属于任何虚拟主机时用的站点
+    pub site: SiteConfig,
+    /// `--vhost` 按出现顺序匹配，之后的站点选项都属于它
+    pub vhosts: Vec<SiteConfig>,
+    /// 所有站点的 CGI 脚本共用超时和并发上限
+    pub cgi: CgiConfig,
+    /// 表单路由收到的文件写到哪里、最大多大
+    pub uploads: UploadConfig,
+    pub tls: TlsOptions,
+}
+
+impl HttpConfig {
+    pub fn build(
+        mut args: impl Iterator<Item = String>,
+    ) -> result::Result<HttpConfig, &'static str> {
+        let mut config = HttpConfig {
+            address: DEFAULT_ADDRESS.to_string(),
+            keep_alive: KeepAlive::default(),
+            threads: DEFAULT_THREADS,
+            compress_min: Some(compress::DEFAULT_MIN_SIZE),
+            site: SiteConfig::default(),
+            vhosts: Vec::new(),
+            cgi: CgiConfig::default(),
+            uploads: UploadConfig::default(),
+            tls: TlsOptions::default(),
+        };
+
+        while let Some(arg) = args.next() {
+            if config.tls.parse_arg(&arg, &mut args)? {
+                continue;
+            }
+            let site = config.vhosts.last_mut().unwrap_or(&mut config.site);
+            if site.parse_arg(&arg, &mut args)? {
+                continue;
+            }
+            match arg.as_str() {
+                "--address" => {
+                    config.address = match args.next() {
+                        Some(address) => address,
+                        None => return Err("--address expects host:port"),
+                    }
+                }
+                "--vhost" => {
+                    let site = args.next().as_deref().and_then(SiteConfig::named);
+                    let site =
+                        site.ok_or("--vhost expects host names such as example.com,*.example.com")?;
+                    let taken = config
+                        .vhosts
+                        .iter()
+                        .flat_map(|other| &other.names)
+                        .any(|name| site.names.contains(name));
+                    if taken {
+                        return Err("--vhost names must be unique");
+                    }
+                    config.vhosts.push(site);
+                }
+                "--cgi-timeout" => {
+                    config.cgi.timeout = match args.next().and_then(|d| parse_duration(&d)) {
+                        Some(duration) if !duration.is_zero() => duration,
+                        _ => return Err("--cgi-timeout expects a duration such as 30s"),
+                    }
+                }
+                "--cgi-max" => {
+                    config.cgi.max_running = match args.next().and_then(|n| n.parse().ok()) {
+                        Some(n) if n > 0 => Some(n),
+                        _ => return Err("--cgi-max expects a positive number"),
+                    }
+                }
+                "--upload-dir" => {
+                    let dir = args.next().ok_or("--upload-dir expects a directory")?;
+                    config.uploads.dir = PathBuf::from(dir);
+                }
+                "--upload-max" => {
+                    config.uploads.max_file_size =
+                        match args.next().and_then(|size| parse_size(&size)) {
+                            Some(size) if size > 0 => size,
+                            _ => return Err("--upload-max expects a size such as 10M"),
+                        }
+                }
+                "--upload-form-max" => {
+                    config.uploads.max_form_size =
+                        match args.next().and_then(|size| parse_size(&size)) {
+                            Some(size) if size > 0 => size,
+                            _ => return Err("--upload-form-max expects a size such as 50M"),
+                        }
+                }
+                "--compress-min" => {
+                    config.compress_min = match args.next().and_then(|size| parse_size(&size)) {
+                        Some(size) => Some(size),
+                        None => return Err("--compress-min expects a size such as 1k"),
+                    }
+                }
+                "--no-compress" => config.compress_min = None,
+                "--keep-alive" => {
+                    config.keep_alive.timeout = match args.next().map(|d| parse_duration(&d)) {
+                        Some(Some(duration)) => Some(duration).filter(|d| !d.is_zero()),
+                        _ => return Err("--keep-alive expects a duration such as 5s, 0 disables"),
+                    }
+                }
+                "--threads" => {
+                    config.threads = match args.next().and_then(|n| n.parse().ok()) {
+                        Some(n) if n > 0 => n,
+                        _ => return Err("--threads expects a positive number"),
+                    }
+                }
+                "--max-requests" => {
+                    config.keep_alive.max_requests = match args.next().and_then(|n| n.parse().ok())
+                    {
+                        Some(n) if n > 0 => n,
+                        _ => return Err("--max-requests expects a positive number"),
+                    }
+                }
+                _ => return Err("unknown http option"),
+            }
+        }
+        config.site.finish()?;
+        for site in &mut config.vhosts {
+            site.finish()?;
+        }
+        config.tls.validate()?;
+        Ok(config)
+    }
+}
+
+/// 一个连接上处理多个请求
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeepAlive {
+    /// 两个请求之间最多等这么久，`None` 表示每个连接只处理一个请求
+    pub timeout: Option<Duration>,
+    pub max_requests: usize,
+}
+
+impl Default for KeepAlive {
+    fn default() -> KeepAlive {
+        KeepAlive {
+            timeout: Some(DEFAULT_KEEP_ALIVE),
+            max_requests: DEFAULT_MAX_REQUESTS,
+        }
+    }
+}
+
+/// 请求头最多这么多字节，超过返回 431
+pub const MAX_HEAD_SIZE: usize = 16 * 1024;
+/// 请求体最多这么多字节，超过返回 413
+pub const MAX_BODY_SIZE: usize = 8 * 1024 * 1024;
+/// 作为客户端把响应整个读进内存时最多读这么多
+pub const MAX_RESPONSE_SIZE: usize = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Version {
+    Http10,
+    Http11,
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Version::Http10 => write!(f, "HTTP/1.0"),
+            Version::Http11 => write!(f, "HTTP/1.1"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    pub method: String,
+    /// 请求行里原样的目标
+    pub target: String,
+    /// 解码后的路径，不含查询串
+    pub path: String,
+    pub query: Vec<(String, String)>,
+    pub version: Version,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// 对端地址，解析时不知道，由服务器读到请求后填上
+    pub peer: Option<SocketAddr>,
+    /// 表单路由的正文由服务器解析好放在这里，`body` 为空
+    pub form: Option<Form>,
+}
+
+impl Request {
+    /// HTTP/1.1 默认保持连接，HTTP/1.0 要显式带 `Connection: keep-alive`
+    pub fn keep_alive(&self) -> bool {
+        let connection = |token: &str| {
+            self.headers
+                .iter()
+                .filter(|(key, _)| key.eq_ignore_ascii_case("Connection"))
+                .flat_map(|(_, value)| value.split(','))
+                .any(|value| value.trim().eq_ignore_ascii_case(token))
+        };
+        match self.version {
+            Version::Http11 => !connection("close"),
+            Version::Http10 => connection("keep-alive"),
+        }
+    }
+
+    /// 带 `Expect: 100-continue` 的客户端要先收到 `100 Continue` 才发正文
+    pub fn expects_continue(&self) -> bool {
+        self.version == Version::Http11
+            && self
+                .header("Expect")
+                .is_some_and(|value| value.trim().eq_ignore_ascii_case("100-continue"))
+    }
+
+    /// 头部名不区分大小写，有多个同名头部时取第一个
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+
+    pub fn query(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// 按原样的请求目标和头部写出，有正文但没有 `Content-Length` 时补上
+    pub fn write_to(&self, mut out: impl Write) -> io::Result<()> {
+        let mut head = format!("{} {} {}\r\n", self.method, self.target, self.version);
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        if !self.body.is_empty() && self.header("Content-Length").is_none() {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        head.push_str("\r\n");
+        out.write_all(head.as_bytes())?;
+        out.write_all(&self.body)?;
+        out.flush()
+    }
+}
+
+#[derive(Debug)]
+pub enum HttpError {
+    BadRequest(&'static str),
+    HeadTooLarge,
+    BodyTooLarge,
+    UnsupportedMediaType,
+    VersionNotSupported,
+    Io(io::Error),
+}
+
+impl HttpError {
+    /// 应该回给客户端的状态码，读写出错时连接已经不可用，返回 `None`
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            HttpError::BadRequest(_) => Some(400),
+            HttpError::HeadTooLarge => Some(431),
+            HttpError::BodyTooLarge => Some(413),
+            HttpError::UnsupportedMediaType => Some(415),
+            HttpError::VersionNotSupported => Some(505),
+            HttpError::Io(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HttpError::BadRequest(reason) => write!(f, "bad request: {reason}"),
+            HttpError::HeadTooLarge => write!(f, "request head larger than {MAX_HEAD_SIZE} bytes"),
+            HttpError::BodyTooLarge => write!(f, "body larger than the size limit"),
+            HttpError::UnsupportedMediaType => write!(f, "unsupported Content-Type"),
+            HttpError::VersionNotSupported => write!(f, "http version not supported"),
+            HttpError::Io(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+/// 状态码对应的原因短语
+pub fn reason(status: u16) -> &'static str {
+    match status {
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        416 => "Range Not Satisfiable",
+        426 => "Upgrade Required",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        _ => "Unknown",
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+impl From<io::Error> for HttpError {
+    /// 读正文时的协议错误包在 `io::Error` 里，这里取出原来的错误
+    fn from(err: io::Error) -> HttpError {
+        if err.get_ref().is_some_and(|inner| inner.is::<HttpError>()) {
+            let kind = err.kind();
+            return match err.into_inner().map(|inner| inner.downcast::<HttpError>()) {
+                Some(Ok(inner)) => *inner,
+                _ => HttpError::Io(kind.into()),
+            };
+        }
+        HttpError::Io(err)
+    }
+}
+
+impl From<HttpError> for io::Error {
+    fn from(err: HttpError) -> io::Error {
+        match err {
+            HttpError::Io(err) => err,
+            err => io::Error::new(io::ErrorKind::InvalidData, err),
+        }
+    }
+}
+
+/// 从连接里逐个读出请求或响应。读多了的数据留在缓冲区里，供下一个消息或者协议升级使用
+pub struct HttpReader<R> {
+    reader: R,
+    buffer: Vec<u8>,
+    max_body: usize,
+    head_deadline: Option<Instant>,
+}
+
+impl<R: Read> HttpReader<R> {
+    pub fn new(reader: R) -> HttpReader<R> {
+        HttpReader {
+            reader,
+            buffer: Vec::new(),
+            max_body: MAX_BODY_SIZE,
+            head_deadline: None,
+        }
+    }
+
+    /// 头部要在这个时刻之前读完，否则返回 `TimedOut`。读超时只限制单次 read，
+    /// 每隔几秒发一个字节的客户端靠它才能断开
+    pub fn set_head_deadline(&mut self, deadline: Option<Instant>) {
+        self.head_deadline = deadline;
+    }
+
+    /// 正文超过这个大小返回 `BodyTooLarge`，默认是 `MAX_BODY_SIZE`
+    pub fn with_max_body(mut self, max_body: usize) -> HttpReader<R> {
+        self.max_body = max_body;
+        self
+    }
+
+    /// 已经读到但还没有解析的数据
+    pub fn buffered(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    /// 对方在两个请求之间关闭连接时返回 `Ok(None)`
+    pub fn read_request(&mut self) -> result::Result<Option<Request>, HttpError> {
+        let Some(mut request) = self.read_request_head()? else {
+            return Ok(None);
+        };
+        request.body = self.read_body(&request.headers)?;
+        Ok(Some(request))
+    }
+
+    /// 只读请求行和头部，正文接着用 `read_body` 或 `body_reader` 读
+    pub fn read_request_head(&mut self) -> result::Result<Option<Request>, HttpError> {
+        match self.read_head()? {
+            Some(head) => parse_head(&head).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// 读一个响应，`100 Continue` 这类中间响应直接跳过。回应 HEAD 的响应没有正文，
+    /// 既没有 `Content-Length` 也不是 chunked 的正文读到对方关闭连接为止
+    pub fn read_response(&mut self, head_request: bool) -> result::Result<Response, HttpError> {
+        loop {
+            let Some(head) = self.read_head()? else {
+                return Err(HttpError::BadRequest("connection closed before response"));
+            };
+            let (status, headers) = parse_status_head(&head)?;
+            if matches!(status, 100..=199) && status != 101 {
+                continue;
+            }
+            let delimited = find_header(&headers, "Transfer-Encoding").is_some()
+                || find_header(&headers, "Content-Length").is_some();
+            let body = if head_request || matches!(status, 100..=199 | 204 | 304) {
+                Vec::new()
+            } else if delimited {
+                self.read_body(&headers)?
+            } else {
+                self.read_to_end()?
+            };
+            return Ok(Response {
+                status,
+                headers,
+                body: Body::Bytes(body),
+            });
+        }
+    }
+
+    /// 返回头部，不含结束的空行
+    fn read_head(&mut self) -> result::Result<Option<String>, HttpError> {
+        let (head_len, consumed) = loop {
+            // 起始行之前的空行要忽略
+            let blank = self
+                .buffer
+                .iter()
+                .take_while(|&&b| b == b'\r' || b == b'\n')
+                .count();
+            self.buffer.drain(..blank);
+            if let Some(end) = find_head_end(&self.buffer) {
+                break end;
+            }
+            if self.buffer.len() > MAX_HEAD_SIZE {
+                return Err(HttpError::HeadTooLarge);
+            }
+            if self
+                .head_deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+            {
+                return Err(HttpError::Io(io::ErrorKind::TimedOut.into()));
+            }
+            if self.fill()? == 0 {
+                return match self.buffer.is_empty() {
+                    true => Ok(None),
+                    false => Err(HttpError::BadRequest("connection closed mid-head")),
+                };
+            }
+        };
+        if head_len > MAX_HEAD_SIZE {
+            return Err(HttpError::HeadTooLarge);
+        }
+        let head: Vec<u8> = self.buffer.drain(..consumed).take(head_len).collect();
+        String::from_utf8(head)
+            .map(Some)
+            .map_err(|_| HttpError::BadRequest("message head is not utf-8"))
+    }
+
+    fn fill(&mut self) -> io::Result<usize> {
+        let mut chunk = [0; 4096];
+        let n = loop {
+            match self.reader.read(&mut chunk) {
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                result => break result?,
+            }
+        };
+        self.buffer.extend_from_slice(&chunk[..n]);
+        Ok(n)
+    }
+
+    /// 读出整个正文。`Content-Length` 超过上限时不读正文直接返回 `BodyTooLarge`
+    pub fn read_body(
+        &mut self,
+        headers: &[(String, String)],
+    ) -> result::Result<Vec<u8>, HttpError> {
+        let max_body = self.max_body;
+        let body = self.body_reader(headers)?;
+        if !body.chunked && body.remaining > max_body as u64 {
+            return Err(HttpError::BodyTooLarge);
+        }
+        let mut bytes = Vec::new();
+        body.take(max_body as u64 + 1).read_to_end(&mut bytes)?;
+        if bytes.len() > max_body {
+            return Err(HttpError::BodyTooLarge);
+        }
+        Ok(bytes)
+    }
+
+    /// 边读边解码的正文，不受 `max_body` 限制，由调用方自己控制读多少
+    pub fn body_reader(
+        &mut self,
+        headers: &[(String, String)],
+    ) -> result::Result<BodyReader<'_, R>, HttpError> {
+        let chunked = match find_header(headers, "Transfer-Encoding") {
+            None => false,
+            Some(_) if find_header(headers, "Content-Length").is_some() => {
+                return Err(HttpError::BadRequest(
+                    "both Content-Length and Transfer-Encoding",
+                ))
+            }
+            Some(encoding) => {
+                let last = encoding.rsplit(',').next().unwrap_or_default().trim();
+                if !last.eq_ignore_ascii_case("chunked") {
+                    return Err(HttpError::BadRequest("unsupported Transfer-Encoding"));
+                }
+                true
+            }
+        };
+        let mut lengths = headers
+            .iter()
+            .filter(|(key, _)| key.eq_ignore_ascii_case("Content-Length"))
+            .map(|(_, value)| parse_content_length(value));
+        let length = match lengths.next() {
+            None => 0,
+            Some(first) => {
+                let first = first?;
+                if lengths.any(|other| other.ok() != Some(first)) {
+                    return Err(HttpError::BadRequest("conflicting Content-Length"));
+                }
+                first
+            }
+        };
+        Ok(BodyReader {
+            reader: self,
+            remaining: length,
+            chunked,
+            done: false,
+        })
+    }
+
+    fn read_to_end(&mut self) -> result::Result<Vec<u8>, HttpError> {
+        loop {
+            if self.buffer.len() > self.max_body {
+                return Err(HttpError::BodyTooLarge);
+            }
+            if self.fill()? == 0 {
+                return Ok(self.buffer.drain(..).collect());
+            }
+        }
+    }
+
+    fn read_line(&mut self) -> result::Result<String, HttpError> {
+        loop {
+            if let Some(end) = self.buffer.iter().position(|&b| b == b'\n') {
+                let mut line: Vec<u8> = self.buffer.drain(..=end).collect();
+                line.pop();
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+                return String::from_utf8(line)
+                    .map_err(|_| HttpError::BadRequest("chunk line is not utf-8"));
+            }
+            if self.buffer.len() > MAX_HEAD_SIZE {
+                return Err(HttpError::HeadTooLarge);
+            }
+            if self.fill()? == 0 {
+                return Err(HttpError::BadRequest("connection closed mid-body"));
+            }
+        }
+    }
+}
+
+/// 请求或响应的正文，按 `Content-Length` 或 chunked 解码，chunked 的 trailer 头部直接丢掉。
+/// 协议错误包在 `io::Error` 里，用 `HttpError::from` 可以取回
+pub struct BodyReader<'a, R> {
+    reader: &'a mut HttpReader<R>,
+    /// 整个正文或者当前块还剩多少字节
+    remaining: u64,
+    chunked: bool,
+    done: bool,
+}
+
+impl<R: Read> BodyReader<'_, R> {
+    fn next_chunk(&mut self) -> result::Result<(), HttpError> {
+        let line = self.reader.read_line()?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(HttpError::BadRequest("invalid chunk size"));
+        }
+        self.remaining = u64::from_str_radix(size, 16)
+            .map_err(|_| HttpError::BadRequest("invalid chunk size"))?;
+        if self.remaining == 0 {
+            while !self.reader.read_line()?.is_empty() {}
+            self.done = true;
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for BodyReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 && !self.done {
+            match self.chunked {
+                true => self.next_chunk().map_err(io::Error::from)?,
+                false => self.done = true,
+            }
+        }
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.reader.buffer.is_empty() && self.reader.fill()? == 0 {
+            return Err(HttpError::BadRequest("connection closed mid-body").into());
+        }
+        let n = buf
+            .len()
+            .min(self.reader.buffer.len())
+            .min(self.remaining.try_into().unwrap_or(usize::MAX));
+        buf[..n].copy_from_slice(&self.reader.buffer[..n]);
+        self.reader.buffer.drain(..n);
+        self.remaining -= n as u64;
+        if self.chunked && self.remaining == 0 && !self.reader.read_line()?.is_empty() {
+            return Err(HttpError::BadRequest("chunk data longer than its size").into());
+        }
+        Ok(n)
+    }
+}
+
+/// 返回 (头部长度, 头部加结束空行的长度)，行尾的 `\r` 可以省略
+pub fn find_head_end(buffer: &[u8]) -> Option<(usize, usize)> {
+    buffer
+        .iter()
+        .enumerate()
+        .filter(|(_, &b)| b == b'\n')
+        .find_map(|(i, _)| match &buffer[i + 1..] {
+            [b'\n', ..] => Some((i, i + 2)),
+            [b'\r', b'\n', ..] => Some((i, i + 3)),
+            _ => None,
+        })
+}
+
+fn parse_content_length(value: &str) -> result::Result<u64, HttpError> {
+    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(HttpError::BadRequest("invalid Content-Length"));
+    }
+    value
+        .parse()
+        .map_err(|_| HttpError::BadRequest("invalid Content-Length"))
+}
+
+fn is_token(text: &str) -> bool {
+    !text.is_empty()
+        && text
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// 解析请求行和头部，不含结束的空行
+pub fn parse_head(head: &str) -> result::Result<Request, HttpError> {
+    let mut lines = head
+        .split('\n')
+        .map(|line| line.strip_suffix('\r').unwrap_or(line));
+    let request_line = lines.next().unwrap_or_default();
+    let mut parts = request_line.split(' ');
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(HttpError::BadRequest("malformed request line"));
+    };
+    if !is_token(method) {
+        return Err(HttpError::BadRequest("invalid method"));
+    }
+    let version = match version {
+        "HTTP/1.1" => Version::Http11,
+        "HTTP/1.0" => Version::Http10,
+        _ if version.len() == 8
+            && version.starts_with("HTTP/")
+            && version.as_bytes()[5].is_ascii_digit()
+            && version.as_bytes()[6] == b'.'
+            && version.as_bytes()[7].is_ascii_digit() =>
+        {
+            return Err(HttpError::VersionNotSupported)
+        }
+        _ => return Err(HttpError::BadRequest("invalid http version")),
+    };
+
+    let headers = parse_headers(lines)?;
+    if version == Version::Http11
+        && !headers
+            .iter()
+            .any(|(name, _)| name.eq_ignore_ascii_case("Host"))
+    {
+        return Err(HttpError::BadRequest("missing Host header"));
+    }
+
+    let (path, query) = parse_target(target)?;
+    Ok(Request {
+        method: method.to_string(),
+        target: target.to_string(),
+        path,
+        query,
+        version,
+        headers,
+        body: Vec::new(),
+        peer: None,
+        form: None,
+    })
+}
+
+/// 解析状态行和头部，不含结束的空行。原因短语不保留，写出时按状态码重新生成
+pub fn parse_status_head(head: &str) -> result::Result<(u16, Vec<(String, String)>), HttpError> {
+    let mut lines = head
+        .split('\n')
+        .map(|line| line.strip_suffix('\r').unwrap_or(line));
+    let status_line = lines.next().unwrap_or_default();
+    let mut parts = status_line.splitn(3, ' ');
+    let (Some(version), Some(status)) = (parts.next(), parts.next()) else {
+        return Err(HttpError::BadRequest("malformed status line"));
+    };
+    if !matches!(version, "HTTP/1.1" | "HTTP/1.0") {
+        return Err(HttpError::VersionNotSupported);
+    }
+    if status.len() != 3 || !status.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(HttpError::BadRequest("invalid status code"));
+    }
+    let status = status.parse().unwrap_or_default();
+    if !(100..600).contains(&status) {
+        return Err(HttpError::BadRequest("invalid status code"));
+    }
+    Ok((status, parse_headers(lines)?))
+}
+
+/// 每行一个 `名字: 值`，行尾已经去掉
+pub fn parse_headers<'a>(
+    lines: impl Iterator<Item = &'a str>,
+) -> result::Result<Vec<(String, String)>, HttpError> {
+    let mut headers = Vec::new();
+    for line in lines {
+        if line.starts_with([' ', '\t']) {
+            return Err(HttpError::BadRequest("obsolete header folding"));
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(HttpError::BadRequest("header without colon"));
+        };
+        if !is_token(name) {
+            return Err(HttpError::BadRequest("invalid header name"));
+        }
+        let value = value.trim_matches([' ', '\t']);
+        if value.bytes().any(|b| b.is_ascii_control() && b != b'\t') {
+            return Err(HttpError::BadRequest("invalid header value"));
+        }
+        headers.push((name.to_string(), value.to_string()));
+    }
+    Ok(headers)
+}
+
+/// 头部名不区分大小写，有多个同名头部时取第一个
+pub fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+/// 支持 origin-form(`/a?b`)、absolute-form(`http://host/a?b`) 和 `*`
+fn parse_target(target: &str) -> result::Result<(String, Vec<(String, String)>), HttpError> {
+    if target == "*" {
+        return Ok(("*".to_string(), Vec::new()));
+    }
+    let origin = match target
+        .strip_prefix("http://")
+        .or_else(|| target.strip_prefix("https://"))
+    {
+        Some(rest) => rest.find('/').map_or("/", |start| &rest[start..]),
+        None => target,
+    };
+    if !origin.starts_with('/') || origin.bytes().any(|b| b <= b' ' || b == 0x7F) {
+        return Err(HttpError::BadRequest("invalid request target"));
+    }
+    let origin = origin.split('#').next().unwrap_or_default();
+    let (path, query) = origin.split_once('?').unwrap_or((origin, ""));
+    let path = percent_decode(path, false).ok_or(HttpError::BadRequest("invalid path encoding"))?;
+    Ok((path, parse_query(query)?))
+}
+
+/// `a=1&b=x+y`，没有 `=` 的参数值为空
+pub fn parse_query(query: &str) -> result::Result<Vec<(String, String)>, HttpError> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            match (percent_decode(key, true), percent_decode(value, true)) {
+                (Some(key), Some(value)) => Ok((key, value)),
+                _ => Err(HttpError::BadRequest("invalid query encoding")),
+            }
+        })
+        .collect()
+}
+
+/// 解码 `%XX`，`plus_as_space` 用于查询串和表单。结果不是合法 UTF-8 时返回 `None`
+pub fn percent_decode(text: &str, plus_as_space: bool) -> Option<String> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = bytes.get(i + 1..i + 3)?;
+                // from_str_radix 接受开头的 `+`，`%+1` 不能当成 0x01
+                if !hex.iter().all(u8::is_ascii_hexdigit) {
+                    return None;
+                }
+                let hex = str::from_utf8(hex).ok()?;
+                decoded.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 3;
+                continue;
+            }
+            b'+' if plus_as_space => decoded.push(b' '),
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8(decoded).ok()
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Body,
+}
+
+/// 文件正文在写出时才边读边发，不会整个读进内存
+#[derive(Debug)]
+pub enum Body {
+    Bytes(Vec<u8>),
+    File(File, Vec<Segment>),
+}
+
+/// 文件正文按顺序写出的各段，multipart 的分隔行放在 `Bytes` 里
+#[derive(Debug, Clone, PartialEq)]
+pub enum Segment {
+    Bytes(Vec<u8>),
+    Range { start: u64, len: u64 },
+}
+
+impl Body {
+    pub fn len(&self) -> u64 {
+        match self {
+            Body::Bytes(bytes) => bytes.len() as u64,
+            Body::File(_, segments) => segments
+                .iter()
+                .map(|segment| match segment {
+                    Segment::Bytes(bytes) => bytes.len() as u64,
+                    Segment::Range { len, .. } => *len,
+                })
+                .sum(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// 文件在发送过程中变短时返回 `UnexpectedEof`，这时连接只能关掉
+    pub fn write_to(&self, out: &mut impl Write) -> io::Result<()> {
+        let (mut file, segments) = match self {
+            Body::Bytes(bytes) => return out.write_all(bytes),
+            Body::File(file, segments) => (file, segments),
+        };
+        for segment in segments {
+            match segment {
+                Segment::Bytes(bytes) => out.write_all(bytes)?,
+                Segment::Range { start, len } => {
+                    file.seek(SeekFrom::Start(*start))?;
+                    if io::copy(&mut file.take(*len), out)? != *len {
+                        return Err(io::ErrorKind::UnexpectedEof.into());
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn into_bytes(self) -> io::Result<Vec<u8>> {
+        match self {
+            Body::Bytes(bytes) => Ok(bytes),
+            body => {
+                let mut bytes = Vec::with_capacity(body.len() as usize);
+                body.write_to(&mut bytes)?;
+                Ok(bytes)
+            }
+        }
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(bytes: Vec<u8>) -> Body {
+        Body::Bytes(bytes)
+    }
+}
+
+impl From<String> for Body {
+    fn from(text: String) -> Body {
+        Body::Bytes(text.into_bytes())
+    }
+}
+
+impl Response {
+    pub fn new(status: u16) -> Response {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Body::Bytes(Vec::new()),
+        }
+    }
+
+    pub fn text(status: u16, body: impl Into<String>) -> Response {
+        Response::new(status)
+            .with_header("Content-Type", "text/plain; charset=utf-8")
+            .with_body(body.into())
+    }
+
+    pub fn html(status: u16, body: impl Into<String>) -> Response {
+        Response::new(status)
+            .with_header("Content-Type", "text/html; charset=utf-8")
+            .with_body(body.into())
+    }
+
+    pub fn json(status: u16, body: impl Into<String>) -> Response {
+        Response::new(status)
+            .with_header("Content-Type", "application/json")
+            .with_body(body.into())
+    }
+
+    /// 正文就是 `404 Not Found` 这样的状态行
+    pub fn error(status: u16) -> Response {
+        Response::text(status, format!("{status} {}\n", reason(status)))
+    }
+
+    /// 同名头部会被替换
+    pub fn with_header(mut self, name: &str, value: impl Into<String>) -> Response {
+        self.headers
+            .retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+        self.headers.push((name.to_string(), value.into()));
+        self
+    }
+
+    pub fn with_body(mut self, body: impl Into<Body>) -> Response {
+        self.body = body.into();
+        self
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+
+    /// 自动补上 `Date` 和 `Content-Length`。回应 HEAD 请求时 `include_body` 为 false，
+    /// 头部和 GET 完全一样，只是不发正文。1xx、204 和 304 没有正文。返回写出的正文字节数
+    pub fn write_to(&self, mut out: impl Write, include_body: bool) -> io::Result<u64> {
+        let bodiless = matches!(self.status, 100..=199 | 204 | 304);
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason(self.status));
+        if self.header("Date").is_none() {
+            head.push_str(&format!("Date: {}\r\n", http_date(SystemTime::now())));
+        }
+        if self.header("Content-Length").is_none() && !bodiless {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        head.push_str("\r\n");
+        out.write_all(head.as_bytes())?;
+        let mut sent = 0;
+        if include_body && !bodiless {
+            self.body.write_to(&mut out)?;
+            sent = self.body.len();
+        }
+        out.flush()?;
+        Ok(sent)
+    }
+}
+
+impl From<&HttpError> for Response {
+    fn from(err: &HttpError) -> Response {
+        let status = err.status().unwrap_or(500);
+        Response::text(status, format!("{status} {}: {err}\n", reason(status)))
+    }
+}
+
+/// 带引号的 JSON 字符串，控制字符都转义
+pub fn json_string(text: &str) -> String {
+    let mut escaped = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+/// RFC 7231 的 IMF-fixdate，例如 `Sun, 06 Nov 1994 08:49:37 GMT`
+pub fn http_date(time: SystemTime) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    let days = secs / 86400;
+    let (year, month, day) = civil_from_days(days as i64);
+    let rest = secs % 86400;
+    format!(
+        "{}, {day:02} {} {year} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days % 7) as usize],
+        MONTHS[month as usize - 1],
+        rest / 3600,
+        rest / 60 % 60,
+        rest % 60
+    )
+}
+
+/// 1970-01-01 以来的天数换算成公历日期，算法来自 Howard Hinnant 的 `civil_from_days`
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = year - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = i64::from((month + 9) % 12);
+    let doy = (153 * mp + 2) / 5 + i64::from(day) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// 只接受 `http_date` 生成的 IMF-fixdate 格式，其它格式返回 `None`
+pub fn parse_http_date(text: &str) -> Option<SystemTime> {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let mut parts = text.split(' ');
+    let (Some(_), Some(day), Some(month), Some(year), Some(time), Some("GMT"), None) = (
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+    ) else {
+        return None;
+    };
+    let number = |text: &str, digits: usize| -> Option<u64> {
+        if text.len() != digits || !text.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        text.parse().ok()
+    };
+    let day = number(day, 2)?;
+    let month = MONTHS.iter().position(|name| *name == month)? as u32 + 1;
+    let year = number(year, 4)?;
+    let mut clock = time.split(':');
+    let (Some(hours), Some(minutes), Some(seconds), None) =
+        (clock.next(), clock.next(), clock.next(), clock.next())
+    else {
+        return None;
+    };
+    let (hours, minutes, seconds) = (number(hours, 2)?, number(minutes, 2)?, number(seconds, 2)?);
+    if !(1..=31).contains(&day) || hours > 23 || minutes > 59 || seconds > 60 || year < 1970 {
+        return None;
+    }
+    let days = days_from_civil(year as i64, month, day as u32) as u64;
+    let secs = days * 86400 + hours * 3600 + minutes * 60 + seconds;
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+/// 编码路径里除了非保留字符和 `/` 以外的字节
+pub fn percent_encode(text: &str) -> String {
+    let mut encoded = String::with_capacity(text.len());
+    for b in text.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                encoded.push(b as char)
+            }
+            _ => encoded.push_str(&format!("%{b:02X}")),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 每次 read 最多返回这么多字节，模拟 TCP 分段
+    struct Trickle<'a> {
+        data: &'a [u8],
+        sizes: Vec<usize>,
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let size = self.sizes.pop().unwrap_or(self.data.len()).max(1);
+            let n = size.min(buf.len()).min(self.data.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            Ok(n)
+        }
+    }
+
+    fn read_all(data: &[u8], sizes: Vec<usize>) -> Vec<result::Result<Request, u16>> {
+        let mut reader = HttpReader::new(Trickle { data, sizes });
+        let mut requests = Vec::new();
+        loop {
+            match reader.read_request() {
+                Ok(Some(request)) => requests.push(Ok(request)),
+                Ok(None) => return requests,
+                Err(err) => {
+                    requests.push(Err(err.status().unwrap_or(0)));
+                    return requests;
+                }
+            }
+        }
+    }
+
+    fn status(data: &[u8]) -> Option<u16> {
+        read_all(data, Vec::new()).pop()?.err()
+    }
+
+    #[test]
+    fn parse_get_with_query() {
+        let requests = read_all(
+            b"GET /docs/a%20b.html?q=rust+lang&page=2&flag HTTP/1.1\r\nHost: example.com\r\nX-Empty:\r\nAccept:  */* \r\n\r\n",
+            vec![1; 20],
+        );
+        let request = requests[0].as_ref().unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path, "/docs/a b.html");
+        assert_eq!(request.query("q"), Some("rust lang"));
+        assert_eq!(request.query("page"), Some("2"));
+        assert_eq!(request.query("flag"), Some(""));
+        assert_eq!(request.version, Version::Http11);
+        assert_eq!(request.header("host"), Some("example.com"));
+        assert_eq!(request.header("accept"), Some("*/*"));
+        assert_eq!(request.header("x-empty"), Some(""));
+        assert!(request.body.is_empty());
+    }
+
+    #[test]
+    fn bodies_and_pipelining() {
+        let data = b"POST /a HTTP/1.1\r\nHost: h\r\nContent-Length: 5\r\n\r\nhelloPOST /b HTTP/1.1\r\nHost: h\r\nTransfer-Encoding: chunked\r\n\r\n4;ext=1\r\nWiki\r\n5\r\npedia\r\n0\r\nTrailer: x\r\n\r\nGET /c HTTP/1.0\n\n";
+        let requests = read_all(data, vec![3; 100]);
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[0].as_ref().unwrap().body, b"hello");
+        assert_eq!(requests[1].as_ref().unwrap().body, b"Wikipedia");
+        let last = requests[2].as_ref().unwrap();
+        assert_eq!((last.path.as_str(), last.version), ("/c", Version::Http10));
+        assert!(requests[0].as_ref().unwrap().keep_alive());
+        assert!(!last.keep_alive());
+    }
+
+    #[test]
+    fn head_deadline() {
+        let data = b"GET /a HTTP/1.1\r\nHost: h\r\n\r\nGET /b HTTP/1.1\r\nHost: h\r\n\r\n";
+        let mut reader = HttpReader::new(Trickle {
+            data,
+            sizes: vec![1; 100],
+        });
+        reader.set_head_deadline(Some(Instant::now() + Duration::from_secs(60)));
+        assert_eq!(reader.read_request_head().unwrap().unwrap().path, "/a");
+        reader.set_head_deadline(Some(Instant::now()));
+        match reader.read_request_head() {
+            Err(HttpError::Io(err)) => assert_eq!(err.kind(), io::ErrorKind::TimedOut),
+            other => panic!("expected a timeout, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn connection_header() {
+        let keep_alive = |head: &str| parse_head(head).unwrap().keep_alive();
+        assert!(!keep_alive(
+            "GET / HTTP/1.1\r\nHost: h\r\nConnection: Upgrade, close"
+        ));
+        assert!(keep_alive(
+            "GET / HTTP/1.1\r\nHost: h\r\nConnection: Upgrade"
+        ));
+        assert!(keep_alive("GET / HTTP/1.0\r\nConnection: Keep-Alive"));
+        assert!(!keep_alive("GET / HTTP/1.0"));
+        let expects_continue = |head: &str| parse_head(head).unwrap().expects_continue();
+        assert!(expects_continue(
+            "POST / HTTP/1.1\r\nHost: h\r\nExpect: 100-Continue"
+        ));
+        assert!(!expects_continue("POST / HTTP/1.0\r\nExpect: 100-continue"));
+        assert!(!expects_continue("POST / HTTP/1.1\r\nHost: h"));
+    }
+
+    #[test]
+    fn malformed_requests() {
+        assert_eq!(status(b"GET / HTTP/1.1\r\n\r\n"), Some(400));
+        assert_eq!(status(b"GET /\r\nHost: h\r\n\r\n"), Some(400));
+        assert_eq!(status(b"GET / HTTP/2.0\r\nHost: h\r\n\r\n"), Some(505));
+        assert_eq!(status(b"GET / FTP/1.1\r\nHost: h\r\n\r\n"), Some(400));
+        assert_eq!(status(b"G(T / HTTP/1.1\r\nHost: h\r\n\r\n"), Some(400));
+        assert_eq!(status(b"GET nope HTTP/1.1\r\nHost: h\r\n\r\n"), Some(400));
+        assert_eq!(status(b"GET / HTTP/1.1\r\nHost h\r\n\r\n"), Some(400));
+        assert_eq!(
+            status(b"GET / HTTP/1.1\r\nHost: h\r\n folded\r\n\r\n"),
+            Some(400)
+        );
+        assert_eq!(status(b"GET /%zz HTTP/1.1\r\nHost: h\r\n\r\n"), Some(400));
+        assert_eq!(
+            status(b"POST / HTTP/1.1\r\nHost: h\r\nContent-Length: -1\r\n\r\n"),
+            Some(400)
+        );
+        assert_eq!(
+            status(
+                b"POST / HTTP/1.1\r\nHost: h\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\nab"
+            ),
+            Some(400)
+        );
+        assert_eq!(
+            status(b"POST / HTTP/1.1\r\nHost: h\r\nTransfer-Encoding: gzip\r\n\r\n"),
+            Some(400)
+        );
+        assert_eq!(
+            status(b"POST / HTTP/1.1\r\nHost: h\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n"),
+            Some(400)
+        );
+        assert_eq!(
+            status(b"POST / HTTP/1.1\r\nHost: h\r\nContent-Length: 10\r\n\r\nshort"),
+            Some(400)
+        );
+        assert_eq!(
+            status(
+                format!(
+                    "POST / HTTP/1.1\r\nHost: h\r\nContent-Length: {}\r\n\r\n",
+                    MAX_BODY_SIZE + 1
+                )
+                .as_bytes()
+            ),
+            Some(413)
+        );
+        let huge = format!(
+            "GET / HTTP/1.1\r\nHost: h\r\nX: {}\r\n\r\n",
+            "a".repeat(MAX_HEAD_SIZE)
+        );
+        assert_eq!(status(huge.as_bytes()), Some(431));
+        assert_eq!(status(b"GET / HTTP/1.1\r\nHost: h\r\n"), Some(400));
+        assert_eq!(status(b"\r\n\r\n"), None);
+    }
+
+    /// 固定种子的 xorshift，测试结果可以复现
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+    }
+
+    #[test]
+    fn fuzz_splits_and_mutations() {
+        let corpus: [&[u8]; 3] = [
+            b"GET /a?b=c HTTP/1.1\r\nHost: h\r\n\r\n",
+            b"POST /p HTTP/1.1\r\nHost: h\r\nContent-Length: 3\r\n\r\nabc",
+            b"PUT /u HTTP/1.1\r\nHost: h\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n",
+        ];
+        let mut rng = Rng(0x2545_F491_4F6C_DD1D);
+        for _ in 0..2000 {
+            let original = corpus[rng.below(corpus.len())];
+            // 同一个合法请求不管怎么分段，解析结果都一样
+            let sizes = (0..original.len()).map(|_| 1 + rng.below(8)).collect();
+            assert_eq!(read_all(original, sizes), read_all(original, Vec::new()));
+
+            // 随机改写、插入、截断，只要求不 panic 并且给出结果
+            let mut mutated = original.to_vec();
+            for _ in 0..1 + rng.below(4) {
+                let at = rng.below(mutated.len());
+                match rng.below(3) {
+                    0 => mutated[at] = rng.next() as u8,
+                    1 => mutated.insert(at, b"\r\n:% \0"[rng.below(6)]),
+                    _ => mutated.truncate(at.max(1)),
+                }
+            }
+            let sizes = (0..mutated.len()).map(|_| 1 + rng.below(8)).collect();
+            let _ = read_all(&mutated, sizes);
+
+            let noise: Vec<u8> = (0..rng.below(64)).map(|_| rng.next() as u8).collect();
+            let _ = read_all(&noise, Vec::new());
+        }
+    }
+
+    #[test]
+    fn write_responses() {
+        let response = Response::error(404).with_header("Date", "today");
+        let mut out = Vec::new();
+        response.write_to(&mut out, true).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 14\r\nContent-Type: text/plain; charset=utf-8\r\nDate: today\r\n\r\n404 Not Found\n"
+        );
+
+        let response = Response::html(200, "<p>hi</p>").with_header("content-type", "text/html");
+        assert_eq!(response.header("Content-Type"), Some("text/html"));
+        let mut out = Vec::new();
+        response.write_to(&mut out, false).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("HTTP/1.1 200 OK\r\nDate: "));
+        assert!(out.ends_with("Content-Length: 9\r\ncontent-type: text/html\r\n\r\n"));
+
+        let err = HttpError::BadRequest("missing Host header");
+        assert_eq!(Response::from(&err).status, 400);
+
+        let mut request = parse_head("PUT /a?b HTTP/1.1\r\nHost: h").unwrap();
+        request.body = b"data".to_vec();
+        let mut out = Vec::new();
+        request.write_to(&mut out).unwrap();
+        assert_eq!(
+            out,
+            b"PUT /a?b HTTP/1.1\r\nHost: h\r\nContent-Length: 4\r\n\r\ndata"
+        );
+        let mut reader = HttpReader::new(out.as_slice());
+        assert_eq!(reader.read_request().unwrap().unwrap().body, request.body);
+    }
+
+    #[test]
+    fn read_responses() {
+        let read = |data: &str, head_request: bool| {
+            HttpReader::new(data.as_bytes()).read_response(head_request)
+        };
+        let response = read(
+            "HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nabcdef",
+            false,
+        )
+        .unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body.into_bytes().unwrap(), b"abc");
+        let response = read(
+            "HTTP/1.0 404 Not Found\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nab\r\n0\r\n\r\n",
+            false,
+        )
+        .unwrap();
+        assert_eq!(response.body.into_bytes().unwrap(), b"ab");
+        let response = read("HTTP/1.1 200 OK\r\n\r\nuntil close", false).unwrap();
+        assert_eq!(response.body.into_bytes().unwrap(), b"until close");
+        let response = read("HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n", true).unwrap();
+        assert_eq!(response.header("Content-Length"), Some("5"));
+        assert!(response.body.is_empty());
+        let response = read("HTTP/1.1 304 Not Modified\r\n\r\nnext", false).unwrap();
+        assert!(response.body.is_empty());
+
+        assert!(read("", false).is_err());
+        assert!(read("HTTP/1.1 20 OK\r\n\r\n", false).is_err());
+        assert!(read("HTTP/2 200 OK\r\n\r\n", false).is_err());
+        assert!(read("HTTP/1.1 200 OK\r\nContent-Length: 9\r\n\r\nshort", false).is_err());
+        let reader = HttpReader::new("HTTP/1.1 200 OK\r\n\r\n0123456789".as_bytes());
+        assert!(matches!(
+            reader.with_max_body(4).read_response(false),
+            Err(HttpError::BodyTooLarge)
+        ));
+    }
+
+    #[test]
+    fn stream_file_bodies() {
+        let path = std::env::temp_dir().join(format!("body-{}", std::process::id()));
+        std::fs::write(&path, "0123456789").unwrap();
+        let segments = vec![
+            Segment::Bytes(b"[".to_vec()),
+            Segment::Range { start: 2, len: 3 },
+            Segment::Bytes(b"|".to_vec()),
+            Segment::Range { start: 8, len: 2 },
+        ];
+        let body = Body::File(File::open(&path).unwrap(), segments);
+        assert_eq!(body.len(), 7);
+        assert_eq!(body.into_bytes().unwrap(), b"[234|89");
+        let past_end = vec![Segment::Range { start: 8, len: 5 }];
+        let body = Body::File(File::open(&path).unwrap(), past_end);
+        assert!(body.into_bytes().is_err());
+        std::fs::remove_file(path).unwrap();
+
+        let mut out = Vec::new();
+        let response = Response::new(304).with_header("Date", "today");
+        response.write_to(&mut out, true).unwrap();
+        assert_eq!(out, b"HTTP/1.1 304 Not Modified\r\nDate: today\r\n\r\n");
+    }
+
+    #[test]
+    fn format_http_dates() {
+        let date = UNIX_EPOCH + std::time::Duration::from_secs(784111777);
+        assert_eq!(http_date(date), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(http_date(UNIX_EPOCH), "Thu, 01 Jan 1970 00:00:00 GMT");
+        let leap = UNIX_EPOCH + std::time::Duration::from_secs(951782400);
+        assert_eq!(http_date(leap), "Tue, 29 Feb 2000 00:00:00 GMT");
+        for date in [date, leap, UNIX_EPOCH] {
+            assert_eq!(parse_http_date(&http_date(date)), Some(date));
+        }
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 25:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 6 Nov 1994 08:49:37 GMT"), None);
+    }
+
+    #[test]
+    fn build_config() {
+        let args = [
+            "--address",
+            "0.0.0.0:80",
+            "--keep-alive",
+            "0",
+            "--max-requests",
+            "10",
+            "--threads",
+            "16",
+        ]
+        .map(String::from);
+        let config = HttpConfig::build(args.into_iter()).unwrap();
+        assert_eq!(config.address, "0.0.0.0:80");
+        assert_eq!(config.keep_alive.timeout, None);
+        assert_eq!(config.keep_alive.max_requests, 10);
+        assert_eq!(config.threads, 16);
+        let args = ["--threads", "0"].map(String::from);
+        assert!(HttpConfig::build(args.into_iter()).is_err());
+    }
+
+    #[test]
+    fn percent_encoding() {
+        assert_eq!(percent_encode("/a b/ü"), "/a%20b/%C3%BC");
+        assert_eq!(
+            percent_decode(&percent_encode("/a b/ü?"), false).unwrap(),
+            "/a b/ü?"
+        );
+        assert_eq!(percent_decode("%2f", false).as_deref(), Some("/"));
+        assert_eq!(percent_decode("%+1", false), None);
+        assert_eq!(percent_decode("%4", false), None);
+    }
+}
@@ -0,0 +1,228 @@
+use std::fmt;
+
+/// 回复和通知里使用的服务器名
+pub const SERVER_NAME: &str = "rust-chat";
+/// 一行最长 512 字节，包括结尾的 `\r\n`
+pub const MAX_LINE: usize = 510;
+
+pub const RPL_WELCOME: &str = "001";
+pub const RPL_YOURHOST: &str = "002";
+pub const RPL_CREATED: &str = "003";
+pub const RPL_MYINFO: &str = "004";
+pub const RPL_UMODEIS: &str = "221";
+pub const RPL_ENDOFWHO: &str = "315";
+pub const RPL_CHANNELMODEIS: &str = "324";
+pub const RPL_NAMREPLY: &str = "353";
+pub const RPL_ENDOFNAMES: &str = "366";
+pub const ERR_NOSUCHNICK: &str = "401";
+pub const ERR_NOSUCHCHANNEL: &str = "403";
+pub const ERR_CANNOTSENDTOCHAN: &str = "404";
+pub const ERR_NORECIPIENT: &str = "411";
+pub const ERR_NOTEXTTOSEND: &str = "412";
+pub const ERR_UNKNOWNCOMMAND: &str = "421";
+pub const ERR_NOMOTD: &str = "422";
+pub const ERR_NONICKNAMEGIVEN: &str = "431";
+pub const ERR_ERRONEUSNICKNAME: &str = "432";
+pub const ERR_NICKNAMEINUSE: &str = "433";
+pub const ERR_NOTONCHANNEL: &str = "442";
+pub const ERR_NOTREGISTERED: &str = "451";
+pub const ERR_NEEDMOREPARAMS: &str = "461";
+pub const ERR_ALREADYREGISTRED: &str = "462";
+pub const ERR_PASSWDMISMATCH: &str = "464";
+pub const ERR_NOPRIVILEGES: &str = "481";
+
+/// `[:prefix] COMMAND param... [:trailing]`，trailing 参数和其它参数一样放在 `params` 里
+#[derive(Debug, Clone, PartialEq)]
+pub struct IrcMessage {
+    pub prefix: Option<String>,
+    pub command: String,
+    pub params: Vec<String>,
+}
+
+impl IrcMessage {
+    pub fn new(prefix: Option<&str>, command: &str, params: &[&str]) -> IrcMessage {
+        IrcMessage {
+            prefix: prefix.map(String::from),
+            command: command.to_string(),
+            params: params.iter().map(|param| param.to_string()).collect(),
+        }
+    }
+
+    /// 空行返回 `None`，命令统一转成大写
+    pub fn parse(line: &str) -> Option<IrcMessage> {
+        let mut rest = line.trim_start_matches(' ');
+        let mut prefix = None;
+        if let Some(stripped) = rest.strip_prefix(':') {
+            let (source, after) = stripped.split_once(' ')?;
+            prefix = Some(source.to_string());
+            rest = after.trim_start_matches(' ');
+        }
+        let (command, mut rest) = rest.split_once(' ').unwrap_or((rest, ""));
+        if command.is_empty() {
+            return None;
+        }
+        let mut params = Vec::new();
+        loop {
+            rest = rest.trim_start_matches(' ');
+            if rest.is_empty() {
+                break;
+            }
+            if let Some(trailing) = rest.strip_prefix(':') {
+                params.push(trailing.to_string());
+                break;
+            }
+            let (param, after) = rest.split_once(' ').unwrap_or((rest, ""));
+            params.push(param.to_string());
+            rest = after;
+        }
+        Some(IrcMessage {
+            prefix,
+            command: command.to_ascii_uppercase(),
+            params,
+        })
+    }
+}
+
+/// 行协议和 WebSocket 用户发来的文本可能带 `\r` 和 NUL，原样转发就能在 IRC 客户端里伪造出新的一行
+fn clean(text: &str) -> String {
+    text.replace(['\r', '\n', '\0'], "")
+}
+
+/// 超长的行在字符边界上截断到 `MAX_LINE` 字节
+impl fmt::Display for IrcMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut line = String::new();
+        if let Some(prefix) = &self.prefix {
+            line.push(':');
+            line.push_str(&clean(prefix));
+            line.push(' ');
+        }
+        line.push_str(&clean(&self.command));
+        if let Some((last, middle)) = self.params.split_last() {
+            for param in middle {
+                line.push(' ');
+                line.push_str(&clean(param));
+            }
+            let last = clean(last);
+            if last.is_empty() || last.contains(' ') || last.starts_with(':') {
+                line.push_str(" :");
+            } else {
+                line.push(' ');
+            }
+            line.push_str(&last);
+        }
+        let mut end = line.len().min(MAX_LINE);
+        while !line.is_char_boundary(end) {
+            end -= 1;
+        }
+        f.write_str(&line[..end])
+    }
+}
+
+/// 服务器发给 `target` 的数字回复
+pub fn reply(code: &str, target: &str, params: &[&str]) -> IrcMessage {
+    let mut message = IrcMessage::new(Some(SERVER_NAME), code, &[target]);
+    message
+        .params
+        .extend(params.iter().map(|param| param.to_string()));
+    message
+}
+
+/// 聊天室 `general` 对应频道 `#general`
+pub fn channel(room: &str) -> String {
+    format!("#{room}")
+}
+
+pub fn room(channel: &str) -> Option<&str> {
+    let room = channel
+        .strip_prefix('#')
+        .or_else(|| channel.strip_prefix('&'))?;
+    if room.is_empty() || room.contains([' ', ',', '\x07']) {
+        return None;
+    }
+    Some(room)
+}
+
+/// RFC 2812 的昵称规则，长度放宽到 30
+pub fn valid_nick(nick: &str) -> bool {
+    let special = |c: char| "[]\\`_^{|}".contains(c);
+    let mut chars = nick.chars();
+    let Some(first) = chars.next() else {
+        return false;
+    };
+    nick.len() <= 30
+        && (first.is_ascii_alphabetic() || special(first))
+        && chars.all(|c| c.is_ascii_alphanumeric() || special(c) || c == '-')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_messages() {
+        assert_eq!(
+            IrcMessage::parse(":alice!a@host privmsg #general :hello there"),
+            Some(IrcMessage::new(
+                Some("alice!a@host"),
+                "PRIVMSG",
+                &["#general", "hello there"]
+            ))
+        );
+        assert_eq!(
+            IrcMessage::parse("USER bob 0 * :Bob  Smith"),
+            Some(IrcMessage::new(
+                None,
+                "USER",
+                &["bob", "0", "*", "Bob  Smith"]
+            ))
+        );
+        assert_eq!(
+            IrcMessage::parse("NICK  bob "),
+            Some(IrcMessage::new(None, "NICK", &["bob"]))
+        );
+        assert_eq!(
+            IrcMessage::parse("PING :"),
+            Some(IrcMessage::new(None, "PING", &[""]))
+        );
+        assert_eq!(IrcMessage::parse(""), None);
+        assert_eq!(IrcMessage::parse(":prefix-only"), None);
+    }
+
+    #[test]
+    fn format_messages() {
+        let message = IrcMessage::new(Some("alice"), "PRIVMSG", &["#general", "hi all"]);
+        assert_eq!(message.to_string(), ":alice PRIVMSG #general :hi all");
+        assert_eq!(
+            IrcMessage::new(None, "NICK", &["bob"]).to_string(),
+            "NICK bob"
+        );
+        assert_eq!(
+            reply(RPL_ENDOFNAMES, "bob", &["#general", "End of /NAMES list"]).to_string(),
+            ":rust-chat 366 bob #general :End of /NAMES list"
+        );
+        let line = ":a PRIVMSG #b ::)";
+        assert_eq!(IrcMessage::parse(line).unwrap().to_string(), line);
+
+        let spoof = IrcMessage::new(Some("eve"), "PRIVMSG", &["#b", "hi\rPRIVMSG #x :spoof\0"]);
+        assert_eq!(spoof.to_string(), ":eve PRIVMSG #b :hiPRIVMSG #x :spoof");
+        let long = "中".repeat(200);
+        let message = IrcMessage::new(Some("eve"), "PRIVMSG", &["#b", &long]).to_string();
+        assert!(message.len() <= MAX_LINE && message.len() > MAX_LINE - "中".len());
+        assert!(message.starts_with(":eve PRIVMSG #b 中"));
+    }
+
+    #[test]
+    fn channels_and_nicks() {
+        assert_eq!(room("#general"), Some("general"));
+        assert_eq!(room("&local"), Some("local"));
+        assert_eq!(room("general"), None);
+        assert_eq!(room("#"), None);
+        assert_eq!(channel("general"), "#general");
+        assert!(valid_nick("alice"));
+        assert!(valid_nick("[bot]-2"));
+        assert!(!valid_nick("2fast"));
+        assert!(!valid_nick("a b"));
+        assert!(!valid_nick(""));
+    }
+}
@@ -0,0 +1,211 @@
+use std::{result, sync::Arc};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::Bytes;
+use dashmap::DashMap;
+
+use crate::http::{json_string, Request, Response};
+use crate::router::{Params, Router};
+
+pub const DEFAULT_ADDRESS: &str = "127.0.0.1:6379";
+
+/// mini-redis 和 http 两边共用的键值存储
+pub type Store = Arc<DashMap<String, Bytes>>;
+
+pub struct KvConfig {
+    /// RESP 协议的监听地址
+    pub address: String,
+    /// 同时启动 http 服务，在 `/kv` 上提供 REST 接口
+    pub http_address: Option<String>,
+}
+
+impl KvConfig {
+    pub fn build(mut args: impl Iterator<Item = String>) -> result::Result<KvConfig, &'static str> {
+        let mut config = KvConfig {
+            address: DEFAULT_ADDRESS.to_string(),
+            http_address: None,
+        };
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--address" => {
+                    config.address = match args.next() {
+                        Some(address) => address,
+                        None => return Err("--address expects host:port"),
+                    }
+                }
+                "--http" => {
+                    config.http_address = match args.next() {
+                        Some(address) => Some(address),
+                        None => return Err("--http expects host:port"),
+                    }
+                }
+                _ => return Err("unknown mini-redis-server option"),
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// 在 `/kv` 下挂上 REST 接口：
+///
+/// - `GET /kv/*key` 取值，`PUT /kv/*key` 把正文原样存成值，`DELETE /kv/*key` 删除
+/// - `GET /kv?prefix=` 按键排序列出这个前缀下的所有键值，不带 `prefix` 列出全部
+///
+/// `/kv/` 后面的整段路径都是键，可以带 `/`（写成 `/` 或者 `%2F` 都行），不能为空
+///
+/// 值是 UTF-8 时放在 `value` 里，否则 base64 以后放在 `base64` 里
+pub fn routes(router: Router, store: &Store) -> Router {
+    let (list_store, get_store, put_store, delete_store) =
+        (store.clone(), store.clone(), store.clone(), store.clone());
+    router
+        .get("/kv", move |request, _| list(&list_store, request))
+        .get("/kv/*key", move |_, params| {
+            let Some(key) = key(params) else {
+                return empty_key();
+            };
+            match get_store.get(key) {
+                Some(value) => Response::json(200, entry(key, &value)),
+                None => not_found(key),
+            }
+        })
+        .route("PUT", "/kv/*key", move |request, params| {
+            let Some(key) = key(params) else {
+                return empty_key();
+            };
+            let value = Bytes::from(request.body.clone());
+            let status = match put_store.insert(key.to_string(), value.clone()) {
+                Some(_) => 200,
+                None => 201,
+            };
+            Response::json(status, entry(key, &value))
+        })
+        .route("DELETE", "/kv/*key", move |_, params| {
+            let Some(key) = key(params) else {
+                return empty_key();
+            };
+            match delete_store.remove(key) {
+                Some(_) => Response::new(204),
+                None => not_found(key),
+            }
+        })
+}
+
+fn list(store: &Store, request: &Request) -> Response {
+    let prefix = request.query("prefix").unwrap_or_default();
+    let mut entries: Vec<_> = store
+        .iter()
+        .filter(|item| item.key().starts_with(prefix))
+        .map(|item| (item.key().clone(), item.value().clone()))
+        .collect();
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    let entries: Vec<_> = entries
+        .iter()
+        .map(|(key, value)| entry(key, value))
+        .collect();
+    Response::json(200, format!("{{\"entries\":[{}]}}", entries.join(",")))
+}
+
+fn entry(key: &str, value: &[u8]) -> String {
+    match std::str::from_utf8(value) {
+        Ok(text) => format!(
+            "{{\"key\":{},\"value\":{}}}",
+            json_string(key),
+            json_string(text)
+        ),
+        Err(_) => format!(
+            "{{\"key\":{},\"base64\":\"{}\"}}",
+            json_string(key),
+            STANDARD.encode(value)
+        ),
+    }
+}
+
+fn key(params: &Params) -> Option<&str> {
+    params.get("key").filter(|key| !key.is_empty())
+}
+
+fn empty_key() -> Response {
+    Response::json(400, r#"{"error":"empty key"}"#)
+}
+
+fn not_found(key: &str) -> Response {
+    Response::json(
+        404,
+        format!("{{\"error\":\"no such key\",\"key\":{}}}", json_string(key)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::parse_head;
+
+    fn send(router: &Router, head: &str, body: &[u8]) -> (u16, String) {
+        let mut request = parse_head(&format!("{head} HTTP/1.1\r\nHost: h")).unwrap();
+        request.body = body.to_vec();
+        let response = router.handle(&request);
+        let body = String::from_utf8(response.body.into_bytes().unwrap()).unwrap();
+        (response.status, body)
+    }
+
+    #[test]
+    fn rest_over_store() {
+        let store = Store::default();
+        let router = routes(Router::new(), &store);
+
+        assert_eq!(
+            send(&router, "PUT /kv/user:1", b"ann \"a\""),
+            (201, r#"{"key":"user:1","value":"ann \"a\""}"#.to_string())
+        );
+        assert_eq!(send(&router, "PUT /kv/user:1", b"ann").0, 200);
+        assert_eq!(send(&router, "PUT /kv/user:2", &[0xff, 0]).0, 201);
+        store.insert("other".to_string(), Bytes::from("x"));
+
+        assert_eq!(
+            send(&router, "GET /kv/user:1", b""),
+            (200, r#"{"key":"user:1","value":"ann"}"#.to_string())
+        );
+        assert_eq!(
+            send(&router, "GET /kv?prefix=user", b""),
+            (
+                200,
+                r#"{"entries":[{"key":"user:1","value":"ann"},{"key":"user:2","base64":"/wA="}]}"#
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            send(&router, "GET /kv", b"").1.matches("\"key\"").count(),
+            3
+        );
+
+        assert_eq!(send(&router, "DELETE /kv/user:1", b"").0, 204);
+        assert_eq!(
+            send(&router, "GET /kv/user:1", b""),
+            (404, r#"{"error":"no such key","key":"user:1"}"#.to_string())
+        );
+        assert_eq!(send(&router, "DELETE /kv/user:1", b"").0, 404);
+        assert!(!store.contains_key("user:1"));
+
+        // 键里的 `/` 不管编码没编码都是同一个键
+        assert_eq!(send(&router, "PUT /kv/dir/a%2Fb", b"x").0, 201);
+        assert!(store.contains_key("dir/a/b"));
+        assert_eq!(
+            send(&router, "GET /kv/dir%2Fa/b", b""),
+            (200, r#"{"key":"dir/a/b","value":"x"}"#.to_string())
+        );
+        assert_eq!(send(&router, "DELETE /kv/dir/a/b", b"").0, 204);
+        assert_eq!(send(&router, "PUT /kv/", b"x").0, 400);
+    }
+
+    #[test]
+    fn build_config() {
+        let args = ["--address", "0.0.0.0:6380", "--http", "127.0.0.1:8080"].map(String::from);
+        let config = KvConfig::build(args.into_iter()).unwrap();
+        assert_eq!(config.address, "0.0.0.0:6380");
+        assert_eq!(config.http_address.as_deref(), Some("127.0.0.1:8080"));
+        let config = KvConfig::build(std::iter::empty()).unwrap();
+        assert_eq!(config.address, DEFAULT_ADDRESS);
+        assert_eq!(config.http_address, None);
+        assert!(KvConfig::build(["--http".to_string()].into_iter()).is_err());
+    }
+}
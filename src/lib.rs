@@ -1,6 +1,29 @@
 #![allow(unused)]
 
+pub mod access_log;
+pub mod auth;
+pub mod bench;
+pub mod cgi;
+pub mod chat;
+pub mod chat_client;
+pub mod compress;
+pub mod conn;
+pub mod fetch;
+pub mod form;
+pub mod http;
+pub mod irc;
+pub mod kv;
+pub mod metrics;
+pub mod moderation;
+pub mod proxy;
+pub mod router;
+pub mod static_files;
 pub mod third;
+pub mod tls;
+pub mod transfer;
+pub mod units;
+pub mod vhost;
+pub mod websocket;
 
 use std::{
     env,
@@ -47,6 +70,11 @@ impl ThreadPool {
 
         self.sender.as_ref().unwrap().send(job).unwrap();
     }
+
+    /// 工作线程数
+    pub fn size(&self) -> usize {
+        self.workers.len()
+    }
 }
 
 impl Drop for ThreadPool {
@@ -2,12 +2,13 @@
 use std::collections::HashMap;
 use std::error::Error;
 use std::fs::File;
-use std::io::{self, Read, Write};
-use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::io::{self, IsTerminal, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
 use std::os::unix::process;
+use std::path::Path;
 use std::process::ExitCode;
 use std::sync::{Arc, Mutex};
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 use std::{fs, result, thread};
 
 use std::env;
@@ -17,18 +18,47 @@ use bytes::Bytes;
 use dashmap::DashMap;
 use mini_redis::server::run;
 use mini_redis::{client, Connection, Frame};
+use rust_commandlines::access_log::{self, AccessLog, AccessLogConfig};
+use rust_commandlines::auth::{self, Credentials};
+use rust_commandlines::bench::{self, BenchConfig};
+use rust_commandlines::cgi::{Cgi, CgiConfig, Slots};
+use rust_commandlines::chat::{
+    self, ChatConfig, ChatServer, History, Message, Payload, Protocol, DEFAULT_ADDRESS,
+};
+use rust_commandlines::chat_client;
+use rust_commandlines::compress;
+use rust_commandlines::conn::Conn;
+use rust_commandlines::fetch::{self, FetchConfig};
+use rust_commandlines::form::{self, UploadConfig};
+use rust_commandlines::http::{
+    HttpConfig, HttpError, HttpReader, KeepAlive, Request, Response, DEFAULT_KEEP_ALIVE,
+};
+use rust_commandlines::kv::{self, KvConfig};
+use rust_commandlines::metrics::ChatMetrics;
+use rust_commandlines::moderation::BanList;
+use rust_commandlines::proxy::{self, Proxy};
+use rust_commandlines::router::Router;
+use rust_commandlines::static_files::{serve_file, StaticFiles};
+use rust_commandlines::tls;
+use rust_commandlines::transfer::FileFrame;
+use rust_commandlines::vhost::{ErrorPages, SiteConfig, VirtualHosts};
+use rust_commandlines::websocket;
 use rust_commandlines::{ThreadPool, run_grep};
 use rust_commandlines::Config;
+use rustls::ServerConfig;
 use tokio::net::TcpListener as TokitTcpListener;
 use tokio::net::TcpStream as TokitTcpStream;
 use tokio::runtime;
 use tun_tap::Iface;
 
-const BANNED_LIMIT: Duration = Duration::from_secs(10 * 60);
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+/// 一行聊天输入的上限，要放得下一个 base64 编码的文件块
+const MAX_LINE_LENGTH: usize = 64 * 1024;
 
 type Result<T> = result::Result<(), T>;
 // type DB = Arc<Mutex<HashMap<String, Bytes>>>;
-type DB = DashMap<String, Bytes>;
+type DB = kv::Store;
 
 struct Command {
     name: &'static str,
@@ -69,162 +99,326 @@ fn underscore_to_camelcase_command(_program: &str, args: env::Args) -> Result<()
     Ok(())
 }
 
-enum Message {
-    // 客户端连接
-    ClientConnected {
-        author: Arc<TcpStream>,
-    },
-    // 断开连接
-    ClientDisconected {
-        author: Arc<TcpStream>,
-    },
-    // 消息
-    New {
-        author: Arc<TcpStream>,
-        msg: Vec<u8>,
-    },
-}
-
-#[derive(Debug)]
-struct Client {
-    conn: Arc<TcpStream>,
-    last_message: SystemTime,
-    strike_count: u32,
-}
-fn server(message: Receiver<Message>) {
-    let mut clients = HashMap::<SocketAddr, Client>::new();
-    let mut banned_mfs = HashMap::<IpAddr, SystemTime>::new();
-    loop {
-        let msg = message.recv().expect("ERROR: could not hung up");
-
-        match msg {
-            Message::ClientConnected { author } => {
-                let author_addr = author.peer_addr().expect("TODO: cache the addr");
-                let mut banned_at = banned_mfs.get(&author_addr.ip());
-                let now = SystemTime::now();
-                banned_at = banned_at.and_then(|bat| {
-                    let duration = now.duration_since(*bat).expect("TODO: clock");
-                    if duration >= BANNED_LIMIT {
-                        None
-                    } else {
-                        Some(bat)
-                    }
-                });
-
-                if let Some(banned_at) = banned_at {
-                    banned_mfs.insert(author_addr.ip(), *banned_at);
-                    writeln!(author.as_ref(), "You are banned");
-                    author.as_ref().shutdown(Shutdown::Both);
-                } else {
-                    clients.insert(
-                        author_addr,
-                        Client {
-                            conn: author.clone(),
-                            last_message: now,
-                            strike_count: 0,
-                        },
-                    );
-                }
-            }
-            Message::ClientDisconected { author } => {
-                let addr = author.peer_addr().expect("ERROR: could not got peer_addr");
-                clients.remove(&addr);
-            }
-            Message::New { author, msg } => {
-                for (addr, client) in &clients {
-                    eprintln!("{addr}:{:?}", client);
-                    let current_addr = author
-                        .peer_addr()
-                        .expect("ERROR: could not got sender peer_addr");
-                    if current_addr != *addr {
-                        let _ = client.conn.as_ref().write(&msg);
-                    }
-                }
-            }
-        };
-    }
-}
-
-fn client(stream: Arc<TcpStream>, sender: Sender<Message>) -> Result<()> {
+fn client(stream: Arc<Conn>, sender: Sender<Message>, protocol: Protocol) -> Result<()> {
     sender
         .send(Message::ClientConnected {
             author: stream.clone(),
+            protocol,
         })
         .map_err(|err| eprint!("ERROR: could not send message to server thread:{err}"))?;
-    let mut buffer = vec![0, 64];
+    let mut buffer = vec![0; 4096];
+    let mut line = Vec::new();
     loop {
         let n = stream.as_ref().read(&mut buffer).map_err(|err| {
-            eprintln!("ERROR: could not read message from server thread:{err}");
             sender.send(Message::ClientDisconected {
                 author: stream.clone(),
+                reason: format!("read failed: {err}"),
             });
         })?;
-        sender
-            .send(Message::New {
-                msg: buffer[0..n].to_vec(),
+        if n == 0 {
+            sender.send(Message::ClientDisconected {
                 author: stream.clone(),
-            })
-            .map_err(|err| eprintln!("ERROR: could not send message to server: {err}"));
+                reason: "connection closed".to_string(),
+            });
+            return Ok(());
+        }
+        line.extend_from_slice(&buffer[0..n]);
+        // 按行切分，不完整的行留到下一次读取
+        while let Some(end) = line.iter().position(|&b| b == b'\n') {
+            let mut msg: Vec<u8> = line.drain(..=end).collect();
+            msg.pop();
+            if msg.last() == Some(&b'\r') {
+                msg.pop();
+            }
+            let text = String::from_utf8_lossy(&msg).into_owned();
+            let msg = match FileFrame::decode(&text) {
+                Some(frame) if protocol == Protocol::Line => Payload::File(frame),
+                _ => Payload::Text(text),
+            };
+            sender
+                .send(Message::New {
+                    msg,
+                    author: stream.clone(),
+                })
+                .map_err(|err| eprintln!("ERROR: could not send message to server: {err}"));
+        }
+        if line.len() > MAX_LINE_LENGTH {
+            sender.send(Message::ClientDisconected {
+                author: stream.clone(),
+                reason: "line too long".to_string(),
+            });
+            return Ok(());
+        }
     }
 }
 
-fn start_tcp_server(_program: &str, _args: env::Args) -> Result<()> {
-    let address = "127.0.0.1:6969";
-    let listener = TcpListener::bind(address)
+fn start_tcp_server(_program: &str, args: env::Args) -> Result<()> {
+    let config = ChatConfig::build(args).map_err(|err| eprintln!("ERROR: {err}"))?;
+    let history = match &config.history_log {
+        Some(path) => History::with_log(config.history_size, path.clone())
+            .map_err(|err| eprintln!("ERROR: could not open {}: {err}", path.display()))?,
+        None => History::new(config.history_size),
+    };
+    let credentials = match &config.credentials {
+        Some(path) => Some(
+            Credentials::load(path)
+                .map_err(|err| eprintln!("ERROR: could not load {}: {err}", path.display()))?,
+        ),
+        None => None,
+    };
+    let banned_mfs = match &config.bans {
+        Some(path) => BanList::load(path.clone())
+            .map_err(|err| eprintln!("ERROR: could not load {}: {err}", path.display()))?,
+        None => BanList::new(),
+    };
+    let address = config.address.clone();
+    let http_address = config.http_address.clone();
+    let listener = TcpListener::bind(&address)
         .map_err(|err| eprintln!("ERROR: could not bind {address}: {err}"))?;
     println!(
         "[DEBUG] tcp server Listen on address:{address}",
         address = address
     );
+    let tls_listener = match config.tls.load() {
+        Ok(Some((tls_address, tls_config))) => {
+            let listener = TcpListener::bind(&tls_address)
+                .map_err(|err| eprintln!("ERROR: could not bind {tls_address}: {err}"))?;
+            println!("[DEBUG] tcp server Listen on tls address:{tls_address}");
+            Some((listener, tls_config))
+        }
+        Ok(None) => None,
+        Err(err) => {
+            eprintln!("ERROR: could not load tls certificate: {err}");
+            return Err(());
+        }
+    };
     let (sender, receiver) = channel();
+    let metrics = Arc::new(ChatMetrics::new());
+
+    let irc_listener = match &config.irc_address {
+        Some(irc_address) => {
+            let listener = TcpListener::bind(irc_address)
+                .map_err(|err| eprintln!("ERROR: could not bind {irc_address}: {err}"))?;
+            println!("[DEBUG] irc server Listen on address:{irc_address}");
+            Some(listener)
+        }
+        None => None,
+    };
+    let chat = ChatServer::new(
+        config,
+        history,
+        credentials,
+        banned_mfs,
+        metrics.clone(),
+        sender.clone(),
+    );
+    thread::spawn(|| chat::server(receiver, chat));
+
+    if let Some(http_address) = &http_address {
+        let http_listener = TcpListener::bind(http_address)
+            .map_err(|err| eprintln!("ERROR: could not bind {http_address}: {err}"))?;
+        println!("[DEBUG] chat websocket Listen on address:{http_address}/ws");
+        let chat = ChatGateway {
+            sender: sender.clone(),
+            metrics: metrics.clone(),
+        };
+        let service = HttpService::single_site(http_router(None, &[], &[], Some(&chat)))
+            .map_err(|err| eprintln!("ERROR: could not open access log: {err}"))?;
+        let service = Arc::new(service);
+        thread::spawn(move || {
+            let pool = ThreadPool::new(4);
+            accept_conns(&http_listener, None, |conn| {
+                let (service, chat) = (service.clone(), chat.clone());
+                pool.execute(move || handle_connection(conn, &service, Some(&chat)))
+            });
+        });
+    }
 
-    thread::spawn(|| server(receiver));
+    let tick_sender = sender.clone();
+    thread::spawn(move || {
+        while tick_sender.send(Message::Tick).is_ok() {
+            thread::sleep(TICK_INTERVAL);
+        }
+    });
 
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                let message_sender: Sender<Message> = sender.clone();
-                let stream = Arc::new(stream);
-                thread::spawn(|| client(stream, message_sender));
-            }
-            Err(err) => {
-                eprintln!("Error: could not accept connection");
+    // 服务端终端作为管理控制台
+    let console_sender = sender.clone();
+    thread::spawn(move || {
+        for line in io::stdin().lines() {
+            let Ok(line) = line else { break };
+            if !line.trim().is_empty() && console_sender.send(Message::Admin { line }).is_err() {
+                break;
             }
         }
+    });
+
+    if let Some((tls_listener, tls_config)) = tls_listener {
+        let sender = sender.clone();
+        thread::spawn(move || {
+            accept_conns(&tls_listener, Some(&tls_config), |conn| {
+                spawn_client(conn, &sender, Protocol::Line)
+            })
+        });
     }
+    if let Some(irc_listener) = irc_listener {
+        let sender = sender.clone();
+        thread::spawn(move || {
+            accept_conns(&irc_listener, None, |conn| {
+                spawn_client(conn, &sender, Protocol::Irc)
+            })
+        });
+    }
+    accept_conns(&listener, None, |conn| {
+        spawn_client(conn, &sender, Protocol::Line)
+    });
     Ok(())
 }
 
-fn connect_tcp_server(_program: &str, _args: env::Args) -> Result<()> {
-    let address = "127.0.0.1:6969";
-    let listener = TcpListener::bind(address)
-        .map_err(|err| eprintln!("ERROR: could not bind {address}: {err}"))?;
-    println!(
-        "[DEBUG] tcp server Listen on address:{address}",
-        address = address
-    );
+fn spawn_client(conn: Conn, sender: &Sender<Message>, protocol: Protocol) {
+    // 写超时避免一个不读数据的客户端卡住整个 server 线程
+    if let Err(err) = conn.stream().set_write_timeout(Some(WRITE_TIMEOUT)) {
+        eprintln!("ERROR: could not set write timeout: {err}");
+    }
+    let message_sender: Sender<Message> = sender.clone();
+    let stream = Arc::new(conn);
+    thread::spawn(move || client(stream, message_sender, protocol));
+}
+
+fn accept_conns(
+    listener: &TcpListener,
+    tls: Option<&Arc<ServerConfig>>,
+    mut handle: impl FnMut(Conn),
+) {
     for stream in listener.incoming() {
-        match stream {
-            Ok(mut stream) => {
-                writeln!(stream, "hallow").map_err(|err| eprintln!("{err}"));
-            }
+        let stream = match stream {
+            Ok(stream) => stream,
             Err(err) => {
-                eprintln!("Error: could not accept connection");
+                eprintln!("Error: could not accept connection: {err}");
+                continue;
             }
+        };
+        // 响应和聊天消息都是分几次写出的，不关掉 Nagle 每次要多等一个延迟确认(约 40ms)
+        if let Err(err) = stream.set_nodelay(true) {
+            eprintln!("ERROR: could not set TCP_NODELAY: {err}");
         }
+        let conn = match tls {
+            Some(tls_config) => match Conn::tls(stream, tls_config.clone()) {
+                Ok(conn) => conn,
+                Err(err) => {
+                    eprintln!("ERROR: could not start tls session: {err}");
+                    continue;
+                }
+            },
+            None => Conn::plain(stream),
+        };
+        handle(conn);
     }
+}
+
+fn tls_selfsigned_command(_program: &str, mut args: env::Args) -> Result<()> {
+    let (Some(cert), Some(key)) = (args.next(), args.next()) else {
+        eprintln!("Usage: tls-selfsigned <cert.pem> <key.pem> [hostname...]");
+        return Err(());
+    };
+    let mut names: Vec<String> = args.collect();
+    if names.is_empty() {
+        names = vec!["localhost".to_string(), "127.0.0.1".to_string()];
+    }
+    tls::write_self_signed(cert.as_ref(), key.as_ref(), names)
+        .map_err(|err| eprintln!("ERROR: could not generate certificate: {err}"))?;
+    println!("wrote {cert} and {key}");
     Ok(())
 }
 
-fn impl_http_server(_program: &str, _args: env::Args) -> Result<()> {
-    let listennewr = TcpListener::bind("127.0.0.1:7878").unwrap();
-    let pool = ThreadPool::new(4);
-    for stream in listennewr.incoming() {
-        let stream = stream.unwrap();
-        pool.execute(|| {
-            handle_connection(stream);
-        });
+/// 密码不放在命令行参数里，免得被 `ps` 和 shell 历史看到。终端上不回显地输入两次，
+/// 否则从标准输入读一行，方便脚本用管道传入
+fn read_password() -> io::Result<String> {
+    if !io::stdin().is_terminal() {
+        let mut password = String::new();
+        io::stdin().read_line(&mut password)?;
+        return Ok(password.trim_end_matches(['\r', '\n']).to_string());
+    }
+    let password = rpassword::prompt_password("password: ")?;
+    if rpassword::prompt_password("again: ")? != password {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "passwords do not match",
+        ));
     }
+    Ok(password)
+}
+
+fn chat_passwd_command(_program: &str, mut args: env::Args) -> Result<()> {
+    let (Some(file), Some(user), None) = (args.next(), args.next(), args.next()) else {
+        eprintln!("Usage: chat-passwd <credentials file> <user>, the password is read from stdin");
+        return Err(());
+    };
+    let password =
+        read_password().map_err(|err| eprintln!("ERROR: could not read password: {err}"))?;
+    if password.is_empty() {
+        eprintln!("ERROR: password must not be empty");
+        return Err(());
+    }
+    auth::add_user(file.as_ref(), &user, &password)
+        .map_err(|err| eprintln!("ERROR: could not add {user} to {file}: {err}"))?;
+    println!("added {user} to {file}");
+    Ok(())
+}
+
+fn connect_tcp_server(_program: &str, mut args: env::Args) -> Result<()> {
+    let address = args.next().unwrap_or_else(|| DEFAULT_ADDRESS.to_string());
+    chat_client::run(&address).map_err(|err| eprintln!("ERROR: chat client failed: {err}"))
+}
+
+fn impl_http_server(_program: &str, args: env::Args) -> Result<()> {
+    let config = HttpConfig::build(args).map_err(|err| eprintln!("ERROR: {err}"))?;
+    let tls = config
+        .tls
+        .load()
+        .map_err(|err| eprintln!("ERROR: could not load tls certificate: {err}"))?;
+    let listennewr = TcpListener::bind(&config.address)
+        .map_err(|err| eprintln!("ERROR: could not bind {}: {err}", config.address))?;
+    let pool = ThreadPool::new(config.threads);
+    // 脚本在处理连接的工作线程里等待，默认留一个线程给其他请求
+    let max_running = config.cgi.max_running.unwrap_or(pool.size() - 1).max(1);
+    let slots = Arc::new(Slots::new(max_running));
+    let mut proxies = Vec::new();
+    let mut logs = Vec::new();
+    let mut open = |site| open_site(site, &config.cgi, &slots, &mut proxies, &mut logs);
+    let mut sites = VirtualHosts::new(open(config.site)?);
+    for vhost in config.vhosts {
+        let names = vhost.names.clone();
+        sites.add(names, open(vhost)?);
+    }
+    if !proxies.is_empty() {
+        proxy::spawn_health_checks(proxies);
+    }
+    let service = Arc::new(HttpService {
+        sites,
+        keep_alive: config.keep_alive,
+        compress_min: config.compress_min,
+        uploads: config.uploads,
+    });
+    thread::scope(|scope| {
+        if let Some((tls_address, tls_config)) = &tls {
+            let tls_listener = TcpListener::bind(tls_address)
+                .map_err(|err| eprintln!("ERROR: could not bind {tls_address}: {err}"))?;
+            let (pool, service) = (&pool, &service);
+            scope.spawn(move || {
+                accept_conns(&tls_listener, Some(tls_config), |conn| {
+                    let service = service.clone();
+                    pool.execute(move || handle_connection(conn, &service, None))
+                })
+            });
+        }
+        accept_conns(&listennewr, None, |conn| {
+            let service = service.clone();
+            pool.execute(move || {
+                handle_connection(conn, &service, None);
+            })
+        });
+        Ok(())
+    })?;
 
     println!("Shutting down.");
     Ok(())
@@ -262,9 +456,28 @@ fn grep_tool(program: &str, args: env::Args) -> Result<()> {
 
 
 
-async fn impl_mini_redis_server(program: &str, args: env::Args) -> Result<()> {
-    let listener = TokitTcpListener::bind("127.0.0.1:6379").await.unwrap();
-    let mut db = DashMap::new();
+async fn impl_mini_redis_server(_program: &str, args: env::Args) -> Result<()> {
+    let config = KvConfig::build(args).map_err(|err| eprintln!("ERROR: {err}"))?;
+    let db = DB::default();
+    if let Some(http_address) = &config.http_address {
+        let http_listener = TcpListener::bind(http_address)
+            .map_err(|err| eprintln!("ERROR: could not bind {http_address}: {err}"))?;
+        println!("[DEBUG] kv rest Listen on address:{http_address}/kv");
+        let service = HttpService::single_site(kv::routes(Router::new(), &db))
+            .map_err(|err| eprintln!("ERROR: could not open access log: {err}"))?;
+        let service = Arc::new(service);
+        thread::spawn(move || {
+            let pool = ThreadPool::new(4);
+            accept_conns(&http_listener, None, |conn| {
+                let service = service.clone();
+                pool.execute(move || handle_connection(conn, &service, None))
+            });
+        });
+    }
+
+    let listener = TokitTcpListener::bind(&config.address)
+        .await
+        .map_err(|err| eprintln!("ERROR: could not bind {}: {err}", config.address))?;
     loop {
         let (socket, _) = listener.accept().await.unwrap();
         let db = db.clone();
@@ -272,8 +485,6 @@ async fn impl_mini_redis_server(program: &str, args: env::Args) -> Result<()> {
             process(socket, db).await;
         });
     }
-
-    Ok(())
 }
 
 async fn process(socket: TokitTcpStream, db: DB) {
@@ -301,6 +512,29 @@ async fn process(socket: TokitTcpStream, db: DB) {
     }
 }
 
+fn fetch_command(_program: &str, args: env::Args) -> Result<()> {
+    let config = FetchConfig::build(args).map_err(|err| eprintln!("ERROR: {err}"))?;
+    let body = match &config.body {
+        Some(path) => fetch::read_body(path)
+            .map_err(|err| eprintln!("ERROR: could not read {}: {err}", path.display()))?,
+        None => Vec::new(),
+    };
+    let response =
+        fetch::fetch(&config, body).map_err(|err| eprintln!("ERROR: {}: {err}", config.url))?;
+    let mut stdout = io::stdout().lock();
+    response
+        .body
+        .write_to(&mut stdout)
+        .and_then(|()| stdout.flush())
+        .map_err(|err| eprintln!("ERROR: could not write response: {err}"))
+}
+
+fn bench_command(_program: &str, args: env::Args) -> Result<()> {
+    let config = BenchConfig::build(args).map_err(|err| eprintln!("ERROR: {err}"))?;
+    print!("{}", bench::run(&config));
+    Ok(())
+}
+
 async fn impl_mini_redis_client(_program: &str, _args: env::Args) -> Result<()> {
     let mut client = client::connect("127.0.0.1:6379")
         .await
@@ -320,50 +554,313 @@ async fn impl_mini_redis_client(_program: &str, _args: env::Args) -> Result<()>
     Ok(())
 }
 
-fn handle_connection(mut stream: TcpStream) {
-    println!("into handler connection");
-    let mut buffer = [0; 1024];
-    let n = stream
-        .read(&mut buffer)
-        .expect("Failed to read from stream");
+/// http 服务访问同进程聊天服务的入口
+#[derive(Clone)]
+struct ChatGateway {
+    sender: Sender<Message>,
+    metrics: Arc<ChatMetrics>,
+}
 
-    let request = String::from_utf8_lossy(&buffer[..]);
+/// 一个 http 端口上的站点和连接设置
+struct HttpService {
+    sites: VirtualHosts<Site>,
+    keep_alive: KeepAlive,
+    compress_min: Option<u64>,
+    uploads: UploadConfig,
+}
 
-    let path = parse_request(&request);
+impl HttpService {
+    /// 只有一个默认站点，其余都用默认设置
+    fn single_site(router: Router) -> io::Result<HttpService> {
+        let site = Site {
+            router,
+            access_log: Arc::new(AccessLog::open(&AccessLogConfig::default())?),
+            error_pages: ErrorPages::default(),
+        };
+        Ok(HttpService {
+            sites: VirtualHosts::new(site),
+            keep_alive: KeepAlive::default(),
+            compress_min: Some(compress::DEFAULT_MIN_SIZE),
+            uploads: UploadConfig::default(),
+        })
+    }
+}
 
-    let hello_file_name: &str = "hello.html";
+/// 一个虚拟主机的路由、访问日志和错误页面
+struct Site {
+    router: Router,
+    access_log: Arc<AccessLog>,
+    error_pages: ErrorPages,
+}
 
-    let response = match path {
-        "/" => "hello world".to_string(),
-        "/hello" => match read_file("hello.html") {
-            Ok(content) => content,
-            Err(_) => "Error reading hello.html".to_string(),
-        },
-        _ => "Not Found".to_string(),
+/// 按站点设置建好路由、访问日志和错误页面，反向代理收集起来统一做健康检查。
+/// 写同一个日志文件的站点共用一个 `AccessLog`，轮转时才不会互相打架
+fn open_site(
+    site: SiteConfig,
+    cgi: &CgiConfig,
+    slots: &Arc<Slots>,
+    proxies: &mut Vec<Arc<Proxy>>,
+    logs: &mut Vec<(AccessLogConfig, Arc<AccessLog>)>,
+) -> result::Result<Site, ()> {
+    let mut cgis = Vec::new();
+    for route in &site.cgi {
+        let script = Cgi::new(route, cgi.timeout, slots.clone())
+            .map_err(|err| eprintln!("ERROR: could not use {}: {err}", route.script.display()))?;
+        cgis.push(Arc::new(script));
+    }
+    let site_proxies: Vec<Arc<Proxy>> = site
+        .proxies
+        .iter()
+        .map(|route| Arc::new(Proxy::new(route)))
+        .collect();
+    proxies.extend(site_proxies.iter().cloned());
+    let access_log = match logs.iter().find(|(config, _)| *config == site.access_log) {
+        Some((_, access_log)) => access_log.clone(),
+        None => {
+            let access_log = AccessLog::open(&site.access_log)
+                .map_err(|err| eprintln!("ERROR: could not open access log: {err}"))?;
+            let access_log = Arc::new(access_log);
+            logs.push((site.access_log, access_log.clone()));
+            access_log
+        }
     };
+    let error_pages = ErrorPages::load(&site.error_pages)
+        .map_err(|err| eprintln!("ERROR: could not load error page {err}"))?;
+    Ok(Site {
+        router: http_router(site.files, &cgis, &site_proxies, None),
+        access_log,
+        error_pages,
+    })
+}
 
-    let response = format!(
-        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
-        response.len(),
-        response
-    );
+fn handle_connection(conn: Conn, service: &HttpService, chat: Option<&ChatGateway>) {
+    let mut stream = &conn;
+    let peer = conn.peer_addr().ok();
+    let client = peer.map_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED), |addr| addr.ip());
+    let keep_alive = &service.keep_alive;
+    // 空闲连接最多占用一个工作线程这么久，关闭 keep-alive 时也要等第一个请求。
+    // 请求头也要在这段时间内读完，慢慢发送的客户端不能一直占着线程
+    let idle = keep_alive.timeout.unwrap_or(DEFAULT_KEEP_ALIVE);
+    let timeouts = conn
+        .stream()
+        .set_read_timeout(Some(idle))
+        .and_then(|()| conn.stream().set_write_timeout(Some(WRITE_TIMEOUT)));
+    if let Err(err) = timeouts {
+        eprintln!("ERROR: could not set timeouts: {err}");
+        return;
+    }
+
+    let hello_file_name: &str = "hello.html";
+
+    let mut reader = HttpReader::new(&conn);
+    let mut served = 0;
+    loop {
+        let mut head_read = false;
+        reader.set_head_deadline(Some(Instant::now() + idle));
+        let result = reader
+            .read_request_head()
+            .and_then(|request| match request {
+                Some(request) => {
+                    head_read = true;
+                    if request.expects_continue() {
+                        stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+                    }
+                    let site = service.sites.pick(&request);
+                    read_request_body(&mut reader, request, site, &service.uploads).map(Some)
+                }
+                None => Ok(None),
+            });
+        let (received, started) = (SystemTime::now(), Instant::now());
+        let log = |site: &Site, request: Option<&Request>, status: u16, bytes: u64| {
+            site.access_log.log(&access_log::Entry {
+                client,
+                time: received,
+                request,
+                status,
+                bytes,
+                latency: started.elapsed(),
+            })
+        };
+        // 请求没读完整时不知道是哪个站点，记到默认站点
+        let default_site = service.sites.default_site();
+        let request = match result {
+            Ok(Some(request)) => Request { peer, ..request },
+            Ok(None) => return,
+            Err(HttpError::Io(err))
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                // 请求发到一半就停下的才回 408，两个请求之间空闲超时直接关闭
+                if head_read || !reader.buffered().is_empty() {
+                    let response = default_site.error_pages.apply(Response::error(408));
+                    let response = response.with_header("Connection", "close");
+                    let bytes = response.write_to(stream, true).unwrap_or(0);
+                    log(default_site, None, 408, bytes);
+                }
+                return;
+            }
+            Err(err) => {
+                eprintln!("ERROR: could not read request: {err}");
+                if err.status().is_some() {
+                    let response = default_site.error_pages.apply(Response::from(&err));
+                    let response = response.with_header("Connection", "close");
+                    let bytes = response.write_to(stream, true).unwrap_or(0);
+                    log(default_site, None, response.status, bytes);
+                }
+                return;
+            }
+        };
+        served += 1;
+        let site = service.sites.pick(&request);
+
+        // 聊天室的 WebSocket 入口，只有和 tcpserver 同进程运行时才有
+        let mut refused = None;
+        if let (Some(chat), "/ws") = (chat, request.path.as_str()) {
+            let upgrade = request
+                .header("Upgrade")
+                .is_some_and(|v| v.eq_ignore_ascii_case("websocket"));
+            let key = request.header("Sec-WebSocket-Key").filter(|_| upgrade);
+            let version = request.header("Sec-WebSocket-Version").map(str::trim);
+            match (key, version) {
+                (Some(key), Some(websocket::VERSION)) => {
+                    if let Err(err) =
+                        stream.write_all(websocket::handshake_response(key).as_bytes())
+                    {
+                        eprintln!("ERROR: could not upgrade websocket: {err}");
+                        return;
+                    }
+                    log(site, Some(&request), 101, 0);
+                    // 聊天连接由心跳检测空闲，不能沿用 http 的读超时
+                    let _ = conn.stream().set_read_timeout(None);
+                    let leftover = reader.buffered().to_vec();
+                    drop(reader);
+                    spawn_client(conn.into_websocket(&leftover), &chat.sender, Protocol::Line);
+                    return;
+                }
+                // 其他版本按 RFC 6455 回 426，并告诉客户端支持哪个版本
+                (Some(_), _) => {
+                    refused = Some(
+                        Response::error(426)
+                            .with_header("Sec-WebSocket-Version", websocket::VERSION),
+                    )
+                }
+                (None, _) => {}
+            }
+        }
 
-    stream.write_all(response.as_bytes()).unwrap();
-    stream.flush().unwrap();
+        let keep = request.keep_alive()
+            && keep_alive.timeout.is_some()
+            && served < keep_alive.max_requests;
+        let response = refused.unwrap_or_else(|| site.router.handle(&request));
+        let mut response = site.error_pages.apply(response);
+        if let Some(min_size) = service.compress_min {
+            response = compress::compress_response(&request, response, min_size);
+        }
+        response = match keep {
+            true => response
+                .with_header("Connection", "keep-alive")
+                .with_header(
+                    "Keep-Alive",
+                    format!(
+                        "timeout={}, max={}",
+                        idle.as_secs(),
+                        keep_alive.max_requests - served
+                    ),
+                ),
+            false => response.with_header("Connection", "close"),
+        };
+        match response.write_to(stream, request.method != "HEAD") {
+            Ok(bytes) => log(site, Some(&request), response.status, bytes),
+            Err(err) => {
+                eprintln!("ERROR: could not write response: {err}");
+                log(site, Some(&request), response.status, 0);
+                return;
+            }
+        }
+        if !keep {
+            return;
+        }
+    }
+}
+
+/// 交给表单路由的请求把正文解析成 `Form`，上传的文件边读边写到磁盘，其余的正文整个读进内存
+fn read_request_body<R: Read>(
+    reader: &mut HttpReader<R>,
+    mut request: Request,
+    site: &Site,
+    uploads: &UploadConfig,
+) -> result::Result<Request, HttpError> {
+    if site.router.wants_form(&request) {
+        request.form = Some(form::read_form(reader, &request, uploads)?);
+    } else {
+        request.body = reader.read_body(&request.headers)?;
+    }
+    Ok(request)
 }
 
-fn read_file(filename: &str) -> io::Result<String> {
-    let mut file = File::open(filename)?;
-    let mut content = String::new();
-    file.read_to_string(&mut content)?;
-    Ok(content)
+/// 聊天入口只在和 tcpserver 同进程时注册，然后依次是 CGI 脚本和反向代理的前缀，
+/// 设置了 `--root` 时其余路径都交给静态文件
+fn http_router(
+    files: Option<StaticFiles>,
+    cgis: &[Arc<Cgi>],
+    proxies: &[Arc<Proxy>],
+    chat: Option<&ChatGateway>,
+) -> Router {
+    let mut router = Router::new();
+    if let Some(chat) = chat {
+        let metrics = chat.metrics.clone();
+        router = router
+            .get("/chat", |request, _| html_page(request, "chat.html"))
+            .get("/metrics", move |_, _| {
+                Response::text(200, metrics.render_prometheus())
+                    .with_header("Content-Type", "text/plain; version=0.0.4")
+            });
+    }
+    for cgi in cgis {
+        let prefix = cgi.prefix().to_string();
+        let cgi = cgi.clone();
+        router = router.mount(&prefix, move |request, _| cgi.run(request));
+    }
+    for proxy in proxies {
+        let prefix = proxy.prefix().to_string();
+        let proxy = proxy.clone();
+        router = router.mount(&prefix, move |request, _| proxy.forward(request));
+    }
+    match files {
+        Some(files) => router.get("/*", move |request, _| files.serve(request)),
+        None => router
+            .get("/", |_, _| Response::text(200, "hello world"))
+            .get("/hello", |request, _| html_page(request, "hello.html"))
+            .post_form("/upload", |_, _, form| {
+                let mut summary = String::new();
+                for (name, value) in &form.fields {
+                    summary += &format!("{name}={value}\n");
+                }
+                // 演示用的路由不保存文件，看完就删
+                for file in &form.files {
+                    summary += &format!(
+                        "{}: {} ({}, {} bytes)\n",
+                        file.name, file.filename, file.content_type, file.size
+                    );
+                    if let Err(err) = fs::remove_file(&file.path) {
+                        eprintln!("ERROR: could not remove {}: {err}", file.path.display());
+                    }
+                }
+                Response::text(200, summary)
+            }),
+    }
 }
 
-fn parse_request(request: &str) -> &str {
-    let lines: Vec<&str> = request.lines().collect();
-    let first_line = lines.first().unwrap_or(&"");
-    let parts: Vec<&str> = first_line.split_whitespace().collect();
-    parts.get(1).map(|&s| s).unwrap_or("")
+/// 内置页面从当前目录读取，找不到属于部署问题，返回 500
+fn html_page(request: &Request, filename: &str) -> Response {
+    let response = serve_file(request, Path::new(filename));
+    if response.status == 404 {
+        eprintln!("ERROR: could not find {filename}");
+        return Response::error(500);
+    }
+    response
 }
 
 fn impl_tcp_protocol(_program: &str, _args: env::Args) -> Result<()> {
@@ -401,6 +898,21 @@ const COMMANDS: &[Command] = &[
         desc: "run a tcp server",
         run: start_tcp_server,
     },
+    Command {
+        name: "tcpclient",
+        desc: "connect to a tcp chat server",
+        run: connect_tcp_server,
+    },
+    Command {
+        name: "chat-passwd",
+        desc: "add a user to a tcpserver credentials file",
+        run: chat_passwd_command,
+    },
+    Command {
+        name: "tls-selfsigned",
+        desc: "generate a self-signed certificate for local tls testing",
+        run: tls_selfsigned_command,
+    },
     Command {
         name: "protocol",
         desc: "accomplish a tcp/ip protocol",
@@ -421,6 +933,16 @@ const COMMANDS: &[Command] = &[
         desc: "accomplish  redis server",
         run: impl_redis_server,
     },
+    Command {
+        name: "fetch",
+        desc: "send an http request and print the response body",
+        run: fetch_command,
+    },
+    Command {
+        name: "bench",
+        desc: "load test the http, chat or mini-redis server",
+        run: bench_command,
+    },
     Command {
         name: "minigrep",
         desc: "accomplish grep tool",
@@ -433,8 +955,11 @@ fn main() -> ExitCode {
     let _program = args.next().expect("program");
     if let Some(command_name) = args.next() {
         if let Some(command) = COMMANDS.iter().find(|command| command.name == command_name) {
-            (command.run)(&_program, args);
-            ExitCode::SUCCESS
+            // 命令自己已经打印过错误
+            match (command.run)(&_program, args) {
+                Ok(()) => ExitCode::SUCCESS,
+                Err(()) => ExitCode::FAILURE,
+            }
         } else {
             usage(&_program);
             ExitCode::FAILURE
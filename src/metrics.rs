@@ -0,0 +1,154 @@
+use std::{
+    collections::VecDeque,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use crate::conn::Traffic;
+
+/// 计算每秒消息数时取最近多少次采样
+const RATE_WINDOW: usize = 10;
+
+/// 聊天服务的计数器和仪表，server 线程写入，http 线程读取
+#[derive(Default)]
+pub struct ChatMetrics {
+    pub connected_clients: AtomicU64,
+    pub connections_total: AtomicU64,
+    pub messages_total: AtomicU64,
+    pub strikes_total: AtomicU64,
+    pub active_bans: AtomicU64,
+    pub traffic: Arc<Traffic>,
+    /// f64 的位模式
+    messages_per_second: AtomicU64,
+    samples: Mutex<VecDeque<u64>>,
+}
+
+impl ChatMetrics {
+    pub fn new() -> ChatMetrics {
+        ChatMetrics::default()
+    }
+
+    /// 每秒调用一次，更新消息速率
+    pub fn tick(&self) {
+        let total = self.messages_total.load(Ordering::Relaxed);
+        let mut samples = self.samples.lock().unwrap();
+        samples.push_back(total);
+        if samples.len() > RATE_WINDOW + 1 {
+            samples.pop_front();
+        }
+        let rate = match samples.front() {
+            Some(first) if samples.len() > 1 => (total - first) as f64 / (samples.len() - 1) as f64,
+            _ => 0.0,
+        };
+        self.messages_per_second
+            .store(rate.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn messages_per_second(&self) -> f64 {
+        f64::from_bits(self.messages_per_second.load(Ordering::Relaxed))
+    }
+
+    fn values(&self) -> [(&'static str, &'static str, &'static str, f64); 8] {
+        let load = |value: &AtomicU64| value.load(Ordering::Relaxed) as f64;
+        [
+            (
+                "chat_connected_clients",
+                "gauge",
+                "Currently connected chat clients.",
+                load(&self.connected_clients),
+            ),
+            (
+                "chat_connections_total",
+                "counter",
+                "Accepted chat connections.",
+                load(&self.connections_total),
+            ),
+            (
+                "chat_messages_total",
+                "counter",
+                "Chat messages received.",
+                load(&self.messages_total),
+            ),
+            (
+                "chat_messages_per_second",
+                "gauge",
+                "Chat messages per second over the last 10 seconds.",
+                self.messages_per_second(),
+            ),
+            (
+                "chat_bytes_in_total",
+                "counter",
+                "Bytes read from chat clients.",
+                load(&self.traffic.bytes_in),
+            ),
+            (
+                "chat_bytes_out_total",
+                "counter",
+                "Bytes written to chat clients.",
+                load(&self.traffic.bytes_out),
+            ),
+            (
+                "chat_strikes_total",
+                "counter",
+                "Strikes issued for failed logins.",
+                load(&self.strikes_total),
+            ),
+            (
+                "chat_active_bans",
+                "gauge",
+                "Currently banned addresses.",
+                load(&self.active_bans),
+            ),
+        ]
+    }
+
+    /// Prometheus 文本格式
+    pub fn render_prometheus(&self) -> String {
+        let mut out = String::new();
+        for (name, kind, help, value) in self.values() {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} {kind}");
+            let _ = writeln!(out, "{name} {value}");
+        }
+        out
+    }
+
+    /// `/stats` 命令的输出
+    pub fn summary(&self) -> Vec<String> {
+        self.values()
+            .iter()
+            .map(|(name, _, _, value)| format!("{}: {value}", name.trim_start_matches("chat_")))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_over_window() {
+        let metrics = ChatMetrics::new();
+        metrics.tick();
+        assert_eq!(metrics.messages_per_second(), 0.0);
+        metrics.messages_total.fetch_add(6, Ordering::Relaxed);
+        metrics.tick();
+        metrics.messages_total.fetch_add(2, Ordering::Relaxed);
+        metrics.tick();
+        assert_eq!(metrics.messages_per_second(), 4.0);
+    }
+
+    #[test]
+    fn prometheus_text() {
+        let metrics = ChatMetrics::new();
+        metrics.connected_clients.store(3, Ordering::Relaxed);
+        metrics.traffic.bytes_in.fetch_add(42, Ordering::Relaxed);
+        let text = metrics.render_prometheus();
+        assert!(text.contains("# TYPE chat_connected_clients gauge\nchat_connected_clients 3\n"));
+        assert!(text.contains("chat_bytes_in_total 42\n"));
+        assert!(metrics.summary().contains(&"strikes_total: 0".to_string()));
+    }
+}
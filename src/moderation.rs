@@ -0,0 +1,177 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Write},
+    net::IpAddr,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ban {
+    pub at: SystemTime,
+    /// `None` 表示永久封禁
+    pub until: Option<SystemTime>,
+}
+
+impl Ban {
+    pub fn is_active(&self, now: SystemTime) -> bool {
+        self.until.is_none_or(|until| now < until)
+    }
+}
+
+/// 被封禁的 IP，配置了文件时每次变更都会整体重写，启动时重新加载
+#[derive(Default)]
+pub struct BanList {
+    bans: HashMap<IpAddr, Ban>,
+    path: Option<PathBuf>,
+}
+
+impl BanList {
+    pub fn new() -> BanList {
+        BanList::default()
+    }
+
+    pub fn load(path: PathBuf) -> io::Result<BanList> {
+        let mut list = BanList::new();
+        if path.exists() {
+            let now = SystemTime::now();
+            for line in fs::read_to_string(&path)?.lines() {
+                if let Some((ip, ban)) = parse_line(line) {
+                    if ban.is_active(now) {
+                        list.bans.insert(ip, ban);
+                    }
+                }
+            }
+        }
+        list.path = Some(path);
+        Ok(list)
+    }
+
+    /// 到期时间超出 `SystemTime` 能表示的范围时返回 `InvalidInput`，不会封禁
+    pub fn ban(&mut self, ip: IpAddr, duration: Option<Duration>) -> io::Result<()> {
+        let at = SystemTime::now();
+        let until = match duration {
+            Some(duration) => Some(at.checked_add(duration).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "ban duration is too long")
+            })?),
+            None => None,
+        };
+        self.bans.insert(ip, Ban { at, until });
+        self.save()
+    }
+
+    pub fn unban(&mut self, ip: &IpAddr) -> io::Result<bool> {
+        let removed = self.bans.remove(ip).is_some();
+        if removed {
+            self.save()?;
+        }
+        Ok(removed)
+    }
+
+    /// 过期的封禁会在这里顺便清掉
+    pub fn is_banned(&mut self, ip: &IpAddr, now: SystemTime) -> bool {
+        match self.bans.get(ip) {
+            Some(ban) if ban.is_active(now) => true,
+            Some(_) => {
+                self.bans.remove(ip);
+                let _ = self.save();
+                false
+            }
+            None => false,
+        }
+    }
+
+    pub fn active(&self, now: SystemTime) -> Vec<(IpAddr, Ban)> {
+        let mut bans: Vec<(IpAddr, Ban)> = self
+            .bans
+            .iter()
+            .filter(|(_, ban)| ban.is_active(now))
+            .map(|(ip, ban)| (*ip, *ban))
+            .collect();
+        bans.sort_by_key(|(ip, _)| *ip);
+        bans
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let mut file = fs::File::create(path)?;
+        for (ip, ban) in &self.bans {
+            let until = ban
+                .until
+                .map_or("-".to_string(), |until| secs(until).to_string());
+            writeln!(file, "{ip} {} {until}", secs(ban.at))?;
+        }
+        Ok(())
+    }
+}
+
+fn secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+// 文件格式: ip 封禁时间 到期时间(永久为 -)
+fn parse_line(line: &str) -> Option<(IpAddr, Ban)> {
+    let mut parts = line.split_whitespace();
+    let ip = parts.next()?.parse().ok()?;
+    let at = UNIX_EPOCH.checked_add(Duration::from_secs(parts.next()?.parse().ok()?))?;
+    let until = match parts.next()? {
+        "-" => None,
+        secs => Some(UNIX_EPOCH.checked_add(Duration::from_secs(secs.parse().ok()?))?),
+    };
+    Some((ip, Ban { at, until }))
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..=59 => format!("{secs}s"),
+        60..=3599 => format!("{}m{}s", secs / 60, secs % 60),
+        3600..=86399 => format!("{}h{}m", secs / 3600, secs % 3600 / 60),
+        _ => format!("{}d{}h", secs / 86400, secs % 86400 / 3600),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::units::parse_duration;
+
+    #[test]
+    fn durations() {
+        assert_eq!(format_duration(Duration::from_secs(3725)), "1h2m");
+        assert_eq!(format_duration(Duration::from_secs(42)), "42s");
+    }
+
+    #[test]
+    fn bans_persist_and_expire() {
+        let path = std::env::temp_dir().join(format!("bans-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let forever: IpAddr = "10.0.0.1".parse().unwrap();
+        let short: IpAddr = "10.0.0.2".parse().unwrap();
+        let gone: IpAddr = "10.0.0.3".parse().unwrap();
+        {
+            let mut bans = BanList::load(path.clone()).unwrap();
+            bans.ban(forever, None).unwrap();
+            bans.ban(short, Some(Duration::from_secs(60))).unwrap();
+            bans.ban(gone, None).unwrap();
+            assert!(bans.unban(&gone).unwrap());
+            assert!(!bans.unban(&gone).unwrap());
+            let too_long = parse_duration("110000000000000d");
+            assert!(bans.ban(gone, too_long).is_err());
+            assert!(!bans.is_banned(&gone, SystemTime::now()));
+        }
+
+        let mut bans = BanList::load(path.clone()).unwrap();
+        let now = SystemTime::now();
+        assert!(bans.is_banned(&forever, now));
+        assert!(bans.is_banned(&short, now));
+        assert!(!bans.is_banned(&gone, now));
+        assert!(!bans.is_banned(&short, now + Duration::from_secs(61)));
+        assert_eq!(bans.active(now).len(), 1);
+        assert_eq!(parse_line(&format!("{gone} 0 {}", u64::MAX)), None);
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,417 @@
+use std::{
+    io,
+    net::{TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::http::{HttpError, HttpReader, Request, Response, Version, MAX_RESPONSE_SIZE};
+use crate::router::parse_prefix;
+
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+/// 上游在这么久里没有响应就回 504
+pub const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(30);
+pub const HEALTH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// 只在一跳之间有效的头部，不能转发
+const HOP_BY_HOP: [&str; 9] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Connection",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+];
+
+/// `--proxy /api=127.0.0.1:9000,127.0.0.1:9001`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProxyRoute {
+    /// 不带结尾的 `/`，转发整个站点时为空
+    pub prefix: String,
+    pub upstreams: Vec<String>,
+}
+
+impl ProxyRoute {
+    pub fn parse(spec: &str) -> Option<ProxyRoute> {
+        let (prefix, upstreams) = spec.split_once('=')?;
+        let prefix = parse_prefix(prefix)?;
+        let upstreams: Vec<String> = upstreams
+            .split(',')
+            .map(|upstream| upstream.trim().to_string())
+            .collect();
+        let valid = |upstream: &String| {
+            upstream
+                .rsplit_once(':')
+                .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok())
+        };
+        if !upstreams.iter().all(valid) {
+            return None;
+        }
+        Some(ProxyRoute { prefix, upstreams })
+    }
+}
+
+/// 把请求轮流转发给几个上游，连不上的上游排到最后，直到健康检查发现它恢复
+pub struct Proxy {
+    prefix: String,
+    upstreams: Vec<Upstream>,
+    next: AtomicUsize,
+}
+
+struct Upstream {
+    address: String,
+    healthy: AtomicBool,
+}
+
+enum Failure {
+    /// 请求还没发出去，可以换一个上游重试
+    Connect(io::Error),
+    Timeout,
+    BadResponse(HttpError),
+}
+
+impl Proxy {
+    pub fn new(route: &ProxyRoute) -> Proxy {
+        Proxy {
+            prefix: route.prefix.clone(),
+            upstreams: route
+                .upstreams
+                .iter()
+                .map(|address| Upstream {
+                    address: address.clone(),
+                    healthy: AtomicBool::new(true),
+                })
+                .collect(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// 所有上游都连不上时回 502，上游超时回 504
+    pub fn forward(&self, request: &Request) -> Response {
+        let head_request = request.method == "HEAD";
+        for upstream in self.candidates() {
+            let failure = match send(&upstream.address, &upstream_request(request, upstream)) {
+                Ok(response) => {
+                    upstream.set_healthy(true, None);
+                    return downstream_response(response, head_request);
+                }
+                Err(failure) => failure,
+            };
+            match failure {
+                Failure::Connect(err) => upstream.set_healthy(false, Some(&err)),
+                Failure::Timeout => {
+                    eprintln!("ERROR: upstream {} timed out", upstream.address);
+                    return Response::error(504);
+                }
+                Failure::BadResponse(err) => {
+                    eprintln!("ERROR: bad response from {}: {err}", upstream.address);
+                    return Response::error(502);
+                }
+            }
+        }
+        Response::error(502)
+    }
+
+    /// 每次从下一个上游开始，健康的排在前面
+    fn candidates(&self) -> Vec<&Upstream> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let count = self.upstreams.len();
+        let mut candidates: Vec<&Upstream> = (0..count)
+            .map(|i| &self.upstreams[(start + i) % count])
+            .collect();
+        candidates.sort_by_key(|upstream| !upstream.healthy.load(Ordering::Relaxed));
+        candidates
+    }
+
+    /// 能建立 TCP 连接就算健康
+    pub fn check_health(&self) {
+        for upstream in &self.upstreams {
+            match connect(&upstream.address) {
+                Ok(_) => upstream.set_healthy(true, None),
+                Err(err) => upstream.set_healthy(false, Some(&err)),
+            }
+        }
+    }
+}
+
+impl Upstream {
+    /// 状态变化时打印一次
+    fn set_healthy(&self, healthy: bool, err: Option<&io::Error>) {
+        if self.healthy.swap(healthy, Ordering::Relaxed) == healthy {
+            return;
+        }
+        match err {
+            Some(err) => eprintln!("ERROR: upstream {} is down: {err}", self.address),
+            None => println!("upstream {} is back up", self.address),
+        }
+    }
+}
+
+/// 后台线程定期检查所有上游
+pub fn spawn_health_checks(proxies: Vec<Arc<Proxy>>) {
+    thread::spawn(move || loop {
+        thread::sleep(HEALTH_INTERVAL);
+        for proxy in &proxies {
+            proxy.check_health();
+        }
+    });
+}
+
+fn connect(address: &str) -> io::Result<TcpStream> {
+    let mut last_err = io::Error::new(io::ErrorKind::NotFound, "address did not resolve");
+    for addr in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = err,
+        }
+    }
+    Err(last_err)
+}
+
+fn send(address: &str, request: &Request) -> Result<Response, Failure> {
+    let stream = connect(address).map_err(Failure::Connect)?;
+    let timed_out = |err: &io::Error| {
+        matches!(
+            err.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+        )
+    };
+    stream
+        .set_read_timeout(Some(UPSTREAM_TIMEOUT))
+        .and_then(|()| stream.set_write_timeout(Some(UPSTREAM_TIMEOUT)))
+        .and_then(|()| request.write_to(&stream))
+        .map_err(|err| match timed_out(&err) {
+            true => Failure::Timeout,
+            false => Failure::BadResponse(err.into()),
+        })?;
+    // 响应整个读进内存再转发，超过大小限制回 502
+    HttpReader::new(&stream)
+        .with_max_body(MAX_RESPONSE_SIZE)
+        .read_response(request.method == "HEAD")
+        .map_err(|err| match err {
+            HttpError::Io(err) if timed_out(&err) => Failure::Timeout,
+            err => Failure::BadResponse(err),
+        })
+}
+
+/// `Connection` 里列出的头部也只在这一跳有效
+fn strip_hop_by_hop(headers: &mut Vec<(String, String)>) {
+    let mut hop: Vec<String> = HOP_BY_HOP.iter().map(|name| name.to_string()).collect();
+    hop.extend(
+        headers
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("Connection"))
+            .flat_map(|(_, value)| value.split(','))
+            .map(|name| name.trim().to_string()),
+    );
+    headers.retain(|(name, _)| !hop.iter().any(|hop| name.eq_ignore_ascii_case(hop)));
+}
+
+/// `Host` 换成上游地址，原来的放进 `X-Forwarded-Host`，客户端地址追加到 `X-Forwarded-For`
+fn upstream_request(request: &Request, upstream: &Upstream) -> Request {
+    let mut headers = request.headers.clone();
+    strip_hop_by_hop(&mut headers);
+    let original_host = request.header("Host").map(str::to_string);
+    let forwarded_for = request.header("X-Forwarded-For").map(str::to_string);
+    // 正文已经完整读到，上游不用再回 100 Continue；正文按 Content-Length 重新发送
+    headers.retain(|(name, _)| {
+        ![
+            "Host",
+            "X-Forwarded-For",
+            "X-Forwarded-Host",
+            "Expect",
+            "Content-Length",
+        ]
+        .iter()
+        .any(|skip| name.eq_ignore_ascii_case(skip))
+    });
+    headers.insert(0, ("Host".to_string(), upstream.address.clone()));
+    if let Some(peer) = request.peer {
+        let forwarded_for = match forwarded_for {
+            Some(earlier) => format!("{earlier}, {}", peer.ip()),
+            None => peer.ip().to_string(),
+        };
+        headers.push(("X-Forwarded-For".to_string(), forwarded_for));
+    }
+    if let Some(host) = original_host {
+        headers.push(("X-Forwarded-Host".to_string(), host));
+    }
+    if request.body.is_empty() && matches!(request.method.as_str(), "POST" | "PUT" | "PATCH") {
+        headers.push(("Content-Length".to_string(), "0".to_string()));
+    }
+    // 上游连接只发一个请求
+    headers.push(("Connection".to_string(), "close".to_string()));
+    Request {
+        target: origin_form(&request.target).to_string(),
+        version: Version::Http11,
+        headers,
+        ..request.clone()
+    }
+}
+
+/// absolute-form 的目标去掉协议和主机
+fn origin_form(target: &str) -> &str {
+    match target
+        .strip_prefix("http://")
+        .or_else(|| target.strip_prefix("https://"))
+    {
+        Some(rest) => rest.find('/').map_or("/", |start| &rest[start..]),
+        None => target,
+    }
+}
+
+/// 正文已经解码成完整的字节，长度由 `Response::write_to` 重新计算。HEAD 的响应没有正文，
+/// 保留上游给的 `Content-Length`
+fn downstream_response(mut response: Response, head_request: bool) -> Response {
+    strip_hop_by_hop(&mut response.headers);
+    if !head_request {
+        response
+            .headers
+            .retain(|(name, _)| !name.eq_ignore_ascii_case("Content-Length"));
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, net::TcpListener};
+
+    use super::*;
+    use crate::http::{parse_head, HttpConfig};
+
+    /// 接受一个连接，返回收到的请求头，回复 `reply`
+    fn upstream(reply: &'static str) -> (String, thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let request = HttpReader::new(&stream).read_request().unwrap().unwrap();
+            (&stream).write_all(reply.as_bytes()).unwrap();
+            let mut head = format!("{} {}\n", request.method, request.target);
+            for (name, value) in &request.headers {
+                head.push_str(&format!("{name}: {value}\n"));
+            }
+            head + &String::from_utf8(request.body).unwrap()
+        });
+        (address, handle)
+    }
+
+    fn request(head: &str, body: &str) -> Request {
+        let mut request = parse_head(head).unwrap();
+        request.body = body.as_bytes().to_vec();
+        request.peer = Some("10.0.0.9:5000".parse().unwrap());
+        request
+    }
+
+    #[test]
+    fn parse_routes() {
+        let route = ProxyRoute::parse("/api/=127.0.0.1:9000, localhost:9001").unwrap();
+        assert_eq!(route.prefix, "/api");
+        assert_eq!(route.upstreams, ["127.0.0.1:9000", "localhost:9001"]);
+        assert_eq!(ProxyRoute::parse("/=[::1]:80").unwrap().prefix, "");
+        assert_eq!(ProxyRoute::parse("api=h:1"), None);
+        assert_eq!(ProxyRoute::parse("/:id=h:1"), None);
+        assert_eq!(ProxyRoute::parse("/api=h"), None);
+        assert_eq!(ProxyRoute::parse("/api=h:1,:2"), None);
+        assert_eq!(ProxyRoute::parse("/api"), None);
+    }
+
+    #[test]
+    fn forward_requests() {
+        let (address, handle) = upstream(
+            "HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 201 Created\r\nConnection: close, X-Hop\r\nX-Hop: 1\r\nTransfer-Encoding: chunked\r\nX-Upstream: yes\r\n\r\n3\r\nabc\r\n0\r\n\r\n",
+        );
+        let proxy = Proxy::new(&ProxyRoute {
+            prefix: "/api".to_string(),
+            upstreams: vec![address.clone()],
+        });
+        let response = proxy.forward(&request(
+            "POST http://example.com/api/items?x=1 HTTP/1.1\r\nHost: example.com\r\nX-Forwarded-For: 1.2.3.4\r\nConnection: keep-alive, X-Secret\r\nX-Secret: s\r\nExpect: 100-continue\r\nTransfer-Encoding: chunked",
+            "hello",
+        ));
+        assert_eq!(response.status, 201);
+        assert_eq!(response.header("X-Upstream"), Some("yes"));
+        assert_eq!(response.header("X-Hop"), None);
+        assert_eq!(response.header("Transfer-Encoding"), None);
+        assert_eq!(response.body.into_bytes().unwrap(), b"abc");
+
+        let received = handle.join().unwrap();
+        assert_eq!(
+            received,
+            format!("POST /api/items?x=1\nHost: {address}\nX-Forwarded-For: 1.2.3.4, 10.0.0.9\nX-Forwarded-Host: example.com\nConnection: close\nContent-Length: 5\nhello")
+        );
+    }
+
+    #[test]
+    fn balance_and_fail_over() {
+        // 先占一个端口再释放，保证连不上
+        let dead = TcpListener::bind("127.0.0.1:0").unwrap();
+        let dead_address = dead.local_addr().unwrap().to_string();
+        drop(dead);
+        let (first, first_handle) = upstream("HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\n1");
+        let (second, second_handle) = upstream("HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\n2");
+        let proxy = Proxy::new(&ProxyRoute {
+            prefix: String::new(),
+            upstreams: vec![first, dead_address, second],
+        });
+        let get = || {
+            let response = proxy.forward(&request("GET / HTTP/1.1\r\nHost: h", ""));
+            (response.status, response.body.into_bytes().unwrap())
+        };
+        assert_eq!(get(), (200, b"1".to_vec()));
+        // 轮到坏掉的上游时换下一个，并把它标记为不健康
+        assert_eq!(get(), (200, b"2".to_vec()));
+        assert!(!proxy.upstreams[1].healthy.load(Ordering::Relaxed));
+        first_handle.join().unwrap();
+        second_handle.join().unwrap();
+        // 所有上游都连不上
+        assert_eq!(get().0, 502);
+        proxy.check_health();
+        assert!(proxy
+            .upstreams
+            .iter()
+            .all(|u| !u.healthy.load(Ordering::Relaxed)));
+    }
+
+    #[test]
+    fn bad_upstream_responses() {
+        let (address, handle) = upstream("garbage\r\n\r\n");
+        let proxy = Proxy::new(&ProxyRoute {
+            prefix: String::new(),
+            upstreams: vec![address],
+        });
+        let response = proxy.forward(&request("GET / HTTP/1.1\r\nHost: h", ""));
+        assert_eq!(response.status, 502);
+        handle.join().unwrap();
+
+        let response =
+            downstream_response(Response::new(200).with_header("Content-Length", "10"), true);
+        assert_eq!(response.header("Content-Length"), Some("10"));
+    }
+
+    #[test]
+    fn proxy_options() {
+        let args = ["--proxy", "/api=127.0.0.1:9000,127.0.0.1:9001"].map(String::from);
+        let config = HttpConfig::build(args.into_iter()).unwrap();
+        assert_eq!(
+            config.site.proxies,
+            [ProxyRoute {
+                prefix: "/api".to_string(),
+                upstreams: vec!["127.0.0.1:9000".to_string(), "127.0.0.1:9001".to_string()],
+            }]
+        );
+        let args = ["--proxy", "/api"].map(String::from);
+        assert!(HttpConfig::build(args.into_iter()).is_err());
+    }
+}
@@ -0,0 +1,333 @@
+use std::sync::Arc;
+
+use crate::form::Form;
+use crate::http::{Request, Response};
+
+pub type Handler = Box<dyn Fn(&Request, &Params) -> Response + Send + Sync>;
+
+/// 按注册顺序匹配 `方法 + 路径模式`。模式里 `:name` 匹配一段，`*` 或 `*name` 只能放在最后，
+/// 匹配剩下的零段或多段。GET 路由同时处理 HEAD，方法写成 `*` 的路由处理所有方法。
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+struct Route {
+    method: String,
+    pattern: Vec<Segment>,
+    handler: Handler,
+    /// 表单路由的正文由服务器先解析成 `Form`
+    form: bool,
+}
+
+impl Route {
+    fn accepts(&self, method: &str) -> bool {
+        let method = match method {
+            "HEAD" => "GET",
+            method => method,
+        };
+        self.method == method || self.method == "*"
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Segment {
+    Literal(String),
+    Param(String),
+    Wildcard(String),
+}
+
+/// 路径参数，通配符没有名字时用 `*` 取
+#[derive(Debug, Default, PartialEq)]
+pub struct Params(Vec<(String, String)>);
+
+impl Params {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router::default()
+    }
+
+    /// 模式写错属于程序错误，直接 panic
+    pub fn route(
+        mut self,
+        method: &str,
+        pattern: &str,
+        handler: impl Fn(&Request, &Params) -> Response + Send + Sync + 'static,
+    ) -> Router {
+        let pattern = parse_pattern(pattern).unwrap_or_else(|err| panic!("{pattern}: {err}"));
+        self.routes.push(Route {
+            method: method.to_ascii_uppercase(),
+            pattern,
+            handler: Box::new(handler),
+            form: false,
+        });
+        self
+    }
+
+    pub fn get(
+        self,
+        pattern: &str,
+        handler: impl Fn(&Request, &Params) -> Response + Send + Sync + 'static,
+    ) -> Router {
+        self.route("GET", pattern, handler)
+    }
+
+    pub fn post(
+        self,
+        pattern: &str,
+        handler: impl Fn(&Request, &Params) -> Response + Send + Sync + 'static,
+    ) -> Router {
+        self.route("POST", pattern, handler)
+    }
+
+    /// 只接受 urlencoded 和 multipart 表单，上传的文件已经写进上传目录
+    pub fn post_form(
+        self,
+        pattern: &str,
+        handler: impl Fn(&Request, &Params, &Form) -> Response + Send + Sync + 'static,
+    ) -> Router {
+        let mut router = self.route("POST", pattern, move |request, params| {
+            match &request.form {
+                Some(form) => handler(request, params, form),
+                None => Response::error(415),
+            }
+        });
+        if let Some(route) = router.routes.last_mut() {
+            route.form = true;
+        }
+        router
+    }
+
+    /// 请求会交给表单路由时，服务器读正文要用 `form::read_form`
+    pub fn wants_form(&self, request: &Request) -> bool {
+        self.routes
+            .iter()
+            .find(|route| {
+                route.accepts(&request.method) && matches(&route.pattern, &request.path).is_some()
+            })
+            .is_some_and(|route| route.form)
+    }
+
+    pub fn any(
+        self,
+        pattern: &str,
+        handler: impl Fn(&Request, &Params) -> Response + Send + Sync + 'static,
+    ) -> Router {
+        self.route("*", pattern, handler)
+    }
+
+    /// 前缀本身和它下面的所有路径都交给 `handler`，所有方法都处理。前缀为空表示整个站点
+    pub fn mount(
+        mut self,
+        prefix: &str,
+        handler: impl Fn(&Request, &Params) -> Response + Send + Sync + 'static,
+    ) -> Router {
+        let handler = Arc::new(handler);
+        let patterns = match prefix {
+            "" => vec!["/*".to_string()],
+            prefix => vec![prefix.to_string(), format!("{prefix}/*")],
+        };
+        for pattern in patterns {
+            let handler = handler.clone();
+            self = self.any(&pattern, move |request, params| handler(request, params));
+        }
+        self
+    }
+
+    /// 路径匹配但方法不对时返回 405 和 `Allow`，都不匹配时返回 404
+    pub fn handle(&self, request: &Request) -> Response {
+        let mut allowed = Vec::new();
+        for route in &self.routes {
+            let Some(params) = matches(&route.pattern, &request.path) else {
+                continue;
+            };
+            if route.accepts(&request.method) {
+                return (route.handler)(request, &params);
+            }
+            allowed.push(route.method.as_str());
+            if route.method == "GET" {
+                allowed.push("HEAD");
+            }
+        }
+        if allowed.is_empty() {
+            return Response::error(404);
+        }
+        allowed.sort();
+        allowed.dedup();
+        Response::error(405).with_header("Allow", allowed.join(", "))
+    }
+}
+
+/// 命令行里给 `mount` 的前缀：必须以 `/` 开头，不能带 `:` 和 `*`，去掉结尾的 `/`
+pub fn parse_prefix(prefix: &str) -> Option<String> {
+    if !prefix.starts_with('/') || prefix.contains([':', '*']) {
+        return None;
+    }
+    Some(prefix.trim_end_matches('/').to_string())
+}
+
+fn parse_pattern(pattern: &str) -> Result<Vec<Segment>, &'static str> {
+    let Some(rest) = pattern.strip_prefix('/') else {
+        return Err("pattern must start with /");
+    };
+    let parts: Vec<&str> = rest.split('/').collect();
+    let mut segments = Vec::new();
+    for (i, part) in parts.iter().enumerate() {
+        let segment = if let Some(name) = part.strip_prefix(':') {
+            if name.is_empty() {
+                return Err("parameter needs a name");
+            }
+            Segment::Param(name.to_string())
+        } else if let Some(name) = part.strip_prefix('*') {
+            if i + 1 != parts.len() {
+                return Err("wildcard must be the last segment");
+            }
+            Segment::Wildcard(if name.is_empty() { "*" } else { name }.to_string())
+        } else {
+            Segment::Literal(part.to_string())
+        };
+        segments.push(segment);
+    }
+    Ok(segments)
+}
+
+fn matches(pattern: &[Segment], path: &str) -> Option<Params> {
+    let mut parts = path.strip_prefix('/')?.split('/');
+    let mut params = Vec::new();
+    for segment in pattern {
+        let part = match segment {
+            Segment::Wildcard(name) => {
+                let rest: Vec<&str> = parts.collect();
+                params.push((name.clone(), rest.join("/")));
+                return Some(Params(params));
+            }
+            _ => parts.next()?,
+        };
+        match segment {
+            Segment::Literal(literal) if literal == part => {}
+            Segment::Param(name) if !part.is_empty() => {
+                params.push((name.clone(), part.to_string()))
+            }
+            _ => return None,
+        }
+    }
+    parts.next().is_none().then_some(Params(params))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::parse_head;
+
+    fn request(method: &str, target: &str) -> Request {
+        parse_head(&format!("{method} {target} HTTP/1.1\r\nHost: h")).unwrap()
+    }
+
+    fn echo(name: &'static str) -> impl Fn(&Request, &Params) -> Response {
+        move |request, params| {
+            Response::text(200, format!("{name} {} {:?}", request.method, params.0))
+        }
+    }
+
+    fn body(response: Response) -> String {
+        String::from_utf8(response.body.into_bytes().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn match_params_and_wildcards() {
+        let router = Router::new()
+            .get("/", echo("index"))
+            .get("/users/:id", echo("user"))
+            .route("DELETE", "/users/:id", echo("delete"))
+            .post("/items", echo("items"))
+            .get("/static/*", echo("static"))
+            .get("/files/*path", echo("files"))
+            .any("/proxy/*", echo("proxy"))
+            .mount("/cgi", echo("cgi"))
+            .post_form("/signup", |_, _, form| {
+                Response::text(200, form.get("name").unwrap_or_default().to_string())
+            });
+
+        assert_eq!(body(router.handle(&request("GET", "/"))), "index GET []");
+        assert_eq!(
+            body(router.handle(&request("GET", "/users/42"))),
+            r#"user GET [("id", "42")]"#
+        );
+        assert_eq!(
+            body(router.handle(&request("DELETE", "/users/a%20b"))),
+            r#"delete DELETE [("id", "a b")]"#
+        );
+        assert_eq!(
+            body(router.handle(&request("HEAD", "/users/7"))),
+            r#"user HEAD [("id", "7")]"#
+        );
+        assert_eq!(
+            body(router.handle(&request("GET", "/static/css/site.css"))),
+            r#"static GET [("*", "css/site.css")]"#
+        );
+        assert_eq!(
+            body(router.handle(&request("GET", "/static"))),
+            r#"static GET [("*", "")]"#
+        );
+        assert_eq!(
+            body(router.handle(&request("GET", "/files/a/b"))),
+            r#"files GET [("path", "a/b")]"#
+        );
+
+        assert_eq!(
+            body(router.handle(&request("PATCH", "/proxy/a"))),
+            r#"proxy PATCH [("*", "a")]"#
+        );
+        assert_eq!(body(router.handle(&request("PUT", "/cgi"))), "cgi PUT []");
+        assert_eq!(
+            body(router.handle(&request("GET", "/cgi/a/b"))),
+            r#"cgi GET [("*", "a/b")]"#
+        );
+        assert_eq!(router.handle(&request("GET", "/cgix")).status, 404);
+
+        let mut signup = request("POST", "/signup");
+        assert!(router.wants_form(&signup));
+        assert!(!router.wants_form(&request("POST", "/items")));
+        assert_eq!(router.handle(&signup).status, 415);
+        signup.form = Some(Form::urlencoded(b"name=ann").unwrap());
+        assert_eq!(body(router.handle(&signup)), "ann");
+
+        assert_eq!(router.handle(&request("GET", "/users")).status, 404);
+        assert_eq!(router.handle(&request("GET", "/users/")).status, 404);
+        assert_eq!(router.handle(&request("GET", "/users/1/posts")).status, 404);
+        assert_eq!(router.handle(&request("GET", "/itemsx")).status, 404);
+
+        let response = router.handle(&request("PUT", "/users/1"));
+        assert_eq!(response.status, 405);
+        assert_eq!(response.header("Allow"), Some("DELETE, GET, HEAD"));
+        let response = router.handle(&request("GET", "/items"));
+        assert_eq!(response.header("Allow"), Some("POST"));
+    }
+
+    #[test]
+    fn reject_bad_patterns() {
+        assert_eq!(parse_prefix("/api/").as_deref(), Some("/api"));
+        assert_eq!(parse_prefix("/").as_deref(), Some(""));
+        assert_eq!(parse_prefix("api"), None);
+        assert_eq!(parse_prefix("/:id"), None);
+        assert!(parse_pattern("users").is_err());
+        assert!(parse_pattern("/users/:").is_err());
+        assert!(parse_pattern("/*/users").is_err());
+        assert_eq!(
+            parse_pattern("/a/:b/*").unwrap(),
+            vec![
+                Segment::Literal("a".to_string()),
+                Segment::Param("b".to_string()),
+                Segment::Wildcard("*".to_string())
+            ]
+        );
+    }
+}
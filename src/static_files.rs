@@ -0,0 +1,526 @@
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::compress::{add_vary, encoded_etag, negotiate, Encoding};
+use crate::http::{http_date, parse_http_date, percent_encode, Body, Request, Response, Segment};
+
+/// `--root` 目录下的静态文件
+#[derive(Debug, Clone, PartialEq)]
+pub struct StaticFiles {
+    pub root: PathBuf,
+    /// 目录下没有 index.html 时生成文件列表，否则返回 404
+    pub listing: bool,
+}
+
+impl StaticFiles {
+    pub fn serve(&self, request: &Request) -> Response {
+        let path = match self.resolve(&request.path) {
+            Ok(Some(path)) => path,
+            Ok(None) => return Response::error(404),
+            Err(status) => return Response::error(status),
+        };
+        if path.is_dir() {
+            // 不带斜杠时页面里的相对链接会指到上一级
+            if !request.path.ends_with('/') {
+                return Response::new(301)
+                    .with_header("Location", format!("{}/", percent_encode(&request.path)));
+            }
+            let index = path.join("index.html");
+            if index.is_file() {
+                return serve_file(request, &index);
+            }
+            if !self.listing {
+                return Response::error(404);
+            }
+            return match listing(&request.path, &path) {
+                Ok(page) => Response::html(200, page),
+                Err(err) => {
+                    eprintln!("ERROR: could not list {}: {err}", path.display());
+                    Response::error(500)
+                }
+            };
+        }
+        serve_file(request, &path)
+    }
+
+    /// 把解码后的 URL 路径映射到根目录下。路径里有 `..` 或者经过符号链接跑到根目录外面时返回 403，
+    /// 文件不存在时返回 `None`
+    pub fn resolve(&self, url_path: &str) -> Result<Option<PathBuf>, u16> {
+        let mut path = self.root.clone();
+        for part in url_path.split('/') {
+            match part {
+                "" | "." => {}
+                ".." => return Err(403),
+                _ if part.contains(['\\', '\0']) => return Err(403),
+                _ if is_hidden(part) => return Ok(None),
+                _ => path.push(part),
+            }
+        }
+        let (Ok(root), Ok(path)) = (self.root.canonicalize(), path.canonicalize()) else {
+            return Ok(None);
+        };
+        if !path.starts_with(&root) {
+            return Err(403);
+        }
+        Ok(Some(path))
+    }
+}
+
+/// 一次请求最多这么多个区间，再多就当作没有 `Range` 头部，返回整个文件
+const MAX_RANGES: usize = 16;
+
+/// 带 `ETag`、`Last-Modified` 的文件响应，处理条件请求和 `Range`。正文在写出时才读取
+pub fn serve_file(request: &Request, path: &Path) -> Response {
+    let (encoded, encoding, varies) = precompressed(request, path);
+    let opened = File::open(encoded.as_deref().unwrap_or(path)).and_then(|file| {
+        let metadata = file.metadata()?;
+        Ok((file, metadata))
+    });
+    let (file, metadata) = match opened {
+        Ok(opened) => opened,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Response::error(404),
+        Err(err) => {
+            eprintln!("ERROR: could not open {}: {err}", path.display());
+            return Response::error(500);
+        }
+    };
+    let len = metadata.len();
+    let modified = metadata.modified().ok();
+    let version = modified
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_nanos());
+    let etag = format!("\"{len:x}-{version:x}\"");
+    let last_modified = modified.map(http_date);
+
+    let mut response = Response::new(200)
+        .with_header("Accept-Ranges", "bytes")
+        .with_header("ETag", etag.as_str());
+    if let Some(last_modified) = &last_modified {
+        response = response.with_header("Last-Modified", last_modified.as_str());
+    }
+    if varies {
+        response = add_vary(response, "Accept-Encoding");
+    }
+    if let Some(encoding) = encoding {
+        response = response.with_header("Content-Encoding", encoding.name());
+    }
+    if not_modified(request, &etag, modified) {
+        response.status = 304;
+        return response;
+    }
+
+    let content_type = mime_type(path);
+    let ranges = request
+        .header("Range")
+        .filter(|_| if_range(request, &etag, last_modified.as_deref()))
+        .and_then(|range| parse_ranges(range, len));
+    let Some(ranges) = ranges else {
+        return response
+            .with_header("Content-Type", content_type)
+            .with_body(Body::File(file, vec![Segment::Range { start: 0, len }]));
+    };
+    response.status = 206;
+    match ranges.as_slice() {
+        [] => Response::error(416).with_header("Content-Range", format!("bytes */{len}")),
+        [(start, end)] => response
+            .with_header("Content-Type", content_type)
+            .with_header("Content-Range", format!("bytes {start}-{end}/{len}"))
+            .with_body(Body::File(
+                file,
+                vec![Segment::Range {
+                    start: *start,
+                    len: end - start + 1,
+                }],
+            )),
+        ranges => {
+            let boundary = format!("{:016x}", version ^ u128::from(len) << 64);
+            let mut segments = Vec::new();
+            for (i, (start, end)) in ranges.iter().enumerate() {
+                let separator = if i == 0 { "" } else { "\r\n" };
+                segments.push(Segment::Bytes(
+                    format!(
+                        "{separator}--{boundary}\r\nContent-Type: {content_type}\r\nContent-Range: bytes {start}-{end}/{len}\r\n\r\n"
+                    )
+                    .into_bytes(),
+                ));
+                segments.push(Segment::Range {
+                    start: *start,
+                    len: end - start + 1,
+                });
+            }
+            segments.push(Segment::Bytes(
+                format!("\r\n--{boundary}--\r\n").into_bytes(),
+            ));
+            response
+                .with_header(
+                    "Content-Type",
+                    format!("multipart/byteranges; boundary={boundary}"),
+                )
+                .with_body(Body::File(file, segments))
+        }
+    }
+}
+
+/// 客户端接受时改用同目录下的 `.br`/`.gz` 文件，返回 (预压缩文件, 编码, 是否存在预压缩文件)。
+/// 符号链接不算，避免指到根目录外面
+fn precompressed(request: &Request, path: &Path) -> (Option<PathBuf>, Option<Encoding>, bool) {
+    let sibling = |extension: &str| {
+        let mut name = path.as_os_str().to_owned();
+        name.push(format!(".{extension}"));
+        PathBuf::from(name)
+    };
+    let available: Vec<Encoding> = Encoding::ALL
+        .into_iter()
+        .filter(|encoding| {
+            encoding.extension().is_some_and(|extension| {
+                fs::symlink_metadata(sibling(extension)).is_ok_and(|metadata| metadata.is_file())
+            })
+        })
+        .collect();
+    let encoding = request
+        .header("Accept-Encoding")
+        .and_then(|accept| negotiate(accept, &available));
+    let encoded = encoding
+        .and_then(Encoding::extension)
+        .map(sibling);
+    (encoded, encoding, !available.is_empty())
+}
+
+/// `If-None-Match` 优先于 `If-Modified-Since`，时间只比较到秒。即时压缩过的 ETag 也算匹配
+fn not_modified(request: &Request, etag: &str, modified: Option<SystemTime>) -> bool {
+    if let Some(tags) = request.header("If-None-Match") {
+        return tags.split(',').map(|tag| tag.trim()).any(|tag| {
+            let tag = tag.trim_start_matches("W/");
+            tag == "*"
+                || tag == etag
+                || Encoding::ALL
+                    .into_iter()
+                    .any(|encoding| tag == encoded_etag(etag, encoding))
+        });
+    }
+    let since = request
+        .header("If-Modified-Since")
+        .and_then(parse_http_date);
+    let secs = |time: SystemTime| time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    match (since, modified) {
+        (Some(since), Some(modified)) => secs(modified) <= secs(since),
+        _ => false,
+    }
+}
+
+/// 没有 `If-Range` 或者它和当前版本一致时才按 `Range` 返回部分内容
+fn if_range(request: &Request, etag: &str, last_modified: Option<&str>) -> bool {
+    match request.header("If-Range") {
+        None => true,
+        Some(tag) if tag.starts_with('"') => tag == etag,
+        Some(date) => Some(date) == last_modified,
+    }
+}
+
+/// `bytes=0-99,200-,-500`，返回闭区间。格式不对或者区间太多时返回 `None`，按没有 `Range` 处理；
+/// 所有区间都在文件范围外时返回空列表，对应 416
+fn parse_ranges(header: &str, len: u64) -> Option<Vec<(u64, u64)>> {
+    let number = |text: &str| -> Option<u64> {
+        if text.is_empty() || !text.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        text.parse().ok()
+    };
+    let specs = header.trim().strip_prefix("bytes=")?;
+    let mut ranges = Vec::new();
+    for spec in specs.split(',') {
+        let (start, end) = spec.trim().split_once('-')?;
+        let range = match (start.trim(), end.trim()) {
+            ("", suffix) => {
+                let suffix = number(suffix)?;
+                if suffix == 0 || len == 0 {
+                    continue;
+                }
+                (len.saturating_sub(suffix), len - 1)
+            }
+            (start, "") => (number(start)?, len.saturating_sub(1)),
+            (start, end) => {
+                let (start, end) = (number(start)?, number(end)?);
+                if end < start {
+                    return None;
+                }
+                (start, end.min(len.saturating_sub(1)))
+            }
+        };
+        if range.0 < len {
+            ranges.push(range);
+        }
+    }
+    if ranges.len() > MAX_RANGES {
+        return None;
+    }
+    Some(ranges)
+}
+
+/// `.git`、`.env` 这类文件不对外提供，`.well-known` 除外
+fn is_hidden(name: &str) -> bool {
+    name.starts_with('.') && name != ".well-known"
+}
+
+/// 按扩展名推断，不认识的按二进制下载处理
+pub fn mime_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "txt" | "log" | "md" | "rs" | "toml" => "text/plain; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "xml" => "application/xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        "ico" => "image/x-icon",
+        "webp" => "image/webp",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "mp3" => "audio/mpeg",
+        "mp4" => "video/mp4",
+        _ => "application/octet-stream",
+    }
+}
+
+pub fn html_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// 目录在前，按名字排序
+fn listing(url_path: &str, dir: &Path) -> io::Result<String> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if is_hidden(&name) {
+            continue;
+        }
+        entries.push((!entry.file_type()?.is_dir(), name));
+    }
+    entries.sort();
+
+    let title = html_escape(url_path);
+    let mut page = Vec::new();
+    writeln!(page, "<!DOCTYPE html>")?;
+    writeln!(
+        page,
+        "<html><head><meta charset=\"UTF-8\"><title>Index of {title}</title></head>"
+    )?;
+    writeln!(page, "<body><h1>Index of {title}</h1><ul>")?;
+    if url_path != "/" {
+        writeln!(page, "<li><a href=\"../\">../</a></li>")?;
+    }
+    for (is_file, name) in entries {
+        let slash = if is_file { "" } else { "/" };
+        writeln!(
+            page,
+            "<li><a href=\"{}{slash}\">{}{slash}</a></li>",
+            html_escape(&percent_encode(&name)),
+            html_escape(&name)
+        )?;
+    }
+    writeln!(page, "</ul></body></html>")?;
+    Ok(String::from_utf8_lossy(&page).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{parse_head, HttpConfig};
+
+    fn get(files: &StaticFiles, target: &str) -> Response {
+        let request = parse_head(&format!("GET {target} HTTP/1.1\r\nHost: h")).unwrap();
+        files.serve(&request)
+    }
+
+    fn get_file(path: &Path, headers: &[&str]) -> Response {
+        let head = format!("GET / HTTP/1.1\r\nHost: h\r\n{}", headers.join("\r\n"));
+        serve_file(&parse_head(head.trim_end()).unwrap(), path)
+    }
+
+    #[test]
+    fn conditional_and_range_requests() {
+        let path = std::env::temp_dir().join(format!("range-{}.txt", std::process::id()));
+        fs::write(&path, "0123456789").unwrap();
+        let response = get_file(&path, &[]);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("Accept-Ranges"), Some("bytes"));
+        let etag = response.header("ETag").unwrap().to_string();
+        let last_modified = response.header("Last-Modified").unwrap().to_string();
+        assert_eq!(response.body.len(), 10);
+
+        let if_none_match = format!("If-None-Match: W/\"x\", {etag}");
+        let response = get_file(&path, &[&if_none_match]);
+        assert_eq!((response.status, response.body.len()), (304, 0));
+        assert_eq!(response.header("ETag"), Some(etag.as_str()));
+        let stale = "If-None-Match: \"other\"";
+        let since = format!("If-Modified-Since: {last_modified}");
+        assert_eq!(get_file(&path, &[stale, &since]).status, 200);
+        assert_eq!(get_file(&path, &[&since]).status, 304);
+        let old = "If-Modified-Since: Thu, 01 Jan 1970 00:00:00 GMT";
+        assert_eq!(get_file(&path, &[old]).status, 200);
+
+        let response = get_file(&path, &["Range: bytes=2-4"]);
+        assert_eq!(response.status, 206);
+        assert_eq!(response.header("Content-Range"), Some("bytes 2-4/10"));
+        assert_eq!(response.body.into_bytes().unwrap(), b"234");
+        let response = get_file(&path, &["Range: bytes=-3"]);
+        assert_eq!(response.body.into_bytes().unwrap(), b"789");
+        let response = get_file(&path, &["Range: bytes=7-100"]);
+        assert_eq!(response.header("Content-Range"), Some("bytes 7-9/10"));
+
+        let response = get_file(&path, &["Range: bytes=0-1, 8-"]);
+        let content_type = response.header("Content-Type").unwrap();
+        let boundary = content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap()
+            .to_string();
+        let body = String::from_utf8(response.body.into_bytes().unwrap()).unwrap();
+        assert_eq!(
+            body,
+            format!(
+                "--{boundary}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Range: bytes 0-1/10\r\n\r\n01\r\n\
+                 --{boundary}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Range: bytes 8-9/10\r\n\r\n89\r\n\
+                 --{boundary}--\r\n"
+            )
+        );
+
+        let response = get_file(&path, &["Range: bytes=10-20"]);
+        assert_eq!(response.status, 416);
+        assert_eq!(response.header("Content-Range"), Some("bytes */10"));
+        assert_eq!(get_file(&path, &["Range: bytes=5-2"]).status, 200);
+        assert_eq!(get_file(&path, &["Range: lines=1-2"]).status, 200);
+        let if_range = format!("If-Range: {etag}");
+        assert_eq!(
+            get_file(&path, &["Range: bytes=0-0", &if_range]).status,
+            206
+        );
+        let changed = "If-Range: \"changed\"";
+        assert_eq!(get_file(&path, &["Range: bytes=0-0", changed]).status, 200);
+        let gzip_etag = format!("If-None-Match: {}", encoded_etag(&etag, Encoding::Gzip));
+        assert_eq!(get_file(&path, &[&gzip_etag]).status, 304);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn precompressed_siblings() {
+        let dir = std::env::temp_dir().join(format!("precompressed-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("app.js");
+        fs::write(&path, "console.log(1)").unwrap();
+        fs::write(dir.join("app.js.gz"), "gzipped").unwrap();
+        fs::write(dir.join("app.js.br"), "brotli").unwrap();
+
+        let response = get_file(&path, &["Accept-Encoding: gzip, br"]);
+        assert_eq!(response.header("Content-Encoding"), Some("br"));
+        assert_eq!(
+            response.header("Content-Type"),
+            Some("text/javascript; charset=utf-8")
+        );
+        assert_eq!(response.header("Vary"), Some("Accept-Encoding"));
+        assert_eq!(response.body.into_bytes().unwrap(), b"brotli");
+        let response = get_file(&path, &["Accept-Encoding: gzip"]);
+        assert_eq!(response.body.into_bytes().unwrap(), b"gzipped");
+        let response = get_file(&path, &[]);
+        assert_eq!(response.header("Content-Encoding"), None);
+        assert_eq!(response.header("Vary"), Some("Accept-Encoding"));
+        assert_eq!(response.body.into_bytes().unwrap(), b"console.log(1)");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn serve_files_and_directories() {
+        let root = std::env::temp_dir().join(format!("static-{}", std::process::id()));
+        fs::create_dir_all(root.join("css")).unwrap();
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::write(root.join("index.html"), "<p>home</p>").unwrap();
+        fs::write(root.join("css/style.css"), "body {}").unwrap();
+        fs::write(root.join("docs/a <b>.txt"), "a").unwrap();
+        fs::write(root.join("docs/.env"), "SECRET=1").unwrap();
+        let mut files = StaticFiles {
+            root: root.clone(),
+            listing: false,
+        };
+
+        let response = get(&files, "/");
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body.into_bytes().unwrap(), b"<p>home</p>");
+        let response = get(&files, "/css/style.css");
+        assert_eq!(
+            response.header("Content-Type"),
+            Some("text/css; charset=utf-8")
+        );
+        assert_eq!(get(&files, "/missing.txt").status, 404);
+        assert_eq!(get(&files, "/docs/").status, 404);
+        let response = get(&files, "/docs");
+        assert_eq!(response.status, 301);
+        assert_eq!(response.header("Location"), Some("/docs/"));
+
+        files.listing = true;
+        let page = get(&files, "/docs/").body.into_bytes().unwrap();
+        let page = String::from_utf8(page).unwrap();
+        assert!(page.contains("<a href=\"a%20%3Cb%3E.txt\">a &lt;b&gt;.txt</a>"));
+        assert!(page.contains("<a href=\"../\">"));
+        assert!(!page.contains(".env"));
+        assert_eq!(get(&files, "/docs/.env").status, 404);
+
+        assert_eq!(get(&files, "/../etc/passwd").status, 403);
+        assert_eq!(get(&files, "/css/%2e%2e/%2e%2e/x").status, 403);
+        assert_eq!(get(&files, "/css/..%5C..%5Cx").status, 403);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn guess_mime_types() {
+        assert_eq!(mime_type(Path::new("a/b.HTML")), "text/html; charset=utf-8");
+        assert_eq!(mime_type(Path::new("logo.png")), "image/png");
+        assert_eq!(mime_type(Path::new("Makefile")), "application/octet-stream");
+        assert_eq!(
+            html_escape("<a href='x'>&"),
+            "&lt;a href=&#39;x&#39;&gt;&amp;"
+        );
+    }
+
+    #[test]
+    fn root_and_listing_options() {
+        let args = ["--root", "public", "--listing"].map(String::from);
+        let config = HttpConfig::build(args.into_iter()).unwrap();
+        assert_eq!(
+            config.site.files,
+            Some(StaticFiles {
+                root: PathBuf::from("public"),
+                listing: true
+            })
+        );
+        let args = ["--listing"].map(String::from);
+        assert!(HttpConfig::build(args.into_iter()).is_err());
+    }
+}
@@ -0,0 +1,135 @@
+use std::{
+    fs::{self, OpenOptions, Permissions},
+    io::{self, Write},
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
+    result,
+    sync::Arc,
+};
+
+use rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+use rustls::ServerConfig;
+
+/// `--tls-address`、`--cert`、`--key` 三个参数，聊天和 http 服务共用
+#[derive(Default)]
+pub struct TlsOptions {
+    pub address: Option<String>,
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+}
+
+impl TlsOptions {
+    /// 尝试解析一个参数，返回是否属于 TLS 选项
+    pub fn parse_arg(
+        &mut self,
+        arg: &str,
+        args: &mut impl Iterator<Item = String>,
+    ) -> result::Result<bool, &'static str> {
+        match arg {
+            "--tls-address" => match args.next() {
+                Some(address) => self.address = Some(address),
+                None => return Err("--tls-address expects host:port"),
+            },
+            "--cert" => match args.next() {
+                Some(path) => self.cert = Some(PathBuf::from(path)),
+                None => return Err("--cert expects a PEM file"),
+            },
+            "--key" => match args.next() {
+                Some(path) => self.key = Some(PathBuf::from(path)),
+                None => return Err("--key expects a PEM file"),
+            },
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    pub fn validate(&self) -> result::Result<(), &'static str> {
+        match (&self.address, &self.cert, &self.key) {
+            (None, None, None) | (Some(_), Some(_), Some(_)) => Ok(()),
+            _ => Err("--tls-address, --cert and --key must be given together"),
+        }
+    }
+
+    /// 启用 TLS 时返回监听地址和服务端配置
+    pub fn load(&self) -> io::Result<Option<(String, Arc<ServerConfig>)>> {
+        match (&self.address, &self.cert, &self.key) {
+            (Some(address), Some(cert), Some(key)) => {
+                Ok(Some((address.clone(), server_config(cert, key)?)))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+/// 从 PEM 格式的证书链和私钥加载服务端配置
+pub fn server_config(cert: &Path, key: &Path) -> io::Result<Arc<ServerConfig>> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    let key = PrivateKeyDer::from_pem_file(key)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    Ok(Arc::new(config))
+}
+
+/// 生成自签名证书，返回 (证书 PEM, 私钥 PEM)
+pub fn self_signed(names: Vec<String>) -> io::Result<(String, String)> {
+    let certified = rcgen::generate_simple_self_signed(names).map_err(io::Error::other)?;
+    Ok((certified.cert.pem(), certified.signing_key.serialize_pem()))
+}
+
+pub fn write_self_signed(cert: &Path, key: &Path, names: Vec<String>) -> io::Result<()> {
+    let (cert_pem, key_pem) = self_signed(names)?;
+    fs::write(cert, cert_pem)?;
+    // 私钥只给自己读写，覆盖已有文件时也把权限收紧
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(key)?;
+    file.set_permissions(Permissions::from_mode(0o600))?;
+    file.write_all(key_pem.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_options() {
+        let mut options = TlsOptions::default();
+        let mut args = ["0.0.0.0:6970", "cert.pem", "key.pem"]
+            .map(String::from)
+            .into_iter();
+        assert_eq!(options.parse_arg("--tls-address", &mut args), Ok(true));
+        assert_eq!(
+            options.validate(),
+            Err("--tls-address, --cert and --key must be given together")
+        );
+        assert_eq!(options.parse_arg("--cert", &mut args), Ok(true));
+        assert_eq!(options.parse_arg("--key", &mut args), Ok(true));
+        assert_eq!(options.parse_arg("--other", &mut args), Ok(false));
+        assert_eq!(options.validate(), Ok(()));
+        assert_eq!(options.address.as_deref(), Some("0.0.0.0:6970"));
+    }
+
+    #[test]
+    fn self_signed_loads() {
+        let dir = std::env::temp_dir();
+        let cert = dir.join(format!("tls-cert-{}.pem", std::process::id()));
+        let key = dir.join(format!("tls-key-{}.pem", std::process::id()));
+        fs::write(&key, "old").unwrap();
+        write_self_signed(&cert, &key, vec!["localhost".to_string()]).unwrap();
+        let mode = fs::metadata(&key).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        assert!(server_config(&cert, &key).is_ok());
+        assert!(server_config(&key, &cert).is_err());
+        fs::remove_file(cert).unwrap();
+        fs::remove_file(key).unwrap();
+    }
+}
@@ -0,0 +1,288 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    result,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use sha2::{Digest, Sha256};
+
+use crate::auth::to_hex;
+
+/// 文件帧以这个命令开头，和普通聊天行走同一条连接
+pub const FRAME_PREFIX: &str = "/file";
+/// 每个 chunk 帧携带的原始字节数，base64 之后一行不到 22 KiB
+pub const CHUNK_SIZE: usize = 16 * 1024;
+pub const DEFAULT_MAX_SIZE: u64 = 10 * 1024 * 1024;
+
+/// 文件传输帧，一帧一行。发送方给自己的传输编号，服务器转发给接收方时换成自己分配的编号，
+/// 所以同一个接收方收到多个发送方的文件时编号也不会冲突。
+///
+/// 每种帧只属于一侧: offer、chunk、done、cancel 用发送方那一侧的编号，accept、reject 用接收方的，
+/// 一个客户端同时收发文件时也能分清。发送方中止用 cancel，接收方拒绝或中止用 reject。
+#[derive(Debug, Clone, PartialEq)]
+pub enum FileFrame {
+    /// 发给服务器时 `peer` 是接收方，服务器转发时换成发送方
+    Offer {
+        id: u64,
+        peer: String,
+        size: u64,
+        sha256: String,
+        name: String,
+    },
+    Accept {
+        id: u64,
+    },
+    Reject {
+        id: u64,
+        reason: String,
+    },
+    Chunk {
+        id: u64,
+        data: Vec<u8>,
+    },
+    Done {
+        id: u64,
+    },
+    Cancel {
+        id: u64,
+        reason: String,
+    },
+}
+
+impl FileFrame {
+    pub fn id(&self) -> u64 {
+        match self {
+            FileFrame::Offer { id, .. }
+            | FileFrame::Accept { id }
+            | FileFrame::Reject { id, .. }
+            | FileFrame::Chunk { id, .. }
+            | FileFrame::Done { id }
+            | FileFrame::Cancel { id, .. } => *id,
+        }
+    }
+
+    pub fn with_id(mut self, new_id: u64) -> FileFrame {
+        match &mut self {
+            FileFrame::Offer { id, .. }
+            | FileFrame::Accept { id }
+            | FileFrame::Reject { id, .. }
+            | FileFrame::Chunk { id, .. }
+            | FileFrame::Done { id }
+            | FileFrame::Cancel { id, .. } => *id = new_id,
+        }
+        self
+    }
+
+    /// 不是文件帧时返回 `None`，是文件帧但格式不对时返回错误
+    pub fn decode(line: &str) -> Option<result::Result<FileFrame, &'static str>> {
+        let rest = line.strip_prefix(FRAME_PREFIX)?;
+        if !rest.is_empty() && !rest.starts_with(' ') {
+            return None;
+        }
+        Some(parse(rest.trim_start()))
+    }
+}
+
+fn parse(text: &str) -> result::Result<FileFrame, &'static str> {
+    let mut parts = text.splitn(3, ' ');
+    let kind = parts.next().unwrap_or_default();
+    let id = parts
+        .next()
+        .and_then(|id| id.parse().ok())
+        .ok_or("file frame needs a numeric id")?;
+    let rest = parts.next().unwrap_or_default();
+    let frame = match kind {
+        "offer" => {
+            let mut fields = rest.splitn(4, ' ');
+            let (Some(peer), Some(size), Some(sha256), Some(name)) =
+                (fields.next(), fields.next(), fields.next(), fields.next())
+            else {
+                return Err("usage: /file offer <id> <nick> <size> <sha256> <name>");
+            };
+            let size = size.parse().map_err(|_| "file size must be a number")?;
+            if sha256.len() != 64 || !sha256.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err("sha256 must be 64 hex digits");
+            }
+            if peer.is_empty() || name.is_empty() {
+                return Err("usage: /file offer <id> <nick> <size> <sha256> <name>");
+            }
+            FileFrame::Offer {
+                id,
+                peer: peer.to_string(),
+                size,
+                sha256: sha256.to_ascii_lowercase(),
+                name: name.to_string(),
+            }
+        }
+        "accept" => FileFrame::Accept { id },
+        "reject" => FileFrame::Reject {
+            id,
+            reason: rest.to_string(),
+        },
+        "chunk" => FileFrame::Chunk {
+            id,
+            data: STANDARD
+                .decode(rest)
+                .map_err(|_| "chunk data must be base64")?,
+        },
+        "done" => FileFrame::Done { id },
+        "cancel" => FileFrame::Cancel {
+            id,
+            reason: rest.to_string(),
+        },
+        _ => return Err("unknown file frame"),
+    };
+    Ok(frame)
+}
+
+impl fmt::Display for FileFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FileFrame::Offer {
+                id,
+                peer,
+                size,
+                sha256,
+                name,
+            } => write!(f, "{FRAME_PREFIX} offer {id} {peer} {size} {sha256} {name}"),
+            FileFrame::Accept { id } => write!(f, "{FRAME_PREFIX} accept {id}"),
+            FileFrame::Reject { id, reason } => write!(f, "{FRAME_PREFIX} reject {id} {reason}"),
+            FileFrame::Chunk { id, data } => {
+                write!(f, "{FRAME_PREFIX} chunk {id} {}", STANDARD.encode(data))
+            }
+            FileFrame::Done { id } => write!(f, "{FRAME_PREFIX} done {id}"),
+            FileFrame::Cancel { id, reason } => write!(f, "{FRAME_PREFIX} cancel {id} {reason}"),
+        }
+    }
+}
+
+pub fn file_sha256(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; CHUNK_SIZE];
+    loop {
+        match file.read(&mut buffer)? {
+            0 => return Ok(to_hex(&hasher.finalize())),
+            n => hasher.update(&buffer[..n]),
+        }
+    }
+}
+
+/// 对方给的文件名只保留最后一段，去掉控制字符和开头的点，避免写到下载目录以外
+pub fn sanitize_name(name: &str) -> String {
+    let base = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let cleaned: String = base
+        .chars()
+        .map(|c| if c.is_control() { '_' } else { c })
+        .collect();
+    match cleaned.trim().trim_start_matches('.') {
+        "" => "download".to_string(),
+        name => name.to_string(),
+    }
+}
+
+/// 目标文件已存在时依次尝试 `name-1.ext`、`name-2.ext`
+pub fn unique_path(dir: &Path, name: &str) -> PathBuf {
+    let path = dir.join(name);
+    if !path.exists() {
+        return path;
+    }
+    let name = Path::new(name);
+    let stem = name.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = name
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+    (1..)
+        .map(|n| dir.join(format!("{stem}-{n}{extension}")))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+pub fn format_size(bytes: u64) -> String {
+    match bytes {
+        0..1024 => format!("{bytes} B"),
+        1024..0x10_0000 => format!("{:.1} KiB", bytes as f64 / 1024.0),
+        0x10_0000..0x4000_0000 => format!("{:.1} MiB", bytes as f64 / 0x10_0000 as f64),
+        _ => format!("{:.1} GiB", bytes as f64 / 0x4000_0000 as f64),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_roundtrip() {
+        let frames = [
+            FileFrame::Offer {
+                id: 7,
+                peer: "bob".to_string(),
+                size: 1234,
+                sha256: "ab".repeat(32),
+                name: "my report.pdf".to_string(),
+            },
+            FileFrame::Accept { id: 7 },
+            FileFrame::Reject {
+                id: 7,
+                reason: "no thanks".to_string(),
+            },
+            FileFrame::Chunk {
+                id: 7,
+                data: vec![0, 1, 2, 255],
+            },
+            FileFrame::Done { id: 7 },
+            FileFrame::Cancel {
+                id: 7,
+                reason: "peer disconnected".to_string(),
+            },
+        ];
+        for frame in frames {
+            let line = frame.to_string();
+            assert_eq!(FileFrame::decode(&line), Some(Ok(frame.clone())));
+            assert_eq!(frame.clone().with_id(9).id(), 9);
+        }
+    }
+
+    #[test]
+    fn decode_rejects_malformed() {
+        assert_eq!(FileFrame::decode("hello"), None);
+        assert_eq!(FileFrame::decode("/files are great"), None);
+        assert!(FileFrame::decode("/file").unwrap().is_err());
+        assert!(FileFrame::decode("/file accept x").unwrap().is_err());
+        assert!(FileFrame::decode("/file chunk 1 !!!").unwrap().is_err());
+        assert!(FileFrame::decode("/file offer 1 bob 10 abc a.txt")
+            .unwrap()
+            .is_err());
+        assert!(FileFrame::decode("/file upload 1").unwrap().is_err());
+    }
+
+    #[test]
+    fn names_and_sizes() {
+        assert_eq!(sanitize_name("../../etc/passwd"), "passwd");
+        assert_eq!(sanitize_name("C:\\temp\\.bashrc"), "bashrc");
+        assert_eq!(sanitize_name(".."), "download");
+        assert_eq!(sanitize_name("a\nb.txt"), "a_b.txt");
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(DEFAULT_MAX_SIZE), "10.0 MiB");
+    }
+
+    #[test]
+    fn checksum_and_unique_path() {
+        let dir = std::env::temp_dir().join(format!("transfer-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("a.txt");
+        std::fs::write(&path, "abc").unwrap();
+        assert_eq!(
+            file_sha256(&path).unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(unique_path(&dir, "a.txt"), dir.join("a-1.txt"));
+        assert_eq!(unique_path(&dir, "b.txt"), dir.join("b.txt"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,328 @@
+use std::{fs, io, path::PathBuf, result};
+
+use crate::access_log::{AccessLogConfig, LogFormat};
+use crate::cgi::CgiRoute;
+use crate::http::{Request, Response};
+use crate::proxy::ProxyRoute;
+use crate::static_files::{mime_type, StaticFiles};
+use crate::units::parse_size;
+
+/// 一个站点自己的设置。`--vhost` 之前的选项属于默认站点，`Host` 没有匹配时也用它
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SiteConfig {
+    /// `Host` 里的名字，小写、不含端口，`*.example.com` 匹配所有子域名。默认站点为空
+    pub names: Vec<String>,
+    /// 设置了 `--root` 时按目录提供静态文件
+    pub files: Option<StaticFiles>,
+    /// 这些前缀下的请求交给 CGI 脚本，优先于反向代理和静态文件
+    pub cgi: Vec<CgiRoute>,
+    /// 这些前缀下的请求转发给上游，优先于静态文件
+    pub proxies: Vec<ProxyRoute>,
+    pub access_log: AccessLogConfig,
+    /// `--error-page 404=pages/404.html`
+    pub error_pages: Vec<(u16, PathBuf)>,
+    listing: bool,
+}
+
+impl SiteConfig {
+    /// `--vhost example.com,www.example.com`
+    pub fn named(names: &str) -> Option<SiteConfig> {
+        let names: Vec<String> = names
+            .split(',')
+            .map(|name| name.trim().to_ascii_lowercase())
+            .collect();
+        let valid = |name: &String| {
+            let name = name.strip_prefix("*.").unwrap_or(name);
+            !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+        };
+        if !names.iter().all(valid) {
+            return None;
+        }
+        Some(SiteConfig {
+            names,
+            ..SiteConfig::default()
+        })
+    }
+
+    /// 尝试解析一个参数，返回是否属于站点选项
+    pub fn parse_arg(
+        &mut self,
+        arg: &str,
+        args: &mut impl Iterator<Item = String>,
+    ) -> result::Result<bool, &'static str> {
+        match arg {
+            "--root" => {
+                let root = args.next().ok_or("--root expects a directory")?;
+                self.files = Some(StaticFiles {
+                    root: PathBuf::from(root),
+                    listing: false,
+                });
+            }
+            "--listing" => self.listing = true,
+            "--cgi" => match args.next().as_deref().and_then(CgiRoute::parse) {
+                Some(route) => self.cgi.push(route),
+                None => return Err("--cgi expects /prefix=script"),
+            },
+            "--proxy" => match args.next().as_deref().and_then(ProxyRoute::parse) {
+                Some(route) => self.proxies.push(route),
+                None => return Err("--proxy expects /prefix=host:port[,host:port...]"),
+            },
+            "--access-log" => {
+                self.access_log.path = match args.next().as_deref() {
+                    Some("-") => None,
+                    Some(path) => Some(PathBuf::from(path)),
+                    None => return Err("--access-log expects a file, - for stdout"),
+                }
+            }
+            "--log-format" => {
+                self.access_log.format = match args.next().as_deref().and_then(LogFormat::parse) {
+                    Some(format) => format,
+                    None => return Err("--log-format expects common, combined or json"),
+                }
+            }
+            "--log-max-size" => {
+                self.access_log.max_size = match args.next().and_then(|size| parse_size(&size)) {
+                    Some(size) if size > 0 => Some(size),
+                    _ => return Err("--log-max-size expects a size such as 10M"),
+                }
+            }
+            "--error-page" => {
+                let page = args.next();
+                let page = page.as_deref().and_then(|page| page.split_once('='));
+                match page.map(|(status, path)| (status.parse(), path)) {
+                    Some((Ok(status @ 400..=599), path)) if !path.is_empty() => {
+                        self.error_pages.push((status, PathBuf::from(path)))
+                    }
+                    _ => return Err("--error-page expects status=file such as 404=404.html"),
+                }
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// 所有参数读完以后检查
+    pub fn finish(&mut self) -> result::Result<(), &'static str> {
+        match &mut self.files {
+            Some(files) => files.listing = self.listing,
+            None if self.listing => return Err("--listing needs --root"),
+            None => {}
+        }
+        Ok(())
+    }
+}
+
+/// `Host` 头部去掉端口并转成小写，没有时返回 `None`
+pub fn host_name(request: &Request) -> Option<String> {
+    let host = request.header("Host")?.trim();
+    let name = match host.rsplit_once(':') {
+        // IPv6 字面量里的 `:` 在方括号里面
+        Some((name, port)) if !port.contains(']') => name,
+        _ => host,
+    };
+    let name = name.trim_end_matches('.');
+    Some(name.to_ascii_lowercase()).filter(|name| !name.is_empty())
+}
+
+fn name_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host
+            .strip_suffix(domain)
+            .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+        None => pattern == host,
+    }
+}
+
+/// 按 `Host` 挑站点，先注册的优先，都不匹配时用默认站点
+pub struct VirtualHosts<T> {
+    default: T,
+    sites: Vec<(Vec<String>, T)>,
+}
+
+impl<T> VirtualHosts<T> {
+    pub fn new(default: T) -> VirtualHosts<T> {
+        VirtualHosts {
+            default,
+            sites: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, names: Vec<String>, site: T) {
+        self.sites.push((names, site));
+    }
+
+    pub fn default_site(&self) -> &T {
+        &self.default
+    }
+
+    pub fn pick(&self, request: &Request) -> &T {
+        let Some(host) = host_name(request) else {
+            return &self.default;
+        };
+        self.sites
+            .iter()
+            .find(|(names, _)| names.iter().any(|name| name_matches(name, &host)))
+            .map_or(&self.default, |(_, site)| site)
+    }
+}
+
+/// 启动时读进内存的错误页面
+#[derive(Default)]
+pub struct ErrorPages(Vec<(u16, &'static str, Vec<u8>)>);
+
+impl ErrorPages {
+    pub fn load(pages: &[(u16, PathBuf)]) -> io::Result<ErrorPages> {
+        let mut loaded = Vec::new();
+        for (status, path) in pages {
+            let page = fs::read(path)
+                .map_err(|err| io::Error::new(err.kind(), format!("{}: {err}", path.display())))?;
+            loaded.push((*status, mime_type(path), page));
+        }
+        Ok(ErrorPages(loaded))
+    }
+
+    /// 状态码有对应的页面时换掉正文，`Allow`、`Retry-After` 这类头部保留
+    pub fn apply(&self, mut response: Response) -> Response {
+        let Some((_, content_type, page)) = self
+            .0
+            .iter()
+            .find(|(status, _, _)| *status == response.status)
+        else {
+            return response;
+        };
+        let stale = [
+            "Content-Length",
+            "Content-Range",
+            "Content-Encoding",
+            "ETag",
+            "Last-Modified",
+        ];
+        response
+            .headers
+            .retain(|(name, _)| !stale.iter().any(|stale| name.eq_ignore_ascii_case(stale)));
+        response
+            .with_header("Content-Type", *content_type)
+            .with_body(page.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{parse_head, HttpConfig};
+
+    fn request(host: Option<&str>) -> Request {
+        let head = match host {
+            Some(host) => format!("GET / HTTP/1.1\r\nHost: {host}"),
+            None => "GET / HTTP/1.0".to_string(),
+        };
+        parse_head(&head).unwrap()
+    }
+
+    #[test]
+    fn pick_sites_by_host() {
+        let mut hosts = VirtualHosts::new("default");
+        hosts.add(
+            SiteConfig::named("example.com,www.example.com")
+                .unwrap()
+                .names,
+            "example",
+        );
+        hosts.add(
+            SiteConfig::named("*.Example.com").unwrap().names,
+            "wildcard",
+        );
+        hosts.add(vec!["[::1]".to_string()], "ipv6");
+
+        let pick = |host| *hosts.pick(&request(host));
+        assert_eq!(pick(Some("example.com")), "example");
+        assert_eq!(pick(Some("WWW.Example.com:8080")), "example");
+        assert_eq!(pick(Some("example.com.")), "example");
+        assert_eq!(pick(Some("api.example.com")), "wildcard");
+        assert_eq!(pick(Some("a.b.example.com")), "wildcard");
+        assert_eq!(pick(Some("badexample.com")), "default");
+        assert_eq!(pick(Some("[::1]:7878")), "ipv6");
+        assert_eq!(pick(Some("other.org")), "default");
+        assert_eq!(pick(Some("")), "default");
+        assert_eq!(pick(None), "default");
+        assert_eq!(*hosts.default_site(), "default");
+
+        assert_eq!(SiteConfig::named("bad host"), None);
+        assert_eq!(SiteConfig::named("a.com,"), None);
+    }
+
+    #[test]
+    fn replace_error_pages() {
+        let dir = std::env::temp_dir().join(format!("vhost-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let page = dir.join("404.html");
+        fs::write(&page, "<h1>gone</h1>").unwrap();
+        let pages = ErrorPages::load(&[(404, page)]).unwrap();
+
+        let response = pages.apply(
+            Response::error(404)
+                .with_header("Content-Length", "14")
+                .with_header("X-Keep", "1"),
+        );
+        assert_eq!(
+            response.header("Content-Type"),
+            Some("text/html; charset=utf-8")
+        );
+        assert_eq!(response.header("Content-Length"), None);
+        assert_eq!(response.header("X-Keep"), Some("1"));
+        assert_eq!(response.body.into_bytes().unwrap(), b"<h1>gone</h1>");
+        let response = pages.apply(Response::error(500));
+        assert_eq!(
+            response.body.into_bytes().unwrap(),
+            b"500 Internal Server Error\n"
+        );
+
+        assert!(ErrorPages::load(&[(404, dir.join("missing.html"))]).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn vhost_options() {
+        let args = [
+            "--root",
+            "public",
+            "--vhost",
+            "example.com,*.example.com",
+            "--root",
+            "sites/example",
+            "--error-page",
+            "404=sites/404.html",
+            "--access-log",
+            "example.log",
+        ]
+        .map(String::from);
+        let config = HttpConfig::build(args.into_iter()).unwrap();
+        assert_eq!(config.site.files.unwrap().root, PathBuf::from("public"));
+        let [example] = &config.vhosts[..] else {
+            panic!("expected one vhost");
+        };
+        assert_eq!(example.names, ["example.com", "*.example.com"]);
+        assert_eq!(
+            example.files,
+            Some(StaticFiles {
+                root: PathBuf::from("sites/example"),
+                listing: false
+            })
+        );
+        assert_eq!(
+            example.error_pages,
+            [(404, PathBuf::from("sites/404.html"))]
+        );
+        assert_eq!(example.access_log.path, Some(PathBuf::from("example.log")));
+        assert!(example.proxies.is_empty() && example.cgi.is_empty());
+        let args = ["--root", "a", "--vhost", "b.com", "--listing"].map(String::from);
+        assert!(HttpConfig::build(args.into_iter()).is_err());
+        let args = ["--vhost", "a.com", "--vhost", "b.com,a.com"].map(String::from);
+        assert!(HttpConfig::build(args.into_iter()).is_err());
+        let args = ["--error-page", "200=ok.html"].map(String::from);
+        assert!(HttpConfig::build(args.into_iter()).is_err());
+    }
+}
@@ -0,0 +1,163 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use sha1::{Digest, Sha1};
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// 只支持 RFC 6455 的 `Sec-WebSocket-Version`
+pub const VERSION: &str = "13";
+/// 单帧大小上限，聊天消息不需要更大
+pub const MAX_PAYLOAD: u64 = 64 * 1024;
+/// 分片拼起来以后整条消息的上限
+pub const MAX_MESSAGE: usize = 64 * 1024;
+pub const TOO_LARGE: &str = "websocket message too large";
+
+/// 关闭帧里的状态码
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_TOO_BIG: u16 = 1009;
+
+pub const CONTINUATION: u8 = 0x0;
+pub const TEXT: u8 = 0x1;
+pub const BINARY: u8 = 0x2;
+pub const CLOSE: u8 = 0x8;
+pub const PING: u8 = 0x9;
+pub const PONG: u8 = 0xA;
+
+#[derive(Debug, PartialEq)]
+pub struct Frame {
+    pub fin: bool,
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+}
+
+/// `Sec-WebSocket-Accept` 的值
+pub fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.trim().as_bytes());
+    hasher.update(GUID.as_bytes());
+    STANDARD.encode(hasher.finalize())
+}
+
+pub fn handshake_response(key: &str) -> String {
+    format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    )
+}
+
+/// 服务端发出的帧不加掩码
+pub fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        len @ 0..=125 => frame.push(len as u8),
+        len @ 126..=0xFFFF => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// 关闭帧的内容: 两字节状态码加原因
+pub fn close_payload(code: u16, reason: &str) -> Vec<u8> {
+    let mut payload = code.to_be_bytes().to_vec();
+    payload.extend_from_slice(reason.as_bytes());
+    payload
+}
+
+/// 从缓冲区解析一帧，数据不够时返回 `Ok(None)`，成功时同时返回消耗的字节数
+pub fn decode_frame(buf: &[u8]) -> Result<Option<(Frame, usize)>, &'static str> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    let fin = buf[0] & 0x80 != 0;
+    let opcode = buf[0] & 0x0F;
+    let masked = buf[1] & 0x80 != 0;
+    let (len, mut offset) = match buf[1] & 0x7F {
+        126 if buf.len() < 4 => return Ok(None),
+        126 => (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4),
+        127 if buf.len() < 10 => return Ok(None),
+        127 => (u64::from_be_bytes(buf[2..10].try_into().unwrap()), 10),
+        len => (len as u64, 2),
+    };
+    if len > MAX_PAYLOAD {
+        return Err(TOO_LARGE);
+    }
+    if !masked {
+        return Err("client frames must be masked");
+    }
+    if buf.len() < offset + 4 {
+        return Ok(None);
+    }
+    let mask = [
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ];
+    offset += 4;
+    let end = offset + len as usize;
+    if buf.len() < end {
+        return Ok(None);
+    }
+    let payload = buf[offset..end]
+        .iter()
+        .enumerate()
+        .map(|(i, b)| b ^ mask[i % 4])
+        .collect();
+    Ok(Some((
+        Frame {
+            fin,
+            opcode,
+            payload,
+        },
+        end,
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rfc6455_accept_key() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn decode_masked_hello() {
+        let frame = [
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58, 0xff,
+        ];
+        let (decoded, used) = decode_frame(&frame).unwrap().unwrap();
+        assert_eq!(used, 11);
+        assert_eq!(
+            decoded,
+            Frame {
+                fin: true,
+                opcode: TEXT,
+                payload: b"Hello".to_vec()
+            }
+        );
+        assert_eq!(decode_frame(&frame[..6]), Ok(None));
+        assert!(decode_frame(&[0x81, 0x05, b'H']).is_err());
+    }
+
+    #[test]
+    fn encode_lengths() {
+        assert_eq!(encode_frame(TEXT, b"Hi"), vec![0x81, 2, b'H', b'i']);
+        let medium = encode_frame(BINARY, &[0; 300]);
+        assert_eq!(&medium[..4], &[0x82, 126, 1, 44]);
+        assert_eq!(medium.len(), 304);
+        assert_eq!(
+            close_payload(CLOSE_TOO_BIG, "big"),
+            vec![0x03, 0xf1, b'b', b'i', b'g']
+        );
+    }
+}